@@ -0,0 +1,58 @@
+#!/bin/bash
+
+# This compares how long `precious lint --all` takes to find files when it
+# asks git for them versus when it walks the tree. It generates a repo with
+# 100,000 files (or as many as you pass as the first argument) in a temp dir.
+# If hyperfine is installed it is used to do the timing.
+
+set -eo pipefail
+
+FILE_COUNT=${1:-100000}
+FILES_PER_DIR=100
+
+ROOT=$(git rev-parse --show-toplevel)
+cargo build --release --manifest-path "$ROOT/Cargo.toml"
+PRECIOUS="$ROOT/target/release/precious"
+
+REPO=$(mktemp -d -t precious-bench-XXXXXX)
+trap 'rm -rf "$REPO"' EXIT
+cd "$REPO"
+
+echo "Generating $FILE_COUNT files in $REPO"
+for ((i = 0; i < FILE_COUNT; i++)); do
+    dir="dir-$((i / FILES_PER_DIR / FILES_PER_DIR))/dir-$((i / FILES_PER_DIR))"
+    if ((i % FILES_PER_DIR == 0)); then
+        mkdir -p "$dir"
+    fi
+    echo "$i" > "$dir/file-$i.txt"
+done
+printf '*.log\n' > .gitignore
+
+git init --quiet
+git add .
+git -c user.name=bench -c user.email=bench@example.com commit --quiet -m 'generated files'
+
+for from in vcs walk; do
+    cat > "precious-$from.toml" <<EOF
+all_files_from = "$from"
+
+[commands.true]
+type          = "lint"
+include       = "**/*"
+invoke        = "once"
+path_args     = "none"
+cmd           = [ "true" ]
+ok_exit_codes = 0
+EOF
+done
+
+if command -v hyperfine > /dev/null; then
+    hyperfine --warmup 1 \
+        "$PRECIOUS --config $REPO/precious-vcs.toml --quiet lint --all" \
+        "$PRECIOUS --config $REPO/precious-walk.toml --quiet lint --all"
+else
+    for from in vcs walk; do
+        echo "all_files_from = \"$from\""
+        time "$PRECIOUS" --config "$REPO/precious-$from.toml" --quiet lint --all
+    done
+fi
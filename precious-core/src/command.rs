@@ -1,5 +1,8 @@
-use crate::paths::matcher::{Matcher, MatcherBuilder};
-use anyhow::Result;
+use crate::paths::{
+    matcher::{Matcher, MatcherBuilder},
+    mode::Mode,
+};
+use anyhow::{Context, Result};
 use itertools::Itertools;
 use log::{debug, info};
 use precious_helpers::exec;
@@ -8,7 +11,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     fmt, fs,
-    io::ErrorKind,
+    io::{ErrorKind, Read, Write},
     path::{Path, PathBuf},
     time::SystemTime,
 };
@@ -22,6 +25,10 @@ pub enum CommandType {
     Tidy,
     #[serde(rename = "both")]
     Both,
+    // These commands lint the commit message file instead of the files in
+    // the project.
+    #[serde(rename = "commit-msg")]
+    CommitMsg,
 }
 
 impl CommandType {
@@ -30,6 +37,7 @@ impl CommandType {
             CommandType::Lint => "linter",
             CommandType::Tidy => "tidier",
             CommandType::Both => "linter/tidier",
+            CommandType::CommitMsg => "commit message linter",
         }
     }
 }
@@ -133,9 +141,13 @@ pub struct Command {
     typ: CommandType,
     includer: Matcher,
     excluder: Matcher,
+    pub exclude_attributes: Vec<String>,
     invoke: Invoke,
     working_dir: WorkingDir,
     path_args: PathArgs,
+    pub once_per_shard: bool,
+    max_file_size: Option<u64>,
+    skip_binary: bool,
     cmd: Vec<String>,
     env: HashMap<String, String>,
     lint_flags: Option<Vec<String>>,
@@ -152,10 +164,16 @@ pub struct CommandParams {
     pub name: String,
     pub typ: CommandType,
     pub include: Vec<String>,
+    pub include_types: Vec<String>,
+    pub include_shebang: Vec<String>,
     pub exclude: Vec<String>,
+    pub exclude_attributes: Vec<String>,
     pub invoke: Invoke,
     pub working_dir: WorkingDir,
     pub path_args: PathArgs,
+    pub once_per_shard: bool,
+    pub max_file_size: Option<u64>,
+    pub skip_binary: bool,
     pub cmd: Vec<String>,
     pub env: HashMap<String, String>,
     pub lint_flags: Vec<String>,
@@ -223,18 +241,27 @@ impl Command {
             )
         };
 
-        let cmd = replace_root(params.cmd, &params.project_root);
         let root = params.project_root.clone();
+        let includer = MatcherBuilder::new(&root)
+            .with(&params.include)?
+            .with_types(&params.include_types)
+            .with_context(|| format!("Invalid include_types for the {} command", params.name))?
+            .with_shebangs(&params.include_shebang)
+            .build()?;
         Ok(Command {
             project_root: params.project_root,
             name: params.name,
             typ: params.typ,
-            includer: MatcherBuilder::new(&root).with(&params.include)?.build()?,
+            includer,
             excluder: MatcherBuilder::new(&root).with(&params.exclude)?.build()?,
+            exclude_attributes: params.exclude_attributes,
             invoke: params.invoke,
             working_dir: params.working_dir,
             path_args: params.path_args,
-            cmd,
+            once_per_shard: params.once_per_shard,
+            max_file_size: params.max_file_size,
+            skip_binary: params.skip_binary,
+            cmd: params.cmd,
             env: params.env,
             lint_flags: if params.lint_flags.is_empty() {
                 None
@@ -308,8 +335,9 @@ impl Command {
         Ok(by_dir)
     }
 
-    pub fn tidy(&self, files: &[&Path]) -> Result<Option<TidyOutcome>> {
+    pub fn tidy(&self, files: &[&Path], mode: Mode) -> Result<Option<TidyOutcome>> {
         self.require_is_not_command_type("tidy", CommandType::Lint)?;
+        self.require_is_not_command_type("tidy", CommandType::CommitMsg)?;
 
         if !self.should_act_on_files(files)? {
             return Ok(None);
@@ -319,7 +347,7 @@ impl Command {
 
         let in_dir = self.in_dir(files[0])?;
         let operating_on = self.operating_on(files, &in_dir)?;
-        let mut cmd = self.command_for_paths(&self.tidy_flags, &operating_on)?;
+        let cmd = self.command_for_paths(&self.tidy_flags, &operating_on)?;
 
         info!(
             "Tidying [{}] with {} in [{}] using command [{}]",
@@ -329,15 +357,7 @@ impl Command {
             cmd.join(" "),
         );
 
-        let bin = cmd.remove(0);
-        exec::run(
-            &bin,
-            &cmd.iter().map(|c| c.as_str()).collect::<Vec<_>>(),
-            &self.env,
-            &self.ok_exit_codes,
-            self.ignore_stderr.as_deref(),
-            Some(&in_dir),
-        )?;
+        self.run(cmd, "tidy", mode, &operating_on, &in_dir)?;
 
         if let Some(pm) = path_metadata {
             if self.paths_were_changed(pm)? {
@@ -348,7 +368,7 @@ impl Command {
         Ok(Some(TidyOutcome::Unknown))
     }
 
-    pub fn lint(&self, files: &[&Path]) -> Result<Option<LintOutcome>> {
+    pub fn lint(&self, files: &[&Path], mode: Mode) -> Result<Option<LintOutcome>> {
         self.require_is_not_command_type("lint", CommandType::Tidy)?;
 
         if !self.should_act_on_files(files)? {
@@ -357,7 +377,7 @@ impl Command {
 
         let in_dir = self.in_dir(files[0])?;
         let operating_on = self.operating_on(files, &in_dir)?;
-        let mut cmd = self.command_for_paths(&self.lint_flags, &operating_on)?;
+        let cmd = self.command_for_paths(&self.lint_flags, &operating_on)?;
 
         info!(
             "Linting [{}] with {} in [{}] using command [{}]",
@@ -367,15 +387,7 @@ impl Command {
             cmd.join(" "),
         );
 
-        let bin = cmd.remove(0);
-        let result = exec::run(
-            &bin,
-            &cmd.iter().map(|c| c.as_str()).collect::<Vec<_>>(),
-            &self.env,
-            &self.ok_exit_codes,
-            self.ignore_stderr.as_deref(),
-            Some(&in_dir),
-        )?;
+        let result = self.run(cmd, "lint", mode, &operating_on, &in_dir)?;
 
         Ok(Some(LintOutcome {
             ok: !self.lint_failure_exit_codes.contains(&result.exit_code),
@@ -384,6 +396,52 @@ impl Command {
         }))
     }
 
+    // Besides the command's own `env`, the command is run with environment
+    // variables that tell it why it's being run. `PRECIOUS_PATHS_FILE` is a
+    // file with one path per line, so a wrapper script doesn't need to parse
+    // its arguments to find the paths. It's deleted once the command exits.
+    fn run(
+        &self,
+        mut cmd: Vec<String>,
+        action: &str,
+        mode: Mode,
+        operating_on: &[PathBuf],
+        in_dir: &Path,
+    ) -> Result<exec::ExecOutput> {
+        let mut paths_file = tempfile::Builder::new()
+            .prefix("precious-paths-")
+            .tempfile()?;
+        for p in operating_on {
+            writeln!(paths_file, "{}", p.display())?;
+        }
+        paths_file.flush()?;
+
+        let mut env = HashMap::from([
+            (
+                "PRECIOUS_ROOT".to_string(),
+                self.project_root.display().to_string(),
+            ),
+            ("PRECIOUS_COMMAND_NAME".to_string(), self.name.clone()),
+            ("PRECIOUS_ACTION".to_string(), action.to_string()),
+            ("PRECIOUS_MODE".to_string(), mode.env_value().to_string()),
+            (
+                "PRECIOUS_PATHS_FILE".to_string(),
+                paths_file.path().display().to_string(),
+            ),
+        ]);
+        env.extend(self.env.clone());
+
+        let bin = cmd.remove(0);
+        exec::run(
+            &bin,
+            &cmd.iter().map(|c| c.as_str()).collect::<Vec<_>>(),
+            &env,
+            &self.ok_exit_codes,
+            self.ignore_stderr.as_deref(),
+            Some(in_dir),
+        )
+    }
+
     fn require_is_not_command_type(
         &self,
         method: &'static str,
@@ -401,6 +459,12 @@ impl Command {
     }
 
     fn should_act_on_files(&self, files: &[&Path]) -> Result<bool> {
+        // The commit message file is the only file a commit message linter
+        // ever sees, so include and exclude rules don't apply to it.
+        if self.typ == CommandType::CommitMsg {
+            return Ok(true);
+        }
+
         match self.invoke {
             Invoke::PerFile => {
                 let f = &files[0];
@@ -615,15 +679,53 @@ impl Command {
     }
 
     fn file_matches_rules(&self, file: &Path) -> bool {
+        if self.typ == CommandType::CommitMsg {
+            return true;
+        }
         if self.excluder.path_matches(file, false) {
             return false;
         }
         if self.includer.path_matches(file, false) {
-            return true;
+            return self.file_content_is_acceptable(file);
         }
         false
     }
 
+    // This is only called for files which match the include and exclude
+    // rules, since it has to look at the file. If we can't read the file we
+    // let the command deal with it.
+    fn file_content_is_acceptable(&self, file: &Path) -> bool {
+        if self.max_file_size.is_none() && !self.skip_binary {
+            return true;
+        }
+
+        let full = self.project_root.join(file);
+        if let Some(max) = self.max_file_size {
+            if let Ok(meta) = fs::metadata(&full) {
+                if meta.len() > max {
+                    debug!(
+                        "File {} is skipped for the {} command because it is {} bytes, which is larger than max_file_size ({max} bytes)",
+                        file.display(),
+                        self.name,
+                        meta.len(),
+                    );
+                    return false;
+                }
+            }
+        }
+
+        if self.skip_binary && is_binary(&full) {
+            debug!(
+                "File {} is skipped for the {} command because it looks like a binary file",
+                file.display(),
+                self.name,
+            );
+            return false;
+        }
+
+        true
+    }
+
     fn metadata_for_file(file: &Path) -> Result<PathInfo> {
         let meta = fs::metadata(file)?;
         Ok(PathInfo {
@@ -706,6 +808,10 @@ impl Command {
         Ok(false)
     }
 
+    pub fn is_invoked_once(&self) -> bool {
+        self.invoke == Invoke::Once
+    }
+
     pub fn config_key(&self) -> String {
         format!("commands.{}", Self::maybe_toml_quote(&self.name),)
     }
@@ -744,15 +850,16 @@ impl Command {
     }
 }
 
-fn replace_root(cmd: Vec<String>, root: &Path) -> Vec<String> {
-    cmd.iter()
-        .map(|c| {
-            c.replace(
-                "$PRECIOUS_ROOT",
-                root.to_string_lossy().into_owned().as_str(),
-            )
-        })
-        .collect()
+// This is the same heuristic git uses. A file is binary if there's a NUL
+// byte anywhere in its first 8KB.
+const BINARY_SNIFF_LEN: u64 = 8000;
+
+fn is_binary(file: &Path) -> bool {
+    let mut buf = vec![];
+    match fs::File::open(file).and_then(|f| f.take(BINARY_SNIFF_LEN).read_to_end(&mut buf)) {
+        Ok(_) => buf.contains(&0),
+        Err(_) => false,
+    }
 }
 
 #[cfg(test)]
@@ -777,9 +884,13 @@ mod tests {
             typ: CommandType::Lint,
             includer: matcher(&[])?,
             excluder: matcher(&[])?,
+            exclude_attributes: vec![],
             invoke: Invoke::PerFile,
             working_dir: WorkingDir::Root,
             path_args: PathArgs::File,
+            once_per_shard: false,
+            max_file_size: None,
+            skip_binary: false,
             cmd: vec![],
             env: HashMap::new(),
             lint_flags: None,
@@ -791,6 +902,60 @@ mod tests {
         })
     }
 
+    #[test]
+    #[parallel]
+    fn files_to_args_sets_skips_large_and_binary_files() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        fs::write(td.path().join("small.txt"), "small")?;
+        fs::write(td.path().join("large.txt"), "x".repeat(101))?;
+        let mut binary = b"GIF89a".to_vec();
+        binary.extend([0, 1, 2]);
+        fs::write(td.path().join("binary.gif"), binary)?;
+        let mut late_nul = "x".repeat(BINARY_SNIFF_LEN as usize).into_bytes();
+        late_nul.push(0);
+        fs::write(td.path().join("late-nul.txt"), late_nul)?;
+
+        let files = ["binary.gif", "large.txt", "late-nul.txt", "small.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let command = Command {
+            project_root: td.path().to_path_buf(),
+            invoke: Invoke::Once,
+            includer: matcher(&["**/*"])?,
+            ..default_command()?
+        };
+        assert_eq!(
+            command.files_to_args_sets(&files)?,
+            vec![files.iter().map(PathBuf::as_path).collect::<Vec<_>>()],
+        );
+
+        let command = Command {
+            max_file_size: Some(100),
+            skip_binary: true,
+            ..command
+        };
+        assert_eq!(
+            command.files_to_args_sets(&files)?,
+            vec![vec![Path::new("small.txt")]],
+        );
+
+        let command = Command {
+            max_file_size: None,
+            ..command
+        };
+        assert_eq!(
+            command.files_to_args_sets(&files)?,
+            vec![vec![
+                Path::new("large.txt"),
+                Path::new("late-nul.txt"),
+                Path::new("small.txt"),
+            ]],
+        );
+
+        Ok(())
+    }
+
     #[test]
     #[parallel]
     fn files_to_args_sets_per_file() -> Result<()> {
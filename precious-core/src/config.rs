@@ -1,26 +1,50 @@
-use crate::command::{self, CommandType, Invoke, PathArgs, WorkingDir};
+use crate::{
+    command::{self, CommandType, Invoke, PathArgs, WorkingDir},
+    paths::finder::AllFilesFrom,
+    vcs::Submodules,
+};
 use anyhow::Result;
 use indexmap::IndexMap;
-//use log::warn;
+use interpolate::Interpolator;
+use itertools::Itertools;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
 use serde::{de, de::Deserializer, Deserialize};
 use std::{
     collections::HashMap,
-    fmt, fs,
+    env, fmt, fs,
     marker::PhantomData,
     path::{Path, PathBuf},
 };
 use thiserror::Error;
+use toml::value::{Table, Value};
+
+mod interpolate;
+pub mod migrate;
+pub mod schema;
+mod validate;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct CommandConfig {
     #[serde(rename = "type")]
     typ: CommandType,
+    #[serde(default)]
     #[serde(deserialize_with = "string_or_seq_string")]
     include: Vec<String>,
     #[serde(default)]
     #[serde(deserialize_with = "string_or_seq_string")]
+    include_types: Vec<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "string_or_seq_string")]
+    include_shebang: Vec<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "string_or_seq_string")]
     exclude: Vec<String>,
     #[serde(default)]
+    #[serde(deserialize_with = "string_or_seq_string")]
+    exclude_attributes: Vec<String>,
+    #[serde(default)]
     invoke: Option<Invoke>,
     #[serde(default)]
     #[serde(deserialize_with = "working_dir")]
@@ -28,6 +52,18 @@ pub struct CommandConfig {
     #[serde(default)]
     path_args: Option<PathArgs>,
     #[serde(default)]
+    once_per_shard: bool,
+    #[serde(default)]
+    #[serde(deserialize_with = "file_size")]
+    max_file_size: Option<u64>,
+    #[serde(default)]
+    skip_binary: Option<bool>,
+    #[serde(default)]
+    disabled: bool,
+    #[serde(default)]
+    #[serde(deserialize_with = "string_or_seq_string")]
+    labels: Vec<String>,
+    #[serde(default)]
     run_mode: Option<OldRunMode>,
     #[serde(default)]
     chdir: Option<bool>,
@@ -69,12 +105,61 @@ fn empty_string() -> String {
     String::new()
 }
 
+// Files with any of these git attributes are never passed to any command.
+fn default_exclude_attributes() -> Vec<String> {
+    vec![String::from("precious-skip")]
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     #[serde(default)]
     #[serde(deserialize_with = "string_or_seq_string")]
     pub exclude: Vec<String>,
+    #[serde(default = "default_exclude_attributes")]
+    #[serde(deserialize_with = "string_or_seq_string")]
+    pub exclude_attributes: Vec<String>,
+    #[serde(default)]
+    pub submodules: Submodules,
+    #[serde(default)]
+    pub all_files_from: AllFilesFrom,
+    #[serde(default)]
+    #[serde(deserialize_with = "file_size")]
+    pub max_file_size: Option<u64>,
+    #[serde(default)]
+    pub skip_binary: bool,
+    #[serde(default)]
+    pub nested_configs: bool,
+    #[serde(default)]
+    pub jobs: Option<usize>,
+    #[serde(default)]
+    pub hooks: Option<HooksConfig>,
+    #[serde(default)]
+    vars: IndexMap<String, String>,
     commands: IndexMap<String, CommandConfig>,
+    // A profile is applied while the config is loaded, so by now this is only
+    // used to check that the selected profile exists.
+    #[serde(default)]
+    profiles: IndexMap<String, Table>,
+    // This is the directory of the project's config file, which is used for
+    // `$PRECIOUS_CONFIG_DIR`.
+    #[serde(skip)]
+    config_dir: PathBuf,
+}
+
+// Each key is the name of a git hook and each value is the arguments to pass
+// to precious when that hook runs, like `["lint", "--staged"]`. A single
+// string is split on whitespace.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HooksConfig {
+    #[serde(default, rename = "pre-commit")]
+    #[serde(deserialize_with = "hook_args")]
+    pub pre_commit: Option<Vec<String>>,
+    #[serde(default, rename = "pre-push")]
+    #[serde(deserialize_with = "hook_args")]
+    pub pre_push: Option<Vec<String>>,
+    #[serde(default, rename = "commit-msg")]
+    #[serde(deserialize_with = "hook_args")]
+    pub commit_msg: Option<Vec<String>>,
 }
 
 #[derive(Debug, Error, PartialEq, Eq)]
@@ -91,10 +176,65 @@ pub enum ConfigError {
     CannotInvokePerDirInRootWithPathArgs { path_args: PathArgs },
     #[error(r#"Cannot set invoke = "once" and working_dir = "dir""#)]
     CannotInvokeOnceWithWorkingDirEqDir,
+    #[error(r#"The {name:} command sets once_per_shard but it is not invoked once"#)]
+    OncePerShardRequiresInvokeOnce { name: String },
+    #[error("The {name:} command must have an include, include_types, or include_shebang key")]
+    CommandRequiresInclude { name: String },
+    #[error("The extends key in {} must be a string or an array of strings", file.display())]
+    InvalidExtends { file: PathBuf },
+    #[error("Config files extend each other in a cycle: {}", files.iter().map(|f| f.display()).join(" -> "))]
+    ExtendsCycle { files: Vec<PathBuf> },
+    #[error("Found more than one precious config: {}. Remove all but one of them.", sources.join(", "))]
+    MultipleConfigSources { sources: Vec<String> },
+    #[error("The file at {} does not have a precious config under {keys:}", file.display())]
+    NoEmbeddedConfig { file: PathBuf, keys: String },
+    #[error("Invalid config in {}: {error:}", file.display())]
+    InvalidConfigInFile { file: PathBuf, error: String },
+    #[error(
+        "{}\nSet allow_unknown_keys = true in the file to only warn about unknown keys.",
+        validate::format_keys(keys)
+    )]
+    UnknownKeys { keys: Vec<validate::UnknownKey> },
+    #[error("{context:} refers to the {name:} environment variable, which is not set")]
+    UndefinedEnvVar { context: String, name: String },
+    #[error("{context:} refers to the {name:} var, which is not in the [vars] table")]
+    UndefinedVar { context: String, name: String },
+    #[error("{context:} refers to {name:}, which is not defined for this project")]
+    UndefinedBuiltinVar { context: String, name: String },
+    #[error("Vars refer to each other in a cycle: {}", names.join(" -> "))]
+    VarCycle { names: Vec<String> },
+    #[error("{context:} has a value with an unterminated variable reference: {value:}")]
+    UnterminatedInterpolation { context: String, value: String },
+    #[error("Cannot migrate the config in {} because only TOML files can be migrated", file.display())]
+    CannotMigrateNonToml { file: PathBuf },
+    #[error("There is no {name:} profile in the config for {}", file.display())]
+    UnknownProfile { name: String, file: PathBuf },
     #[error(transparent)]
     Toml(#[from] toml::de::Error),
 }
 
+pub const CONFIG_FILE_NAMES: &[&str] = &["precious.toml", ".precious.toml"];
+
+const LOCAL_CONFIG_FILE_NAME: &str = "precious.local.toml";
+
+// These parse the text of a `toml::de::Error`, which is the only way to get
+// the key and position of the error. The `toml_error_format` test checks that
+// the toml crate still formats its errors the way these expect.
+static ERROR_POSITION_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r" at line \d+ column \d+$").unwrap());
+static ERROR_KEY_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"for key `([^`]+)`").unwrap());
+
+// These are files which can have a precious config embedded in them, along
+// with the keys that can hold it.
+const EMBEDDED_CONFIGS: &[(&str, &[&str])] = &[
+    (
+        "Cargo.toml",
+        &["package.metadata.precious", "workspace.metadata.precious"],
+    ),
+    ("pyproject.toml", &["tool.precious"]),
+    ("package.json", &["precious"]),
+];
+
 // Copied from https://stackoverflow.com/a/43627388 - CC-BY-SA 3.0
 fn string_or_seq_string<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
 where
@@ -127,6 +267,77 @@ where
     deserializer.deserialize_any(StringOrVec(PhantomData))
 }
 
+fn hook_args<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut args = string_or_seq_string(deserializer)?;
+    if args.len() == 1 {
+        args = args[0].split_whitespace().map(String::from).collect();
+    }
+    Ok(Some(args))
+}
+
+// A file size can be given as a number of bytes or as a string with a unit,
+// like "500KB" or "2MiB". The "KB", "MB", and "GB" units are powers of 1000,
+// while "KiB", "MiB", and "GiB" are powers of 1024.
+fn file_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct FileSize;
+
+    impl<'de> de::Visitor<'de> for FileSize {
+        type Value = Option<u64>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str(r#"a number of bytes or a size like "500KB" or "2MiB""#)
+        }
+
+        fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            if value < 0 {
+                return Err(de::Error::invalid_value(
+                    de::Unexpected::Signed(value),
+                    &self,
+                ));
+            }
+            Ok(Some(value as u64))
+        }
+
+        fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            let trimmed = value.trim();
+            let split = trimmed
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(trimmed.len());
+            let (number, unit) = trimmed.split_at(split);
+            let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+                "" | "b" => 1,
+                "kb" => 1000,
+                "mb" => 1000 * 1000,
+                "gb" => 1000 * 1000 * 1000,
+                "kib" => 1024,
+                "mib" => 1024 * 1024,
+                "gib" => 1024 * 1024 * 1024,
+                _ => return Err(de::Error::invalid_value(de::Unexpected::Str(value), &self)),
+            };
+            number
+                .parse::<u64>()
+                .ok()
+                .and_then(|n| n.checked_mul(multiplier))
+                .map(Some)
+                .ok_or_else(|| de::Error::invalid_value(de::Unexpected::Str(value), &self))
+        }
+    }
+
+    deserializer.deserialize_any(FileSize)
+}
+
 fn u8_or_seq_u8<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
 where
     D: Deserializer<'de>,
@@ -158,7 +369,7 @@ where
         where
             E: de::Error,
         {
-            if value < 0 || value > std::u8::MAX as i16 {
+            if value < 0 || value > u8::MAX as i16 {
                 return Err(de::Error::invalid_type(
                     de::Unexpected::Signed(value as i64),
                     &"an integer from 0-255",
@@ -172,7 +383,7 @@ where
         where
             E: de::Error,
         {
-            if value < 0 || value > std::u8::MAX as i32 {
+            if value < 0 || value > u8::MAX as i32 {
                 return Err(de::Error::invalid_type(
                     de::Unexpected::Signed(value as i64),
                     &"an integer from 0-255",
@@ -186,7 +397,7 @@ where
         where
             E: de::Error,
         {
-            if value < 0 || value > std::u8::MAX as i64 {
+            if value < 0 || value > u8::MAX as i64 {
                 return Err(de::Error::invalid_type(
                     de::Unexpected::Signed(value),
                     &"an integer from 0-255",
@@ -207,7 +418,7 @@ where
         where
             E: de::Error,
         {
-            if value > std::u8::MAX as u16 {
+            if value > u8::MAX as u16 {
                 return Err(de::Error::invalid_type(
                     de::Unexpected::Unsigned(value as u64),
                     &"an integer from 0-255",
@@ -221,7 +432,7 @@ where
         where
             E: de::Error,
         {
-            if value > std::u8::MAX as u32 {
+            if value > u8::MAX as u32 {
                 return Err(de::Error::invalid_type(
                     de::Unexpected::Unsigned(value as u64),
                     &"an integer from 0-255",
@@ -235,7 +446,7 @@ where
         where
             E: de::Error,
         {
-            if value > std::u8::MAX as u64 {
+            if value > u8::MAX as u64 {
                 return Err(de::Error::invalid_type(
                     de::Unexpected::Unsigned(value),
                     &"an integer from 0-255",
@@ -340,60 +551,699 @@ where
 }
 
 impl Config {
-    pub fn new(file: PathBuf) -> Result<Config> {
-        match fs::read(&file) {
-            Err(e) => Err(ConfigError::FileCannotBeRead {
-                file,
-                error: e.to_string(),
+    // This loads a single config file, like a nested config, and applies the
+    // given profile if the file has one with that name.
+    pub fn new(file: PathBuf, profile: Option<&str>) -> Result<Config> {
+        let config_dir = Self::config_dir(&file);
+        Ok(Config {
+            config_dir,
+            ..Self::from_layers(&[file], profile)?
+        })
+    }
+
+    // This loads the project's config file along with the user's config
+    // file and the project's local config file, if they exist. Each layer is
+    // merged on top of the one before it, in this order:
+    //
+    // 1. The user's config, in `$XDG_CONFIG_HOME/precious/config.toml`.
+    // 2. The project's config file.
+    // 3. The `precious.local.toml` file next to the project's config file.
+    //
+    // If a profile is given, it is applied on top of all of these, and it's
+    // an error if none of them define it.
+    pub fn with_layers(file: PathBuf, profile: Option<&str>) -> Result<Config> {
+        let config_dir = Self::config_dir(&file);
+        let config = Config {
+            config_dir,
+            ..Self::from_layers(
+                &Self::layer_files(user_config_file(), file.clone()),
+                profile,
+            )?
+        };
+        if let Some(name) = profile {
+            if !config.profiles.contains_key(name) {
+                return Err(ConfigError::UnknownProfile {
+                    name: name.to_string(),
+                    file,
+                }
+                .into());
+            }
+        }
+        Ok(config)
+    }
+
+    fn config_dir(file: &Path) -> PathBuf {
+        match file.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+
+    fn layer_files(user: Option<PathBuf>, file: PathBuf) -> Vec<PathBuf> {
+        let local = file.with_file_name(LOCAL_CONFIG_FILE_NAME);
+        let mut files = vec![];
+        if let Some(user) = user.filter(|u| u.exists()) {
+            debug!("Loading user config from {}", user.display());
+            files.push(user);
+        }
+        files.push(file);
+        if local.exists() {
+            debug!("Loading local config from {}", local.display());
+            files.push(local);
+        }
+        files
+    }
+
+    fn from_layers(files: &[PathBuf], profile: Option<&str>) -> Result<Config> {
+        match files {
+            [file] if !is_embedded(file) => {
+                let table = read_config_table(file)?;
+                // We only go through the merge when we have to, since errors
+                // from a single file have the right line numbers.
+                if !table.contains_key("extends")
+                    && (profile.is_none() || !table.contains_key("profiles"))
+                {
+                    let bytes = read_config_file(file)?;
+                    return toml::from_slice(&bytes).map_err(|e| {
+                        ConfigError::InvalidConfigInFile {
+                            file: file.clone(),
+                            error: e.to_string(),
+                        }
+                        .into()
+                    });
+                }
+            }
+            _ => (),
+        }
+
+        let mut merged = Table::new();
+        let mut origins = HashMap::new();
+        for file in files {
+            let table = read_config_table(file)?;
+            let mut stack = vec![fs::canonicalize(file)?];
+            merge_tables(
+                &mut merged,
+                resolve_extends(file, table, &mut stack, &mut origins)?,
+            );
+        }
+        if let Some(name) = profile {
+            apply_profile(&mut merged, name, &mut origins);
+        }
+
+        if log::log_enabled!(log::Level::Debug) {
+            for (key, origin) in origins.iter().sorted_by_key(|(k, _)| *k) {
+                // We only log the keys that hold a setting, not the tables
+                // that hold commands. The keys inside profiles are logged
+                // under their merged name when the profile is applied.
+                if key == "commands"
+                    || key.matches('.').count() == 1 && key.starts_with("commands.")
+                    || key.starts_with("profiles.")
+                {
+                    continue;
+                }
+                match &origin.profile {
+                    Some(p) => debug!(
+                        "Config key {key} is set by the {p} profile in {}",
+                        origin.file.display(),
+                    ),
+                    None => debug!("Config key {key} is set in {}", origin.file.display()),
+                }
             }
-            .into()),
-            Ok(bytes) => Ok(toml::from_slice(&bytes)?),
         }
+
+        Self::from_merged(merged, &origins)
+    }
+
+    // The merged config is turned back into TOML text so that any error
+    // tells us the key it's for. We use that to find the file that set the
+    // key. The line and column don't mean anything after merging, so we
+    // replace those with the position of the key in that file. If a profile
+    // set the key, the error names the key under that profile instead.
+    fn from_merged(merged: Table, origins: &HashMap<String, Origin>) -> Result<Config> {
+        let text = toml::to_string(&Value::Table(merged))?;
+        toml::from_str(&text).map_err(|e| {
+            let error = ERROR_POSITION_RE.replace(&e.to_string(), "").into_owned();
+            let key = ERROR_KEY_RE.captures(&error).map(|c| c[1].to_string());
+            match key.and_then(|k| origin_of(&k, origins).map(|o| (k, o))) {
+                Some((key, origin)) => {
+                    let file = &origin.file;
+                    let source_key = origin.source_key(&key);
+                    let error = error.replace(&format!("`{key}`"), &format!("`{source_key}`"));
+                    let source =
+                        validate::Source::load(file, embedded_keys(file).unwrap_or_default());
+                    let error = match source.position(&source_key.split('.').collect::<Vec<_>>()) {
+                        Some((line, col)) => format!("{error} at line {line} column {col}"),
+                        None => error,
+                    };
+                    ConfigError::InvalidConfigInFile {
+                        file: file.clone(),
+                        error,
+                    }
+                    .into()
+                }
+                None => e.into(),
+            }
+        })
     }
 
     pub fn into_tidy_commands(
         self,
         project_root: &Path,
-        command: Option<&str>,
+        selection: &CommandSelection,
     ) -> Result<Vec<command::Command>> {
-        self.into_commands(project_root, command, CommandType::Tidy)
+        self.into_commands(project_root, selection, CommandType::Tidy)
     }
 
     pub fn into_lint_commands(
         self,
         project_root: &Path,
-        command: Option<&str>,
+        selection: &CommandSelection,
+    ) -> Result<Vec<command::Command>> {
+        self.into_commands(project_root, selection, CommandType::Lint)
+    }
+
+    pub fn into_commit_msg_commands(
+        self,
+        project_root: &Path,
+        selection: &CommandSelection,
     ) -> Result<Vec<command::Command>> {
-        self.into_commands(project_root, command, CommandType::Lint)
+        self.into_commands(project_root, selection, CommandType::CommitMsg)
+    }
+
+    // This is called once for each config file that commands are run from,
+    // so each command that uses the old keys gets one warning.
+    pub fn warn_about_deprecated_keys(&self) {
+        for (name, c) in &self.commands {
+            let options = match (c.run_mode, c.chdir) {
+                (None, None) => continue,
+                (Some(_), None) => "run_mode",
+                (None, Some(_)) => "chdir",
+                (Some(_), Some(_)) => "run_mode and chdir",
+            };
+            warn!(
+                "The {name} command is using deprecated config options: {options}. Run `precious config migrate` to update your config."
+            );
+        }
+    }
+
+    pub fn has_commit_msg_commands(&self) -> bool {
+        self.commands
+            .values()
+            .any(|c| c.typ == CommandType::CommitMsg && !c.disabled)
+    }
+
+    // The top-level `exclude` patterns can refer to vars and built-in
+    // variables, just like the patterns for a command.
+    pub fn interpolated_exclude(&self, project_root: &Path) -> Result<Vec<String>> {
+        Ok(
+            Interpolator::new(&self.vars, project_root, &self.config_dir)
+                .interpolate_all(self.exclude.clone(), "The top-level exclude key")?,
+        )
     }
 
     fn into_commands(
         self,
         project_root: &Path,
-        command: Option<&str>,
+        selection: &CommandSelection,
         typ: CommandType,
     ) -> Result<Vec<command::Command>> {
+        let defaults = CommandDefaults {
+            max_file_size: self.max_file_size,
+            skip_binary: self.skip_binary,
+        };
+        let interpolator = Interpolator::new(&self.vars, project_root, &self.config_dir);
         let mut commands: Vec<command::Command> = vec![];
         for (name, c) in self.commands.into_iter() {
-            if let Some(c) = command {
-                if name != c {
-                    continue;
-                }
+            if c.disabled {
+                debug!("The {name} command is disabled");
+                continue;
+            }
+            if !selection.selects(&name, &c.labels) {
+                debug!("The {name} command was not selected to run");
+                continue;
             }
-            if c.typ != typ && c.typ != CommandType::Both {
+            // Commands of type "both" never lint commit messages.
+            if c.typ != typ && (c.typ != CommandType::Both || typ == CommandType::CommitMsg) {
                 continue;
             }
 
-            commands.push(c.into_command(project_root, name)?);
+            commands.push(c.into_command(project_root, name, &defaults, &interpolator)?);
         }
 
         Ok(commands)
     }
 }
 
+// This is which commands to run, from the `--command`, `--label`, and
+// `--skip-command` flags. With no names or labels, every command is selected.
+// Otherwise a command is selected if its name is one of the names or it has
+// one of the labels. A skipped command is never selected.
+#[derive(Clone, Debug, Default)]
+pub struct CommandSelection {
+    pub names: Vec<String>,
+    pub labels: Vec<String>,
+    pub skip: Vec<String>,
+}
+
+impl CommandSelection {
+    fn selects(&self, name: &str, labels: &[String]) -> bool {
+        if self.skip.iter().any(|s| s == name) {
+            return false;
+        }
+        if self.names.is_empty() && self.labels.is_empty() {
+            return true;
+        }
+        self.names.iter().any(|n| n == name) || labels.iter().any(|l| self.labels.contains(l))
+    }
+
+    pub fn is_everything(&self) -> bool {
+        self.names.is_empty() && self.labels.is_empty() && self.skip.is_empty()
+    }
+
+    // Returns the `--command` and `--label` flags that don't match any
+    // command in any of the configs, which are most likely typos. A command
+    // that is disabled or is for some other action still counts as a match.
+    pub fn unmatched(&self, configs: &[&Config]) -> Vec<String> {
+        let names = self
+            .names
+            .iter()
+            .filter(|n| !configs.iter().any(|c| c.commands.contains_key(*n)))
+            .map(|n| format!("--command {n}"));
+        let labels = self
+            .labels
+            .iter()
+            .filter(|l| {
+                !configs
+                    .iter()
+                    .flat_map(|c| c.commands.values())
+                    .any(|c| c.labels.contains(l))
+            })
+            .map(|l| format!("--label {l}"));
+        names.chain(labels).collect()
+    }
+}
+
+impl fmt::Display for CommandSelection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            ("--command", &self.names),
+            ("--label", &self.labels),
+            ("--skip-command", &self.skip),
+        ];
+        f.write_str(
+            &flags
+                .iter()
+                .flat_map(|(flag, values)| values.iter().map(move |v| format!("{flag} {v}")))
+                .join(" "),
+        )
+    }
+}
+
+// Setting `$PRECIOUS_USER_CONFIG` overrides where the user's config is
+// found, and setting it to an empty string turns off the user's config. The
+// tests use this so that they don't depend on the config of whoever runs
+// them. Otherwise this follows the XDG base directory spec, which says to use
+// `$HOME/.config` when `$XDG_CONFIG_HOME` is not set.
+fn user_config_file() -> Option<PathBuf> {
+    if let Some(f) = env::var_os("PRECIOUS_USER_CONFIG") {
+        if f.is_empty() {
+            return None;
+        }
+        return Some(PathBuf::from(f));
+    }
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(d) if !d.is_empty() => PathBuf::from(d),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("precious").join("config.toml"))
+}
+
+// Returns the file in the given directory that holds the precious config, if
+// there is one. If both `precious.toml` and `.precious.toml` exist, the first
+// one wins, as it always has. Otherwise it's an error for a directory to have
+// more than one config.
+//
+// A file like `package.json` that can't be read or parsed is treated as not
+// having a config, since this is called for every directory above the files
+// we operate on, and those may include broken test fixtures. If that file is
+// the config, the error is reported when it's loaded.
+pub fn find_config_file(dir: &Path) -> Result<Option<PathBuf>> {
+    let mut sources = CONFIG_FILE_NAMES
+        .iter()
+        .map(|n| dir.join(n))
+        .find(|f| f.exists())
+        .map(|f| (f.display().to_string(), f))
+        .into_iter()
+        .collect::<Vec<_>>();
+    for (name, _) in EMBEDDED_CONFIGS {
+        let file = dir.join(name);
+        if !file.exists() {
+            continue;
+        }
+        match embedded_config(&file) {
+            Ok(Some((key, _))) => sources.push((format!("{} [{key}]", file.display()), file)),
+            Ok(None) => (),
+            Err(e) => match e.downcast_ref::<ConfigError>() {
+                Some(
+                    ConfigError::InvalidConfigInFile { .. } | ConfigError::FileCannotBeRead { .. },
+                ) => {
+                    debug!(
+                        "Not looking for a precious config in {}: {e}",
+                        file.display(),
+                    );
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+
+    if sources.len() > 1 {
+        return Err(ConfigError::MultipleConfigSources {
+            sources: sources.into_iter().map(|(s, _)| s).collect(),
+        }
+        .into());
+    }
+    Ok(sources.pop().map(|(_, f)| f))
+}
+
+// Returns the name of every file that can hold a precious config, including
+// local overrides and files with an embedded config.
+pub fn file_names() -> Vec<&'static str> {
+    CONFIG_FILE_NAMES
+        .iter()
+        .copied()
+        .chain([LOCAL_CONFIG_FILE_NAME])
+        .chain(EMBEDDED_CONFIGS.iter().map(|(n, _)| *n))
+        .collect()
+}
+
+fn is_embedded(file: &Path) -> bool {
+    embedded_keys(file).is_some()
+}
+
+fn embedded_keys(file: &Path) -> Option<&'static [&'static str]> {
+    let name = file.file_name()?;
+    EMBEDDED_CONFIGS
+        .iter()
+        .find(|(n, _)| name == *n)
+        .map(|(_, keys)| *keys)
+}
+
+// Returns the precious config embedded in a file like `Cargo.toml`, along
+// with the key it's under, or `None` if the file doesn't have one.
+fn embedded_config(file: &Path) -> Result<Option<(&'static str, Table)>> {
+    let keys = match embedded_keys(file) {
+        Some(k) => k,
+        None => return Ok(None),
+    };
+    let invalid = |error: String| ConfigError::InvalidConfigInFile {
+        file: file.to_path_buf(),
+        error,
+    };
+
+    let bytes = read_config_file(file)?;
+    let mut found = vec![];
+    if file.extension().is_some_and(|e| e == "json") {
+        // We only convert the "precious" value to TOML, since the rest of the
+        // file may have values, like null, that TOML can't represent.
+        let doc: serde_json::Value =
+            serde_json::from_slice(&bytes).map_err(|e| invalid(e.to_string()))?;
+        for key in keys {
+            if let Some(v) = doc.get(key) {
+                let table = serde_json::from_value::<Table>(v.clone())
+                    .map_err(|e| invalid(format!("the {key} key: {e}")))?;
+                found.push((*key, table));
+            }
+        }
+    } else {
+        let doc: Table = toml::from_slice(&bytes).map_err(|e| invalid(e.to_string()))?;
+        for key in keys {
+            let value = key
+                .split('.')
+                .try_fold(&doc, |t, k| t.get(k).and_then(Value::as_table));
+            if let Some(table) = value {
+                found.push((*key, table.clone()));
+            }
+        }
+    }
+
+    if found.len() > 1 {
+        return Err(ConfigError::MultipleConfigSources {
+            sources: found
+                .iter()
+                .map(|(key, _)| format!("{} [{key}]", file.display()))
+                .collect(),
+        }
+        .into());
+    }
+    Ok(found.pop())
+}
+
+// Reads the config table from a file and checks it for unknown keys.
+fn read_config_table(file: &Path) -> Result<Table> {
+    let table = match embedded_keys(file) {
+        Some(keys) => match embedded_config(file)? {
+            Some((_, table)) => table,
+            None => {
+                return Err(ConfigError::NoEmbeddedConfig {
+                    file: file.to_path_buf(),
+                    keys: keys.join(" or "),
+                }
+                .into())
+            }
+        },
+        None => {
+            let bytes = read_config_file(file)?;
+            toml::from_slice(&bytes).map_err(|e| ConfigError::InvalidConfigInFile {
+                file: file.to_path_buf(),
+                error: e.to_string(),
+            })?
+        }
+    };
+
+    let source = validate::Source::load(file, embedded_keys(file).unwrap_or_default());
+    validate::check_unknown_keys(file, &table, &source)?;
+
+    Ok(table)
+}
+
+fn read_config_file(file: &Path) -> Result<Vec<u8>> {
+    fs::read(file).map_err(|e| {
+        ConfigError::FileCannotBeRead {
+            file: file.to_path_buf(),
+            error: e.to_string(),
+        }
+        .into()
+    })
+}
+
+// Returns the config in the given file merged on top of all the files it
+// extends, in order. Paths in `extends` are relative to the directory of the
+// file they're in. The `stack` holds the canonical path of every file we're
+// in the middle of resolving, which is how we find cycles. The `origins`
+// record which file each key in the merged config came from.
+fn resolve_extends(
+    file: &Path,
+    mut table: Table,
+    stack: &mut Vec<PathBuf>,
+    origins: &mut HashMap<String, Origin>,
+) -> Result<Table> {
+    let extends = match table.remove("extends") {
+        None => vec![],
+        Some(Value::String(s)) => vec![s],
+        Some(Value::Array(a)) => a
+            .into_iter()
+            .map(|v| match v {
+                Value::String(s) => Ok(s),
+                _ => Err(ConfigError::InvalidExtends {
+                    file: file.to_path_buf(),
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => {
+            return Err(ConfigError::InvalidExtends {
+                file: file.to_path_buf(),
+            }
+            .into())
+        }
+    };
+
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    let mut merged = Table::new();
+    for e in extends {
+        let base = dir.join(e);
+        debug!("{} extends {}", file.display(), base.display());
+        let base_table = read_config_table(&base)?;
+        let canonical = fs::canonicalize(&base)?;
+        if stack.contains(&canonical) {
+            let mut files = stack.clone();
+            files.push(canonical);
+            return Err(ConfigError::ExtendsCycle { files }.into());
+        }
+        stack.push(canonical);
+        let base_table = resolve_extends(&base, base_table, stack, origins)?;
+        stack.pop();
+        merge_tables(&mut merged, base_table);
+    }
+
+    record_origins(file, &table, origins);
+    merge_tables(&mut merged, table);
+
+    Ok(merged)
+}
+
+// Top-level keys in `from` replace the same key in `into`. Commands are
+// merged by name, one key at a time, so a file can change a single key of a
+// command it inherits. A command keeps its place in the order it was first
+// defined in. Vars are merged one var at a time, and profiles are merged by
+// name in the same way as the whole config.
+fn merge_tables(into: &mut Table, from: Table) {
+    for (key, value) in from {
+        match (key.as_str(), into.get_mut(&key), value) {
+            ("vars", Some(Value::Table(into_vars)), Value::Table(from_vars)) => {
+                into_vars.extend(from_vars);
+            }
+            ("profiles", Some(Value::Table(into_profiles)), Value::Table(from_profiles)) => {
+                for (name, profile) in from_profiles {
+                    match (into_profiles.get_mut(&name), profile) {
+                        (Some(Value::Table(into_profile)), Value::Table(from_profile)) => {
+                            merge_tables(into_profile, from_profile);
+                        }
+                        (_, profile) => {
+                            into_profiles.insert(name, profile);
+                        }
+                    }
+                }
+            }
+            ("commands", Some(Value::Table(into_commands)), Value::Table(from_commands)) => {
+                for (name, command) in from_commands {
+                    match (into_commands.get_mut(&name), command) {
+                        (Some(Value::Table(into_command)), Value::Table(from_command)) => {
+                            into_command.extend(from_command);
+                        }
+                        (_, command) => {
+                            into_commands.insert(name, command);
+                        }
+                    }
+                }
+            }
+            (_, _, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+// A profile is merged on top of the rest of the config in the same way as
+// another layer, so it can change a single key of a command, turn a command on
+// or off with `disabled`, or add a new command. Each key the profile sets now
+// comes from the profile, so we record that in the `origins`.
+fn apply_profile(merged: &mut Table, name: &str, origins: &mut HashMap<String, Origin>) {
+    let profile = match merged.get("profiles").and_then(|p| p.get(name)) {
+        Some(Value::Table(p)) => p.clone(),
+        _ => return,
+    };
+    debug!("Applying the {name} profile");
+    merge_tables(merged, profile);
+
+    let prefix = format!("profiles.{name}.");
+    let applied = origins
+        .iter()
+        .filter_map(|(key, origin)| {
+            let origin = Origin {
+                file: origin.file.clone(),
+                profile: Some(name.to_string()),
+            };
+            Some((key.strip_prefix(&prefix)?.to_string(), origin))
+        })
+        .collect::<Vec<_>>();
+    origins.extend(applied);
+}
+
+// This is where a key in the merged config was set. For a key set by a
+// profile, the `profile` is the name of that profile.
+#[derive(Clone, Debug)]
+struct Origin {
+    file: PathBuf,
+    profile: Option<String>,
+}
+
+impl Origin {
+    // Returns the path of the key in the file that set it.
+    fn source_key(&self, key: &str) -> String {
+        match &self.profile {
+            Some(p) => format!("profiles.{p}.{key}"),
+            None => key.to_string(),
+        }
+    }
+}
+
+// Records the top-level keys, the commands, and each key of each command,
+// along with the same keys inside each profile, like
+// "profiles.ci.commands.foo.lint_flags".
+fn record_origins(file: &Path, table: &Table, origins: &mut HashMap<String, Origin>) {
+    record_keys(file, "", table, origins);
+    if let Some(Value::Table(profiles)) = table.get("profiles") {
+        for (name, profile) in profiles {
+            if let Value::Table(profile) = profile {
+                record_keys(file, &format!("profiles.{name}."), profile, origins);
+            }
+        }
+    }
+}
+
+fn record_keys(file: &Path, prefix: &str, table: &Table, origins: &mut HashMap<String, Origin>) {
+    let origin = Origin {
+        file: file.to_path_buf(),
+        profile: None,
+    };
+    for (key, value) in table {
+        origins.insert(format!("{prefix}{key}"), origin.clone());
+        if let ("commands", Value::Table(commands)) = (key.as_str(), value) {
+            for (name, command) in commands {
+                let command_key = format!("{prefix}commands.{name}");
+                origins.insert(command_key.clone(), origin.clone());
+                if let Value::Table(command) = command {
+                    for k in command.keys() {
+                        origins.insert(format!("{command_key}.{k}"), origin.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Finds the origin for the longest prefix of the key that we have a record
+// of. The key in an error may be for a nested value, like
+// "commands.foo.env.BAR".
+fn origin_of<'a>(key: &str, origins: &'a HashMap<String, Origin>) -> Option<&'a Origin> {
+    let parts = key.split('.').collect::<Vec<_>>();
+    (1..=parts.len())
+        .rev()
+        .find_map(|n| origins.get(&parts[..n].join(".")))
+}
+
+// These are top-level settings which each command can override.
+#[derive(Debug, Default)]
+struct CommandDefaults {
+    max_file_size: Option<u64>,
+    skip_binary: bool,
+}
+
 impl CommandConfig {
-    fn into_command(self, project_root: &Path, name: String) -> Result<command::Command> {
-        let n = command::Command::new(self.into_command_params(project_root, name)?)?;
+    fn into_command(
+        self,
+        project_root: &Path,
+        name: String,
+        defaults: &CommandDefaults,
+        interpolator: &Interpolator,
+    ) -> Result<command::Command> {
+        let n = command::Command::new(self.into_command_params(
+            project_root,
+            name,
+            defaults,
+            interpolator,
+        )?)?;
         Ok(n)
     }
 
@@ -401,7 +1251,17 @@ impl CommandConfig {
         self,
         project_root: &Path,
         name: String,
+        defaults: &CommandDefaults,
+        interpolator: &Interpolator,
     ) -> Result<command::CommandParams> {
+        if self.include.is_empty()
+            && self.include_types.is_empty()
+            && self.include_shebang.is_empty()
+            && self.typ != CommandType::CommitMsg
+        {
+            return Err(ConfigError::CommandRequiresInclude { name }.into());
+        }
+
         let (invoke, working_dir, path_args) = Self::invoke_args(
             &name,
             self.run_mode,
@@ -410,20 +1270,42 @@ impl CommandConfig {
             self.working_dir,
             self.path_args,
         )?;
+        if self.once_per_shard && invoke != Invoke::Once {
+            return Err(ConfigError::OncePerShardRequiresInvokeOnce { name }.into());
+        }
+
+        let context = format!("The {name} command");
+        let i = interpolator;
+        let working_dir = match working_dir {
+            WorkingDir::ChdirTo(dir) => WorkingDir::ChdirTo(i.interpolate_path(&dir, &context)?),
+            wd => wd,
+        };
+        let env = self
+            .env
+            .into_iter()
+            .map(|(k, v)| Ok((k, i.interpolate(&v, &context)?)))
+            .collect::<Result<HashMap<_, _>, ConfigError>>()?;
+
         Ok(command::CommandParams {
             project_root: project_root.to_owned(),
             name,
             typ: self.typ,
-            include: self.include,
-            exclude: self.exclude,
+            include: i.interpolate_all(self.include, &context)?,
+            include_types: self.include_types,
+            include_shebang: self.include_shebang,
+            exclude: i.interpolate_all(self.exclude, &context)?,
+            exclude_attributes: self.exclude_attributes,
             invoke,
             working_dir,
             path_args,
-            cmd: self.cmd,
-            env: self.env,
-            lint_flags: self.lint_flags,
-            tidy_flags: self.tidy_flags,
-            path_flag: self.path_flag,
+            once_per_shard: self.once_per_shard,
+            max_file_size: self.max_file_size.or(defaults.max_file_size),
+            skip_binary: self.skip_binary.unwrap_or(defaults.skip_binary),
+            cmd: i.interpolate_all(self.cmd, &context)?,
+            env,
+            lint_flags: i.interpolate_all(self.lint_flags, &context)?,
+            tidy_flags: i.interpolate_all(self.tidy_flags, &context)?,
+            path_flag: i.interpolate(&self.path_flag, &context)?,
             ok_exit_codes: self.ok_exit_codes,
             lint_failure_exit_codes: self.lint_failure_exit_codes,
             expect_stderr: self.expect_stderr,
@@ -448,36 +1330,8 @@ impl CommandConfig {
             .into());
         }
 
-        // This translates the old config options into their equivalent new
-        // options.
         if run_mode.is_some() || chdir.is_some() {
-            // let options = match (run_mode, chdir) {
-            //     (Some(_), None) => "run_mode",
-            //     (None, Some(_)) => "chdir",
-            //     _ => "run_mode and chdir",
-            // };
-            // warn!("The {name} command is using deprecated config options: {options}");
-
-            match (run_mode, chdir) {
-                (Some(OldRunMode::Files) | None, Some(false) | None) => {
-                    return Ok((Invoke::PerFile, WorkingDir::Root, PathArgs::File))
-                }
-                (Some(OldRunMode::Files) | None, Some(true)) => {
-                    return Ok((Invoke::PerFile, WorkingDir::Dir, PathArgs::File))
-                }
-                (Some(OldRunMode::Dirs), Some(false) | None) => {
-                    return Ok((Invoke::PerDir, WorkingDir::Root, PathArgs::Dir))
-                }
-                (Some(OldRunMode::Dirs), Some(true)) => {
-                    return Ok((Invoke::PerDir, WorkingDir::Dir, PathArgs::None))
-                }
-                (Some(OldRunMode::Root), Some(false) | None) => {
-                    return Ok((Invoke::Once, WorkingDir::Root, PathArgs::Dot))
-                }
-                (Some(OldRunMode::Root), Some(true)) => {
-                    return Ok((Invoke::Once, WorkingDir::Root, PathArgs::None))
-                }
-            }
+            return Ok(Self::old_invoke_args(run_mode, chdir));
         }
 
         let invoke = invoke.unwrap_or(Invoke::PerFile);
@@ -485,17 +1339,15 @@ impl CommandConfig {
         let path_args = path_args.unwrap_or(PathArgs::File);
 
         match (invoke, &working_dir, path_args) {
-            (Invoke::PerFile, _, path_args) => {
-                if path_args != PathArgs::File && path_args != PathArgs::AbsoluteFile {
-                    return Err(ConfigError::CannotInvokePerFileWithPathArgs { path_args }.into());
-                }
+            (Invoke::PerFile, _, path_args)
+                if path_args != PathArgs::File && path_args != PathArgs::AbsoluteFile =>
+            {
+                return Err(ConfigError::CannotInvokePerFileWithPathArgs { path_args }.into());
             }
-            (Invoke::PerDir, &WorkingDir::Root | &WorkingDir::ChdirTo(_), path_args) => {
-                if path_args == PathArgs::Dot || path_args == PathArgs::None {
-                    return Err(
-                        ConfigError::CannotInvokePerDirInRootWithPathArgs { path_args }.into(),
-                    );
-                }
+            (Invoke::PerDir, &WorkingDir::Root | &WorkingDir::ChdirTo(_), path_args)
+                if path_args == PathArgs::Dot || path_args == PathArgs::None =>
+            {
+                return Err(ConfigError::CannotInvokePerDirInRootWithPathArgs { path_args }.into());
             }
             (Invoke::Once, &WorkingDir::Dir, _) => {
                 return Err(ConfigError::CannotInvokeOnceWithWorkingDirEqDir.into());
@@ -505,13 +1357,42 @@ impl CommandConfig {
 
         Ok((invoke, working_dir, path_args))
     }
+
+    // This translates the old config options into their equivalent new
+    // options. The warning about the old options is given when the config is
+    // loaded, so `precious config migrate` can use this without warning.
+    fn old_invoke_args(
+        run_mode: Option<OldRunMode>,
+        chdir: Option<bool>,
+    ) -> (Invoke, WorkingDir, PathArgs) {
+        match (run_mode, chdir) {
+            (Some(OldRunMode::Files) | None, Some(false) | None) => {
+                (Invoke::PerFile, WorkingDir::Root, PathArgs::File)
+            }
+            (Some(OldRunMode::Files) | None, Some(true)) => {
+                (Invoke::PerFile, WorkingDir::Dir, PathArgs::File)
+            }
+            (Some(OldRunMode::Dirs), Some(false) | None) => {
+                (Invoke::PerDir, WorkingDir::Root, PathArgs::Dir)
+            }
+            (Some(OldRunMode::Dirs), Some(true)) => {
+                (Invoke::PerDir, WorkingDir::Dir, PathArgs::None)
+            }
+            (Some(OldRunMode::Root), Some(false) | None) => {
+                (Invoke::Once, WorkingDir::Root, PathArgs::Dot)
+            }
+            (Some(OldRunMode::Root), Some(true)) => {
+                (Invoke::Once, WorkingDir::Root, PathArgs::None)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
-    use serial_test::parallel;
+    use serial_test::{parallel, serial};
     use test_case::test_case;
 
     #[test_case(
@@ -620,11 +1501,14 @@ mod tests {
         }
 
         let config: Config = toml::from_str(&toml_text)?;
+        let interpolator = Interpolator::new(&config.vars, root, root);
         let params = config
             .commands
             .into_iter()
             .next()
-            .map(|(name, conf)| conf.into_command_params(root, name))
+            .map(|(name, conf)| {
+                conf.into_command_params(root, name, &CommandDefaults::default(), &interpolator)
+            })
             .unwrap()?;
         assert_eq!(params.invoke, invoke, "invoke");
         assert_eq!(params.working_dir, working_dir, "working_dir");
@@ -837,8 +1721,16 @@ mod tests {
             invoke: Some(invoke),
             working_dir: Some(working_dir),
             path_args: Some(path_args),
+            once_per_shard: false,
+            max_file_size: None,
+            skip_binary: None,
+            disabled: false,
+            labels: vec![],
             include: vec![String::from("**/*.rs")],
+            include_types: vec![],
+            include_shebang: vec![],
             exclude: vec![],
+            exclude_attributes: vec![],
             run_mode: None,
             chdir: None,
             cmd: vec![String::from("some-linter")],
@@ -851,10 +1743,1177 @@ mod tests {
             expect_stderr: false,
             ignore_stderr: vec![],
         };
-        let res = config.into_command(Path::new("."), String::from("some-linter"));
+        let vars = IndexMap::new();
+        let res = config.into_command(
+            Path::new("."),
+            String::from("some-linter"),
+            &CommandDefaults::default(),
+            &Interpolator::new(&vars, Path::new("."), Path::new(".")),
+        );
         let err = res.unwrap_err().downcast::<ConfigError>().unwrap();
         assert_eq!(err, expect_err);
 
         Ok(())
     }
+
+    #[test_case("max_file_size = 100", Some(100); "integer")]
+    #[test_case(r#"max_file_size = "100""#, Some(100); "string without unit")]
+    #[test_case(r#"max_file_size = "500KB""#, Some(500_000); "kilobytes")]
+    #[test_case(r#"max_file_size = "2 MiB""#, Some(2 * 1024 * 1024); "mebibytes with space")]
+    #[test_case(r#"max_file_size = "1gb""#, Some(1_000_000_000); "lowercase gigabytes")]
+    #[test_case("", None; "not set")]
+    #[parallel]
+    fn max_file_size(setting: &str, expect: Option<u64>) -> Result<()> {
+        let config: Config = toml::from_str(&format!("{setting}\n[commands]\n"))?;
+        assert_eq!(config.max_file_size, expect);
+        Ok(())
+    }
+
+    #[test_case("max_file_size = -1"; "negative")]
+    #[test_case(r#"max_file_size = "12XB""#; "unknown unit")]
+    #[test_case(r#"max_file_size = "MB""#; "no number")]
+    #[test_case(r#"max_file_size = "99999999999GB""#; "overflow")]
+    #[parallel]
+    fn invalid_max_file_size(setting: &str) {
+        assert!(toml::from_str::<Config>(&format!("{setting}\n[commands]\n")).is_err());
+    }
+
+    #[test]
+    #[parallel]
+    fn commands_inherit_file_content_settings() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+max_file_size = "1MB"
+skip_binary   = true
+
+[commands.inherits]
+type          = "lint"
+include       = "**/*"
+cmd           = ["true"]
+ok_exit_codes = 0
+
+[commands.overrides]
+type          = "lint"
+include       = "**/*"
+max_file_size = 10
+skip_binary   = false
+cmd           = ["true"]
+ok_exit_codes = 0
+"#,
+        )?;
+        let defaults = CommandDefaults {
+            max_file_size: config.max_file_size,
+            skip_binary: config.skip_binary,
+        };
+
+        let interpolator = Interpolator::new(&config.vars, Path::new("."), Path::new("."));
+        let params = config
+            .commands
+            .into_iter()
+            .map(|(name, c)| c.into_command_params(Path::new("."), name, &defaults, &interpolator))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            params
+                .iter()
+                .map(|p| (p.max_file_size, p.skip_binary))
+                .collect::<Vec<_>>(),
+            vec![(Some(1_000_000), true), (Some(10), false)],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn once_per_shard_requires_invoke_once() -> Result<()> {
+        let config = r#"
+[commands.per-file]
+type           = "lint"
+include        = "**/*"
+once_per_shard = true
+cmd            = ["true"]
+ok_exit_codes  = 0
+"#;
+        let err = toml::from_str::<Config>(config)?
+            .into_lint_commands(Path::new("."), &CommandSelection::default())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ConfigError::OncePerShardRequiresInvokeOnce {
+                name: String::from("per-file"),
+            }),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn include_is_only_optional_for_commit_msg_commands() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+[commands.linter]
+type = "lint"
+cmd = ["some-linter"]
+ok_exit_codes = 0
+
+[commands.message]
+type = "commit-msg"
+cmd = ["some-message-linter"]
+ok_exit_codes = 0
+"#,
+        )?;
+        assert!(config.has_commit_msg_commands());
+
+        let commands = config
+            .clone()
+            .into_commit_msg_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["message"],
+        );
+
+        let err = config
+            .into_lint_commands(Path::new("."), &CommandSelection::default())
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        assert_eq!(
+            err,
+            ConfigError::CommandRequiresInclude {
+                name: String::from("linter"),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn include_types_or_shebang_can_replace_include() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+[commands.perlcritic]
+type = "lint"
+include_types = ["perl"]
+cmd = ["perlcritic"]
+ok_exit_codes = 0
+
+[commands.shellcheck]
+type = "lint"
+include_shebang = ["bash", "sh"]
+cmd = ["shellcheck"]
+ok_exit_codes = 0
+"#,
+        )?;
+
+        let commands = config.into_lint_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["perlcritic", "shellcheck"],
+        );
+
+        Ok(())
+    }
+
+    const BASE_CONFIG: &str = r#"
+exclude = "vendor/**/*"
+
+[commands.rustfmt]
+type          = "both"
+include       = "**/*.rs"
+cmd           = ["rustfmt", "--edition", "2021"]
+lint_flags    = "--check"
+ok_exit_codes = 0
+
+[commands.clippy]
+type          = "lint"
+include       = "**/*.rs"
+invoke        = "once"
+path_args     = "none"
+cmd           = ["cargo", "clippy"]
+ok_exit_codes = 0
+
+[commands.omegasort]
+type          = "both"
+include       = "**/.gitignore"
+cmd           = ["omegasort"]
+lint_flags    = "--check"
+tidy_flags    = "--in-place"
+ok_exit_codes = 0
+"#;
+
+    fn write_configs(files: &[(&str, &str)]) -> Result<tempfile::TempDir> {
+        let td = tempfile::tempdir()?;
+        for (name, content) in files {
+            let path = td.path().join(name);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, content)?;
+        }
+        Ok(td)
+    }
+
+    #[test]
+    #[parallel]
+    fn extends_merges_commands_by_name() -> Result<()> {
+        let td = write_configs(&[
+            ("shared/base.toml", BASE_CONFIG),
+            (
+                "shared/more.toml",
+                r#"
+[commands.prettier]
+type          = "both"
+include       = "**/*.md"
+cmd           = ["prettier"]
+lint_flags    = "--check"
+tidy_flags    = "--write"
+ok_exit_codes = 0
+"#,
+            ),
+            (
+                "repo/precious.toml",
+                r#"
+extends = ["../shared/base.toml", "../shared/more.toml"]
+
+[commands.typos]
+type          = "lint"
+include       = "**/*"
+cmd           = ["typos"]
+ok_exit_codes = 0
+
+[commands.rustfmt]
+cmd = ["rustfmt", "--edition", "2018"]
+
+[commands.clippy]
+disabled = true
+"#,
+            ),
+        ])?;
+
+        let config = Config::new(td.path().join("repo/precious.toml"), None)?;
+        assert_eq!(config.exclude, vec![String::from("vendor/**/*")]);
+        assert_eq!(
+            config.commands.keys().collect::<Vec<_>>(),
+            vec!["rustfmt", "clippy", "omegasort", "prettier", "typos"],
+        );
+        assert_eq!(
+            config.commands["rustfmt"].cmd,
+            vec!["rustfmt", "--edition", "2018"],
+        );
+        assert_eq!(config.commands["rustfmt"].lint_flags, vec!["--check"]);
+
+        let commands = config.into_lint_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["rustfmt", "omegasort", "prettier", "typos"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn extends_cycle_is_an_error() -> Result<()> {
+        let td = write_configs(&[
+            ("a.toml", r#"extends = "b.toml""#),
+            ("b.toml", r#"extends = ["c.toml"]"#),
+            ("c.toml", r#"extends = "a.toml""#),
+        ])?;
+
+        let err = Config::new(td.path().join("a.toml"), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        let root = fs::canonicalize(td.path())?;
+        assert_eq!(
+            err,
+            ConfigError::ExtendsCycle {
+                files: ["a.toml", "b.toml", "c.toml", "a.toml"]
+                    .iter()
+                    .map(|f| root.join(f))
+                    .collect(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn extends_error_names_the_file_with_the_bad_key() -> Result<()> {
+        let td = write_configs(&[
+            (
+                "base.toml",
+                &format!("{BASE_CONFIG}\n[commands.bad]\ntype = \"lint\"\ninvoke = \"never\"\n"),
+            ),
+            (
+                "precious.toml",
+                r#"
+extends = "base.toml"
+
+[commands.bad]
+include       = "**/*"
+cmd           = ["bad"]
+ok_exit_codes = 0
+"#,
+            ),
+        ])?;
+
+        let err = Config::new(td.path().join("precious.toml"), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        match err {
+            ConfigError::InvalidConfigInFile { file, error } => {
+                assert_eq!(file, td.path().join("base.toml"));
+                assert!(
+                    error.contains("for key `commands.bad.invoke`"),
+                    "error names the key: {error}",
+                );
+            }
+            e => panic!("unexpected error: {e}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn invalid_extends() -> Result<()> {
+        let td = write_configs(&[("precious.toml", "extends = 42")])?;
+        let err = Config::new(td.path().join("precious.toml"), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        assert_eq!(
+            err,
+            ConfigError::InvalidExtends {
+                file: td.path().join("precious.toml"),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn layers_are_merged_in_order() -> Result<()> {
+        let td = write_configs(&[
+            (
+                "home/precious/config.toml",
+                r#"
+jobs = 2
+
+[commands.mine]
+type          = "lint"
+include       = "**/*"
+cmd           = ["my-linter"]
+ok_exit_codes = 0
+"#,
+            ),
+            ("project/precious.toml", BASE_CONFIG),
+            (
+                "project/precious.local.toml",
+                r#"
+jobs = 4
+
+[commands.clippy]
+disabled = true
+"#,
+            ),
+        ])?;
+
+        let files = Config::layer_files(
+            Some(td.path().join("home/precious/config.toml")),
+            td.path().join("project/precious.toml"),
+        );
+        assert_eq!(
+            files,
+            vec![
+                td.path().join("home/precious/config.toml"),
+                td.path().join("project/precious.toml"),
+                td.path().join("project/precious.local.toml"),
+            ],
+        );
+
+        let config = Config::from_layers(&files, None)?;
+        assert_eq!(config.jobs, Some(4));
+        let commands = config.into_lint_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["mine", "rustfmt", "omegasort"],
+        );
+
+        Ok(())
+    }
+
+    const PROFILE_CONFIG: &str = r#"
+jobs = 2
+
+[commands.slow-check]
+type          = "lint"
+include       = "**/*.rs"
+cmd           = ["slow-check"]
+ok_exit_codes = 0
+disabled      = true
+
+[profiles.ci]
+jobs = 8
+
+[profiles.ci.commands.clippy]
+lint_flags = ["--", "--deny", "warnings"]
+
+[profiles.ci.commands.slow-check]
+disabled = false
+
+[profiles.ci.commands.omegasort]
+disabled = true
+
+[profiles.ci.commands.typos]
+type          = "lint"
+include       = "**/*"
+cmd           = ["typos"]
+ok_exit_codes = 0
+"#;
+
+    #[test]
+    #[parallel]
+    fn profile_overrides_commands_and_jobs() -> Result<()> {
+        let td = write_configs(&[
+            ("base.toml", BASE_CONFIG),
+            (
+                "precious.toml",
+                &format!("extends = \"base.toml\"\n{PROFILE_CONFIG}"),
+            ),
+        ])?;
+        let file = td.path().join("precious.toml");
+
+        let config = Config::new(file.clone(), None)?;
+        assert_eq!(config.jobs, Some(2));
+        assert!(config.commands["clippy"].lint_flags.is_empty());
+        let commands = config.into_lint_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["rustfmt", "clippy", "omegasort"],
+        );
+
+        let config = Config::new(file, Some("ci"))?;
+        assert_eq!(config.jobs, Some(8));
+        assert_eq!(
+            config.commands["clippy"].lint_flags,
+            vec!["--", "--deny", "warnings"],
+        );
+        assert_eq!(config.commands["clippy"].cmd, vec!["cargo", "clippy"]);
+        let commands = config.into_lint_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["rustfmt", "clippy", "slow-check", "typos"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn profiles_are_merged_across_layers() -> Result<()> {
+        let td = write_configs(&[
+            (
+                "precious.toml",
+                &format!("{BASE_CONFIG}\n[profiles.ci]\njobs = 8\n"),
+            ),
+            (
+                "precious.local.toml",
+                "[profiles.ci.commands.rustfmt]\ndisabled = true\n",
+            ),
+        ])?;
+
+        let files = Config::layer_files(None, td.path().join("precious.toml"));
+        let config = Config::from_layers(&files, Some("ci"))?;
+        assert_eq!(config.jobs, Some(8));
+        let commands = config.into_lint_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["clippy", "omegasort"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn profile_error_names_the_key_in_the_profile() -> Result<()> {
+        let td = write_configs(&[
+            ("precious.toml", BASE_CONFIG),
+            (
+                "precious.local.toml",
+                "jobs = 2\n\n[profiles.ci.commands.clippy]\nskip_binary = \"yes\"\n",
+            ),
+        ])?;
+
+        let files = Config::layer_files(None, td.path().join("precious.toml"));
+        let err = Config::from_layers(&files, Some("ci"))
+            .unwrap_err()
+            .downcast::<ConfigError>()?;
+        assert_eq!(
+            err,
+            ConfigError::InvalidConfigInFile {
+                file: td.path().join("precious.local.toml"),
+                error: "invalid type: string \"yes\", expected a boolean for key `profiles.ci.commands.clippy.skip_binary` at line 4 column 1".to_string(),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn user_config_file_can_be_overridden() {
+        let old = (
+            env::var_os("PRECIOUS_USER_CONFIG"),
+            env::var_os("XDG_CONFIG_HOME"),
+        );
+
+        env::set_var("PRECIOUS_USER_CONFIG", "/some/dir/precious.toml");
+        assert_eq!(
+            user_config_file(),
+            Some(PathBuf::from("/some/dir/precious.toml")),
+        );
+
+        env::set_var("PRECIOUS_USER_CONFIG", "");
+        assert_eq!(user_config_file(), None);
+
+        env::remove_var("PRECIOUS_USER_CONFIG");
+        env::set_var("XDG_CONFIG_HOME", "/xdg");
+        assert_eq!(
+            user_config_file(),
+            Some(PathBuf::from("/xdg/precious/config.toml")),
+        );
+
+        restore_env_var("PRECIOUS_USER_CONFIG", old.0);
+        restore_env_var("XDG_CONFIG_HOME", old.1);
+    }
+
+    fn restore_env_var(name: &str, value: Option<std::ffi::OsString>) {
+        match value {
+            Some(v) => env::set_var(name, v),
+            None => env::remove_var(name),
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn unknown_profile_is_an_error() -> Result<()> {
+        let td = write_configs(&[("precious.toml", PROFILE_CONFIG)])?;
+        let file = td.path().join("precious.toml");
+
+        let old = env::var_os("PRECIOUS_USER_CONFIG");
+        env::set_var("PRECIOUS_USER_CONFIG", "");
+        let err = Config::with_layers(file.clone(), Some("nope"));
+        restore_env_var("PRECIOUS_USER_CONFIG", old);
+        let err = err.unwrap_err().downcast::<ConfigError>()?;
+        assert_eq!(
+            err,
+            ConfigError::UnknownProfile {
+                name: "nope".to_string(),
+                file,
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn unknown_key_in_profile_is_an_error() -> Result<()> {
+        let td = write_configs(&[(
+            "precious.toml",
+            &format!("{BASE_CONFIG}\n[profiles.ci]\njob = 8\n\n[profiles.ci.commands.clippy]\nlint_flag = \"--deny\"\n"),
+        )])?;
+        let file = td.path().join("precious.toml");
+
+        let err = Config::new(file.clone(), Some("ci"))
+            .unwrap_err()
+            .downcast::<ConfigError>()?;
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Unknown key `profiles.ci.job` in {f} at line 28 column 1. Did you mean `jobs`?\nUnknown key `profiles.ci.commands.clippy.lint_flag` in {f} at line 31 column 1. Did you mean `lint_flags`?\nSet allow_unknown_keys = true in the file to only warn about unknown keys.",
+                f = file.display(),
+            ),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn toml_error_format() {
+        let err = toml::from_str::<Config>(
+            "jobs = 1\n\n[commands.foo]\ntype = \"lint\"\ninclude = \"*\"\ncmd = \"foo\"\nok_exit_codes = 0\nskip_binary = \"yes\"\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            err,
+            "invalid type: string \"yes\", expected a boolean for key `commands.foo.skip_binary` at line 8 column 15",
+        );
+        assert_eq!(
+            ERROR_POSITION_RE.replace(&err, ""),
+            "invalid type: string \"yes\", expected a boolean for key `commands.foo.skip_binary`",
+        );
+        assert_eq!(
+            &ERROR_KEY_RE.captures(&err).unwrap()[1],
+            "commands.foo.skip_binary",
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn missing_layers_are_skipped() -> Result<()> {
+        let td = write_configs(&[("project/precious.toml", BASE_CONFIG)])?;
+        let files = Config::layer_files(
+            Some(td.path().join("home/precious/config.toml")),
+            td.path().join("project/precious.toml"),
+        );
+        assert_eq!(files, vec![td.path().join("project/precious.toml")]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn layer_error_names_the_file() -> Result<()> {
+        let td = write_configs(&[
+            ("precious.toml", BASE_CONFIG),
+            ("precious.local.toml", "jobs = \"many\"\n"),
+        ])?;
+
+        let files = Config::layer_files(None, td.path().join("precious.toml"));
+        let err = Config::from_layers(&files, None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        match err {
+            ConfigError::InvalidConfigInFile { file, .. } => {
+                assert_eq!(file, td.path().join("precious.local.toml"));
+            }
+            e => panic!("unexpected error: {e}"),
+        }
+
+        Ok(())
+    }
+
+    const EMBEDDED_TOML_COMMAND: &str = r#"
+[commands.rustfmt]
+type          = "both"
+include       = "**/*.rs"
+cmd           = ["rustfmt"]
+lint_flags    = "--check"
+ok_exit_codes = 0
+"#;
+
+    const PACKAGE_JSON: &str = r#"{
+    "name": "some-package",
+    "private": null,
+    "precious": {
+        "commands": {
+            "rustfmt": {
+                "type": "both",
+                "include": "**/*.rs",
+                "cmd": ["rustfmt"],
+                "lint_flags": "--check",
+                "ok_exit_codes": 0
+            }
+        }
+    }
+}
+"#;
+
+    #[test_case("Cargo.toml", "[package]\nname = \"foo\"\n", "package.metadata.precious"; "Cargo.toml package")]
+    #[test_case("Cargo.toml", "[workspace]\nmembers = []\n", "workspace.metadata.precious"; "Cargo.toml workspace")]
+    #[test_case("pyproject.toml", "[project]\nname = \"foo\"\n", "tool.precious"; "pyproject.toml")]
+    #[test_case("package.json", PACKAGE_JSON, ""; "package.json")]
+    #[parallel]
+    fn embedded_config(name: &str, content: &str, table: &str) -> Result<()> {
+        let content = if table.is_empty() {
+            content.to_string()
+        } else {
+            format!(
+                "{content}{}",
+                EMBEDDED_TOML_COMMAND.replace("[commands.", &format!("[{table}.commands.")),
+            )
+        };
+        let td = write_configs(&[(name, &content)])?;
+
+        let file = find_config_file(td.path())?;
+        assert_eq!(file, Some(td.path().join(name)));
+
+        let config = Config::new(file.unwrap(), None)?;
+        let commands = config.into_lint_commands(Path::new("."), &CommandSelection::default())?;
+        assert_eq!(
+            commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["rustfmt"],
+        );
+
+        Ok(())
+    }
+
+    #[test_case("Cargo.toml", "[package]\nname = \"foo\"\n"; "Cargo.toml")]
+    #[test_case("pyproject.toml", "[tool.black]\nline-length = 100\n"; "pyproject.toml")]
+    #[test_case("package.json", r#"{"name": "foo"}"#; "package.json")]
+    #[parallel]
+    fn file_without_embedded_config(name: &str, content: &str) -> Result<()> {
+        let td = write_configs(&[(name, content)])?;
+        assert_eq!(find_config_file(td.path())?, None);
+
+        let err = Config::new(td.path().join(name), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        assert!(
+            matches!(err, ConfigError::NoEmbeddedConfig { .. }),
+            "got {err:?}",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn more_than_one_config_source_is_an_error() -> Result<()> {
+        let td = write_configs(&[
+            ("precious.toml", EMBEDDED_TOML_COMMAND),
+            ("package.json", PACKAGE_JSON),
+        ])?;
+        let err = find_config_file(td.path())
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        assert_eq!(
+            err,
+            ConfigError::MultipleConfigSources {
+                sources: vec![
+                    td.path().join("precious.toml").display().to_string(),
+                    format!("{} [precious]", td.path().join("package.json").display()),
+                ],
+            },
+        );
+
+        let cargo_toml = format!(
+            "{}{}",
+            EMBEDDED_TOML_COMMAND.replace("[commands.", "[package.metadata.precious.commands."),
+            EMBEDDED_TOML_COMMAND.replace("[commands.", "[workspace.metadata.precious.commands."),
+        );
+        let td = write_configs(&[("Cargo.toml", &cargo_toml)])?;
+        let err = find_config_file(td.path())
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        let cargo_toml = td.path().join("Cargo.toml");
+        assert_eq!(
+            err,
+            ConfigError::MultipleConfigSources {
+                sources: vec![
+                    format!("{} [package.metadata.precious]", cargo_toml.display()),
+                    format!("{} [workspace.metadata.precious]", cargo_toml.display()),
+                ],
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn precious_toml_wins_over_dot_precious_toml() -> Result<()> {
+        let td = write_configs(&[
+            ("precious.toml", EMBEDDED_TOML_COMMAND),
+            (".precious.toml", EMBEDDED_TOML_COMMAND),
+        ])?;
+        assert_eq!(
+            find_config_file(td.path())?,
+            Some(td.path().join("precious.toml")),
+        );
+
+        Ok(())
+    }
+
+    #[test_case("Cargo.toml", "[package\nname = "; "Cargo.toml")]
+    #[test_case("pyproject.toml", "[tool.precious]]\n"; "pyproject.toml")]
+    #[test_case("package.json", r#"{"name": "#; "package.json")]
+    #[parallel]
+    fn unparseable_file_is_not_a_config(name: &str, content: &str) -> Result<()> {
+        let td = write_configs(&[(name, content)])?;
+        assert_eq!(find_config_file(td.path())?, None);
+
+        let td = write_configs(&[(name, content), ("precious.toml", EMBEDDED_TOML_COMMAND)])?;
+        assert_eq!(
+            find_config_file(td.path())?,
+            Some(td.path().join("precious.toml")),
+        );
+
+        // When the file is used as the config, it's still an error.
+        let err = Config::new(td.path().join(name), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        assert!(
+            matches!(err, ConfigError::InvalidConfigInFile { .. }),
+            "got {err:?}",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn embedded_config_error_names_the_file() -> Result<()> {
+        let td = write_configs(&[(
+            "pyproject.toml",
+            "[tool.precious.commands.bad]\ntype = \"lint\"\ninvoke = \"never\"\n",
+        )])?;
+        let err = Config::new(td.path().join("pyproject.toml"), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        match err {
+            ConfigError::InvalidConfigInFile { file, error } => {
+                assert_eq!(file, td.path().join("pyproject.toml"));
+                assert!(
+                    error.contains("for key `commands.bad.invoke`"),
+                    "error names the key: {error}",
+                );
+                assert!(
+                    error.ends_with(" at line 3 column 1"),
+                    "error has the position in the file: {error}",
+                );
+            }
+            e => panic!("unexpected error: {e}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn unknown_keys_are_an_error() -> Result<()> {
+        let td = write_configs(&[(
+            "precious.toml",
+            r#"
+exlude = "target"
+
+[commands.rustfmt]
+type          = "both"
+include       = "**/*.rs"
+cmd           = ["rustfmt"]
+lint_flag     = "--check"
+ok_exit_codes = 0
+"#,
+        )])?;
+        let file = td.path().join("precious.toml");
+
+        let err = Config::new(file.clone(), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        assert_eq!(
+            err,
+            ConfigError::UnknownKeys {
+                keys: vec![
+                    validate::UnknownKey {
+                        file: file.clone(),
+                        key: "exlude".to_string(),
+                        position: Some((2, 1)),
+                        suggestion: Some("exclude"),
+                    },
+                    validate::UnknownKey {
+                        file: file.clone(),
+                        key: "commands.rustfmt.lint_flag".to_string(),
+                        position: Some((8, 1)),
+                        suggestion: Some("lint_flags"),
+                    },
+                ],
+            },
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Unknown key `exlude` in {f} at line 2 column 1. Did you mean `exclude`?\n\
+                 Unknown key `commands.rustfmt.lint_flag` in {f} at line 8 column 1. Did you mean `lint_flags`?\n\
+                 Set allow_unknown_keys = true in the file to only warn about unknown keys.",
+                f = file.display(),
+            ),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn allow_unknown_keys() -> Result<()> {
+        let td = write_configs(&[(
+            "precious.toml",
+            &format!("allow_unknown_keys = true\nfrom_the_future = 42\n{BASE_CONFIG}"),
+        )])?;
+        let config = Config::new(td.path().join("precious.toml"), None)?;
+        assert_eq!(config.commands.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn unknown_key_error_names_the_extended_file() -> Result<()> {
+        let td = write_configs(&[
+            (
+                "base.toml",
+                &format!("{BASE_CONFIG}\n[hooks]\npre_commit = [\"lint\", \"--staged\"]\n"),
+            ),
+            ("precious.toml", "extends = \"base.toml\"\n"),
+        ])?;
+
+        let err = Config::new(td.path().join("precious.toml"), None)
+            .unwrap_err()
+            .downcast::<ConfigError>()
+            .unwrap();
+        match err {
+            ConfigError::UnknownKeys { keys } => {
+                assert_eq!(keys.len(), 1);
+                assert_eq!(keys[0].file, td.path().join("base.toml"));
+                assert_eq!(keys[0].key, "hooks.pre_commit");
+                assert_eq!(keys[0].suggestion, Some("pre-commit"));
+            }
+            e => panic!("unexpected error: {e}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn vars_are_interpolated() -> Result<()> {
+        let td = write_configs(&[
+            (
+                "base.toml",
+                r#"
+[vars]
+tools   = "$PRECIOUS_CONFIG_DIR/tools"
+edition = "2018"
+"#,
+            ),
+            (
+                "precious.toml",
+                r#"
+extends = "base.toml"
+
+[vars]
+edition = "2021"
+
+[commands.rustfmt]
+type          = "both"
+include       = "${var:tools}/**/*.rs"
+exclude       = "$PRECIOUS_ROOT/target"
+working_dir.chdir_to = "${var:tools}"
+cmd           = ["${var:tools}/rustfmt", "--edition", "${var:edition}"]
+lint_flags    = "--config-path=$PRECIOUS_CONFIG_DIR/rustfmt.toml"
+env           = { TOOLS = "${var:tools}" }
+ok_exit_codes = 0
+"#,
+            ),
+        ])?;
+        let config = Config::new(td.path().join("precious.toml"), None)?;
+        let interpolator = Interpolator::new(&config.vars, Path::new("/root"), &config.config_dir);
+
+        let tools = format!("{}/tools", td.path().display());
+        let (name, command) = config.commands.into_iter().next().unwrap();
+        let params = command.into_command_params(
+            Path::new("/root"),
+            name,
+            &CommandDefaults::default(),
+            &interpolator,
+        )?;
+        assert_eq!(params.include, vec![format!("{tools}/**/*.rs")]);
+        assert_eq!(params.exclude, vec!["/root/target"]);
+        assert_eq!(
+            params.working_dir,
+            WorkingDir::ChdirTo(PathBuf::from(&tools)),
+        );
+        assert_eq!(
+            params.cmd,
+            vec![
+                format!("{tools}/rustfmt"),
+                "--edition".to_string(),
+                "2021".to_string()
+            ],
+        );
+        assert_eq!(
+            params.lint_flags,
+            vec![format!(
+                "--config-path={}/rustfmt.toml",
+                td.path().display()
+            )],
+        );
+        assert_eq!(params.env.get("TOOLS"), Some(&tools));
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn top_level_exclude_is_interpolated() -> Result<()> {
+        let td = write_configs(&[(
+            "precious.toml",
+            r#"
+exclude = ["${var:vendor}/**/*", "$PRECIOUS_ROOT/target"]
+
+[vars]
+vendor = "third-party"
+
+[commands.foo]
+type          = "lint"
+include       = "**/*"
+cmd           = ["foo"]
+ok_exit_codes = 0
+"#,
+        )])?;
+        let config = Config::new(td.path().join("precious.toml"), None)?;
+        assert_eq!(
+            config.interpolated_exclude(Path::new("/root"))?,
+            vec!["third-party/**/*", "/root/target"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn undefined_var_is_an_error() -> Result<()> {
+        let td = write_configs(&[(
+            "precious.toml",
+            r#"
+[commands.foo]
+type          = "lint"
+include       = "**/*"
+cmd           = ["foo", "--level", "${var:level}"]
+ok_exit_codes = 0
+"#,
+        )])?;
+        let config = Config::new(td.path().join("precious.toml"), None)?;
+        let err = config
+            .into_lint_commands(td.path(), &CommandSelection::default())
+            .unwrap_err()
+            .downcast::<ConfigError>()?;
+        assert_eq!(
+            err.to_string(),
+            "The foo command refers to the level var, which is not in the [vars] table",
+        );
+
+        Ok(())
+    }
+
+    #[test_case(&[], &[], &[], &["clippy", "rustfmt", "typos"]; "everything")]
+    #[test_case(&["clippy"], &[], &[], &["clippy"]; "one command")]
+    #[test_case(&["clippy", "typos"], &[], &[], &["clippy", "typos"]; "two commands")]
+    #[test_case(&[], &["rust"], &[], &["clippy", "rustfmt"]; "label")]
+    #[test_case(&["typos"], &["fast"], &[], &["rustfmt", "typos"]; "command or label")]
+    #[test_case(&[], &["rust"], &["clippy"], &["rustfmt"]; "label with skip")]
+    #[test_case(&[], &[], &["clippy"], &["rustfmt", "typos"]; "skip only")]
+    #[test_case(&[], &["nope"], &[], &[]; "no matching label")]
+    #[parallel]
+    fn command_selection(
+        names: &[&str],
+        labels: &[&str],
+        skip: &[&str],
+        expect: &[&str],
+    ) -> Result<()> {
+        let td = write_configs(&[(
+            "precious.toml",
+            r#"
+[commands.clippy]
+type          = "lint"
+include       = "**/*.rs"
+cmd           = "clippy"
+labels        = ["rust", "slow"]
+ok_exit_codes = 0
+
+[commands.rustfmt]
+type          = "lint"
+include       = "**/*.rs"
+cmd           = "rustfmt"
+labels        = ["rust", "fast"]
+ok_exit_codes = 0
+
+[commands.typos]
+type          = "lint"
+include       = "**/*"
+cmd           = "typos"
+ok_exit_codes = 0
+"#,
+        )])?;
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let selection = CommandSelection {
+            names: strings(names),
+            labels: strings(labels),
+            skip: strings(skip),
+        };
+        let config = Config::new(td.path().join("precious.toml"), None)?;
+        let names = config
+            .into_lint_commands(td.path(), &selection)?
+            .into_iter()
+            .map(|c| c.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, expect);
+
+        Ok(())
+    }
+
+    #[test_case(&["clippy", "rustfmt"], &["rust"], &[]; "everything matches")]
+    #[test_case(&["rustfmt", "clipy"], &[], &["--command clipy"]; "unknown command")]
+    #[test_case(&[], &["rust", "slwo"], &["--label slwo"]; "unknown label")]
+    #[test_case(&["typo"], &["nope"], &["--command typo", "--label nope"]; "both")]
+    #[parallel]
+    fn command_selection_unmatched(names: &[&str], labels: &[&str], expect: &[&str]) -> Result<()> {
+        let td = write_configs(&[(
+            "precious.toml",
+            r#"
+[commands.clippy]
+type          = "lint"
+include       = "**/*.rs"
+cmd           = "clippy"
+labels        = ["rust", "slow"]
+ok_exit_codes = 0
+
+[commands.rustfmt]
+type          = "tidy"
+include       = "**/*.rs"
+cmd           = "rustfmt"
+ok_exit_codes = 0
+disabled      = true
+"#,
+        )])?;
+        let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let selection = CommandSelection {
+            names: strings(names),
+            labels: strings(labels),
+            skip: vec![],
+        };
+        let config = Config::new(td.path().join("precious.toml"), None)?;
+        assert_eq!(selection.unmatched(&[&config]), expect);
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn command_selection_display() {
+        let selection = CommandSelection {
+            names: vec!["clippy".to_string()],
+            labels: vec!["rust".to_string(), "fast".to_string()],
+            skip: vec!["typos".to_string()],
+        };
+        assert_eq!(
+            selection.to_string(),
+            "--command clippy --label rust --label fast --skip-command typos",
+        );
+    }
 }
@@ -0,0 +1,286 @@
+use super::ConfigError;
+use crate::vcs;
+use indexmap::IndexMap;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+const ENV_PREFIX: &str = "${env:";
+const VAR_PREFIX: &str = "${var:";
+
+// This expands references to variables in config values. A value can contain
+// `${env:NAME}` for an environment variable, `${var:name}` for a key in the
+// `[vars]` table, and the built-in `$PRECIOUS_ROOT`, `$PRECIOUS_GIT_ROOT`,
+// and `$PRECIOUS_CONFIG_DIR` variables. Referring to anything that isn't
+// defined is an error, since silently using an empty string would run
+// commands with the wrong arguments.
+//
+// Vars are expanded when they're used, so a var can refer to other vars, or
+// to an environment variable that is only set in some places, like CI.
+#[derive(Debug)]
+pub(super) struct Interpolator<'a> {
+    vars: &'a IndexMap<String, String>,
+    builtins: [(&'static str, Option<String>); 3],
+}
+
+impl<'a> Interpolator<'a> {
+    pub(super) fn new(
+        vars: &'a IndexMap<String, String>,
+        project_root: &Path,
+        config_dir: &Path,
+    ) -> Interpolator<'a> {
+        let git_root = project_root
+            .ancestors()
+            .find(|a| vcs::checkout_dir(a) == Some(".git"));
+        Interpolator {
+            vars,
+            builtins: [
+                ("$PRECIOUS_ROOT", Some(path_string(project_root))),
+                ("$PRECIOUS_GIT_ROOT", git_root.map(path_string)),
+                ("$PRECIOUS_CONFIG_DIR", Some(path_string(config_dir))),
+            ],
+        }
+    }
+
+    // The `context` says where the value came from, like "The rustfmt
+    // command", for error messages.
+    pub(super) fn interpolate(&self, value: &str, context: &str) -> Result<String, ConfigError> {
+        self.expand(value, context, &mut vec![])
+    }
+
+    pub(super) fn interpolate_all(
+        &self,
+        values: Vec<String>,
+        context: &str,
+    ) -> Result<Vec<String>, ConfigError> {
+        values
+            .iter()
+            .map(|v| self.interpolate(v, context))
+            .collect()
+    }
+
+    pub(super) fn interpolate_path(
+        &self,
+        path: &Path,
+        context: &str,
+    ) -> Result<PathBuf, ConfigError> {
+        Ok(PathBuf::from(
+            self.interpolate(&path_string(path), context)?,
+        ))
+    }
+
+    // The `stack` holds the names of the vars we're in the middle of
+    // expanding, which is how we find a var that refers to itself.
+    fn expand(
+        &self,
+        value: &str,
+        context: &str,
+        stack: &mut Vec<String>,
+    ) -> Result<String, ConfigError> {
+        let mut expanded = String::new();
+        let mut rest = value;
+        while let Some(i) = rest.find('$') {
+            expanded.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            let prefix = [ENV_PREFIX, VAR_PREFIX]
+                .into_iter()
+                .find(|p| rest.starts_with(p));
+            if let Some(prefix) = prefix {
+                let after = &rest[prefix.len()..];
+                let end =
+                    after
+                        .find('}')
+                        .ok_or_else(|| ConfigError::UnterminatedInterpolation {
+                            context: context.to_string(),
+                            value: value.to_string(),
+                        })?;
+                let name = &after[..end];
+                if prefix == ENV_PREFIX {
+                    expanded.push_str(&env_var(name, context)?);
+                } else {
+                    expanded.push_str(&self.var(name, context, stack)?);
+                }
+                rest = &after[end + 1..];
+            } else if let Some((name, v)) = self.builtin(rest) {
+                let v = v.as_ref().ok_or_else(|| ConfigError::UndefinedBuiltinVar {
+                    context: context.to_string(),
+                    name: name.to_string(),
+                })?;
+                expanded.push_str(v);
+                rest = &rest[name.len()..];
+            } else {
+                expanded.push('$');
+                rest = &rest[1..];
+            }
+        }
+        expanded.push_str(rest);
+
+        Ok(expanded)
+    }
+
+    // A built-in only matches when the name isn't followed by more of an
+    // identifier, so `$PRECIOUS_ROOTDIR` is not `$PRECIOUS_ROOT` followed by
+    // "DIR".
+    fn builtin(&self, rest: &str) -> Option<&(&'static str, Option<String>)> {
+        self.builtins.iter().find(|(b, _)| {
+            rest.strip_prefix(b).is_some_and(|after| {
+                !after
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+            })
+        })
+    }
+
+    fn var(
+        &self,
+        name: &str,
+        context: &str,
+        stack: &mut Vec<String>,
+    ) -> Result<String, ConfigError> {
+        let value = self
+            .vars
+            .get(name)
+            .ok_or_else(|| ConfigError::UndefinedVar {
+                context: context.to_string(),
+                name: name.to_string(),
+            })?;
+        if stack.iter().any(|s| s == name) {
+            let mut names = stack.clone();
+            names.push(name.to_string());
+            return Err(ConfigError::VarCycle { names });
+        }
+
+        stack.push(name.to_string());
+        let expanded = self.expand(value, &format!("The {name} var"), stack)?;
+        stack.pop();
+
+        Ok(expanded)
+    }
+}
+
+fn env_var(name: &str, context: &str) -> Result<String, ConfigError> {
+    env::var_os(name)
+        .map(|v| v.to_string_lossy().into_owned())
+        .ok_or_else(|| ConfigError::UndefinedEnvVar {
+            context: context.to_string(),
+            name: name.to_string(),
+        })
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serial_test::{parallel, serial};
+    use test_case::test_case;
+
+    fn vars() -> IndexMap<String, String> {
+        [
+            ("tools", "$PRECIOUS_ROOT/tools"),
+            ("linter", "${var:tools}/bin/lint"),
+            ("self", "${var:self}"),
+            ("a", "${var:b}"),
+            ("b", "${var:a}"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    #[test_case("no vars here", "no vars here"; "no vars")]
+    #[test_case("$PRECIOUS_ROOT/foo", "/project/foo"; "project root")]
+    #[test_case("$PRECIOUS_CONFIG_DIR/foo.conf", "/project/sub/foo.conf"; "config dir")]
+    #[test_case("${var:tools}", "/project/tools"; "var")]
+    #[test_case("--linter=${var:linter}", "--linter=/project/tools/bin/lint"; "nested var")]
+    #[test_case("${HOME} and $HOME", "${HOME} and $HOME"; "other dollar signs are left alone")]
+    #[test_case("foo$", "foo$"; "trailing dollar sign")]
+    #[test_case("$PRECIOUS_ROOTDIR/foo", "$PRECIOUS_ROOTDIR/foo"; "longer name is not a built-in")]
+    #[test_case("$PRECIOUS_ROOT_FOO", "$PRECIOUS_ROOT_FOO"; "name with underscore is not a built-in")]
+    #[test_case("$PRECIOUS_ROOT", "/project"; "built-in at end of value")]
+    #[test_case("--dir=$PRECIOUS_ROOT.", "--dir=/project."; "built-in followed by punctuation")]
+    #[parallel]
+    fn interpolate(value: &str, expect: &str) -> anyhow::Result<()> {
+        let vars = vars();
+        let i = Interpolator::new(&vars, Path::new("/project"), Path::new("/project/sub"));
+        assert_eq!(i.interpolate(value, "The test command")?, expect);
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn interpolate_env() -> anyhow::Result<()> {
+        let vars = vars();
+        let i = Interpolator::new(&vars, Path::new("/project"), Path::new("/project"));
+
+        env::set_var("PRECIOUS_TEST_VAR", "value");
+        assert_eq!(
+            i.interpolate("x-${env:PRECIOUS_TEST_VAR}-y", "The test command")?,
+            "x-value-y",
+        );
+
+        env::remove_var("PRECIOUS_TEST_VAR");
+        assert_eq!(
+            i.interpolate("${env:PRECIOUS_TEST_VAR}", "The test command"),
+            Err(ConfigError::UndefinedEnvVar {
+                context: "The test command".to_string(),
+                name: "PRECIOUS_TEST_VAR".to_string(),
+            }),
+        );
+
+        Ok(())
+    }
+
+    #[test_case(
+        "${var:nope}",
+        ConfigError::UndefinedVar {
+            context: "The test command".to_string(),
+            name: "nope".to_string(),
+        };
+        "undefined var"
+    )]
+    #[test_case(
+        "${var:self}",
+        ConfigError::VarCycle {
+            names: vec!["self".to_string(), "self".to_string()],
+        };
+        "var refers to itself"
+    )]
+    #[test_case(
+        "${var:a}",
+        ConfigError::VarCycle {
+            names: vec!["a".to_string(), "b".to_string(), "a".to_string()],
+        };
+        "vars refer to each other"
+    )]
+    #[test_case(
+        "${env:FOO",
+        ConfigError::UnterminatedInterpolation {
+            context: "The test command".to_string(),
+            value: "${env:FOO".to_string(),
+        };
+        "unterminated"
+    )]
+    #[test_case(
+        "$PRECIOUS_GIT_ROOT/foo",
+        ConfigError::UndefinedBuiltinVar {
+            context: "The test command".to_string(),
+            name: "$PRECIOUS_GIT_ROOT".to_string(),
+        };
+        "not in a git checkout"
+    )]
+    #[parallel]
+    fn interpolate_errors(value: &str, expect: ConfigError) -> anyhow::Result<()> {
+        let td = tempfile::tempdir()?;
+        let vars = vars();
+        let i = Interpolator::new(&vars, td.path(), td.path());
+        assert_eq!(i.interpolate(value, "The test command"), Err(expect));
+        Ok(())
+    }
+}
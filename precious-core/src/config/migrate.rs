@@ -0,0 +1,431 @@
+use super::{embedded_keys, read_config_file, CommandConfig, ConfigError, OldRunMode};
+use crate::command::{Invoke, PathArgs, WorkingDir};
+use anyhow::Result;
+use std::path::Path;
+use toml_edit::{Document, Item, Key, Table, TableLike};
+
+const OLD_KEYS: &[&str] = &["run_mode", "chdir"];
+const NEW_KEYS: &[&str] = &["invoke", "working_dir", "path_args"];
+
+#[derive(Debug)]
+pub struct Migration {
+    pub old: String,
+    pub new: String,
+}
+
+impl Migration {
+    pub fn diff(&self, file: &Path) -> String {
+        let name = file.display().to_string();
+        similar::TextDiff::from_lines(&self.old, &self.new)
+            .unified_diff()
+            .header(&name, &name)
+            .to_string()
+    }
+}
+
+// Rewrites any commands in the file that use the old `run_mode` and `chdir`
+// keys to use the equivalent `invoke`, `working_dir`, and `path_args` keys
+// instead. This covers the top-level commands and the commands in each
+// profile. The new keys go where `run_mode` (or `chdir`) was, and everything
+// else in the file, including comments, is left as is. This returns `None` if
+// there is nothing to change.
+//
+// Only this one file is rewritten. Any files it extends, and a
+// `precious.local.toml` next to it, must be migrated on their own.
+pub fn migrate(file: &Path) -> Result<Option<Migration>> {
+    if file.extension().is_some_and(|e| e == "json") {
+        return Err(ConfigError::CannotMigrateNonToml {
+            file: file.to_path_buf(),
+        }
+        .into());
+    }
+
+    let old = String::from_utf8(read_config_file(file)?)?;
+    let invalid = |error: String| ConfigError::InvalidConfigInFile {
+        file: file.to_path_buf(),
+        error,
+    };
+    let mut doc = old
+        .parse::<Document>()
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let mut changed = false;
+    if let Some(table) = precious_table(&mut doc, file) {
+        changed |=
+            migrate_commands(table.get_mut("commands")).map_err(|e| invalid(e.to_string()))?;
+        if let Some(profiles) = table.get_mut("profiles").and_then(Item::as_table_like_mut) {
+            for (_, profile) in profiles.iter_mut() {
+                let commands = profile
+                    .as_table_like_mut()
+                    .and_then(|p| p.get_mut("commands"));
+                changed |= migrate_commands(commands).map_err(|e| invalid(e.to_string()))?;
+            }
+        }
+    }
+
+    if !changed {
+        return Ok(None);
+    }
+    Ok(Some(Migration {
+        old,
+        new: doc.to_string(),
+    }))
+}
+
+// For a config embedded in a file like `Cargo.toml`, this is the table that
+// holds the config. Otherwise it's the whole document.
+fn precious_table<'a>(doc: &'a mut Document, file: &Path) -> Option<&'a mut Table> {
+    let keys = match embedded_keys(file) {
+        Some(k) => k,
+        None => return Some(doc.as_table_mut()),
+    };
+    let key = keys.iter().find(|k| {
+        k.split('.')
+            .try_fold(doc.as_table(), |t, k| t.get(k)?.as_table())
+            .is_some()
+    })?;
+    key.split('.')
+        .try_fold(doc.as_table_mut(), |t, k| t.get_mut(k)?.as_table_mut())
+}
+
+// Commands may be written as regular tables (`[commands.foo]`) or as inline
+// tables (`commands.foo = { ... }`), so we handle anything table-like here.
+fn migrate_commands(commands: Option<&mut Item>) -> Result<bool> {
+    let mut changed = false;
+    if let Some(commands) = commands.and_then(Item::as_table_like_mut) {
+        for (name, command) in commands.iter_mut() {
+            if let Some(command) = command.as_table_like_mut() {
+                changed |= migrate_command(name.get(), command)?;
+            }
+        }
+    }
+    Ok(changed)
+}
+
+fn migrate_command(name: &str, command: &mut dyn TableLike) -> Result<bool> {
+    if !OLD_KEYS.iter().any(|k| command.contains_key(k)) {
+        return Ok(false);
+    }
+    if NEW_KEYS.iter().any(|k| command.contains_key(k)) {
+        return Err(ConfigError::CannotMixOldAndNewCommandParams {
+            name: name.to_string(),
+        }
+        .into());
+    }
+
+    let run_mode = match command.get("run_mode") {
+        Some(item) => {
+            let value = item.as_str().unwrap_or_default().to_string();
+            Some(
+                toml::Value::String(value)
+                    .try_into::<OldRunMode>()
+                    .map_err(|e| anyhow::anyhow!("invalid run_mode for the {name} command: {e}"))?,
+            )
+        }
+        None => None,
+    };
+    let chdir = match command.get("chdir") {
+        Some(item) => Some(
+            item.as_bool()
+                .ok_or_else(|| anyhow::anyhow!("chdir for the {name} command must be a boolean"))?,
+        ),
+        None => None,
+    };
+
+    let (invoke, working_dir, path_args) = CommandConfig::old_invoke_args(run_mode, chdir);
+    let new_values = [
+        ("invoke", invoke_str(invoke)),
+        ("working_dir", working_dir_str(&working_dir)),
+        ("path_args", path_args_str(path_args)),
+    ];
+
+    // We rebuild the table so that the new keys can go where the first old
+    // key was. Removing and reinserting each key keeps its formatting.
+    let keys = command
+        .iter()
+        .map(|(k, _)| k.to_string())
+        .collect::<Vec<_>>();
+    let mut entries = keys
+        .iter()
+        .filter_map(|k| {
+            let key = command.get_key_value(k)?.0.clone();
+            Some((key, command.remove(k)?))
+        })
+        .collect::<Vec<_>>();
+    let first_old = entries
+        .iter()
+        .position(|(k, _)| OLD_KEYS.contains(&k.get()))
+        .unwrap();
+    let (old_key, old_item) = entries[first_old].clone();
+    entries.retain(|(k, _)| !OLD_KEYS.contains(&k.get()));
+
+    // If the `=` after the old key was lined up with other keys, we line up
+    // the new keys the same way.
+    let padding = old_key
+        .decor()
+        .suffix()
+        .and_then(|s| s.as_str())
+        .map_or(1, str::len);
+    let width = if padding > 1 {
+        old_key.get().len() + padding
+    } else {
+        0
+    };
+    let new_entries = new_values.iter().enumerate().map(|(i, (k, v))| {
+        let mut key = Key::new(*k);
+        if i == 0 {
+            if let Some(prefix) = old_key.decor().prefix().and_then(|p| p.as_str()) {
+                key.decor_mut().set_prefix(prefix);
+            }
+        }
+        key.decor_mut()
+            .set_suffix(" ".repeat(width.saturating_sub(k.len()).max(1)));
+        // Any comment after the old value stays with the first new one.
+        let mut value = toml_edit::value(*v);
+        if let (Some(value), Some(old)) = (value.as_value_mut(), old_item.as_value()) {
+            if let Some(prefix) = old.decor().prefix().and_then(|p| p.as_str()) {
+                value.decor_mut().set_prefix(prefix);
+            }
+            if i == 0 {
+                if let Some(suffix) = old.decor().suffix().and_then(|s| s.as_str()) {
+                    value.decor_mut().set_suffix(suffix);
+                }
+            }
+        }
+        (key, value)
+    });
+    let entries = entries[..first_old]
+        .iter()
+        .cloned()
+        .chain(new_entries)
+        .chain(entries[first_old..].iter().cloned())
+        .collect::<Vec<_>>();
+
+    for (key, item) in entries {
+        command.entry_format(&key).or_insert(item);
+    }
+
+    Ok(true)
+}
+
+fn invoke_str(invoke: Invoke) -> &'static str {
+    match invoke {
+        Invoke::PerFile => "per-file",
+        Invoke::PerDir => "per-dir",
+        Invoke::Once => "once",
+    }
+}
+
+fn working_dir_str(working_dir: &WorkingDir) -> &'static str {
+    match working_dir {
+        WorkingDir::Dir => "dir",
+        // The old keys never translate to `chdir_to`.
+        WorkingDir::Root | WorkingDir::ChdirTo(_) => "root",
+    }
+}
+
+fn path_args_str(path_args: PathArgs) -> &'static str {
+    match path_args {
+        PathArgs::File => "file",
+        PathArgs::Dir => "dir",
+        PathArgs::None => "none",
+        PathArgs::Dot => "dot",
+        PathArgs::AbsoluteFile => "absolute-file",
+        PathArgs::AbsoluteDir => "absolute-dir",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serial_test::parallel;
+    use std::fs;
+    use test_case::test_case;
+
+    #[test_case(
+        r#"
+# Formats Rust code.
+[commands.rustfmt]
+type          = "both"
+include       = "**/*.rs"
+# Each file is passed on its own.
+run_mode      = "files" # one at a time
+cmd           = ["rustfmt"]
+ok_exit_codes = 0
+"#,
+        r#"
+# Formats Rust code.
+[commands.rustfmt]
+type          = "both"
+include       = "**/*.rs"
+# Each file is passed on its own.
+invoke        = "per-file" # one at a time
+working_dir   = "root"
+path_args     = "file"
+cmd           = ["rustfmt"]
+ok_exit_codes = 0
+"#;
+        "run_mode only"
+    )]
+    #[test_case(
+        r#"
+[commands.golangci-lint]
+type = "lint"
+include = "**/*.go"
+cmd = ["golangci-lint", "run"]
+run_mode = "dirs"
+chdir = true
+ok_exit_codes = 0
+"#,
+        r#"
+[commands.golangci-lint]
+type = "lint"
+include = "**/*.go"
+cmd = ["golangci-lint", "run"]
+invoke = "per-dir"
+working_dir = "dir"
+path_args = "none"
+ok_exit_codes = 0
+"#;
+        "run_mode and chdir"
+    )]
+    #[test_case(
+        r#"
+[commands.prettier]
+type = "both"
+include = "**/*.md"
+chdir = true
+cmd = ["prettier"]
+ok_exit_codes = 0
+"#,
+        r#"
+[commands.prettier]
+type = "both"
+include = "**/*.md"
+invoke = "per-file"
+working_dir = "dir"
+path_args = "file"
+cmd = ["prettier"]
+ok_exit_codes = 0
+"#;
+        "chdir only"
+    )]
+    #[test_case(
+        r#"
+[commands]
+rustfmt = { type = "both", include = "**/*.rs", run_mode = "files", cmd = ["rustfmt"], ok_exit_codes = 0 }
+"#,
+        r#"
+[commands]
+rustfmt = { type = "both", include = "**/*.rs", invoke = "per-file", working_dir = "root", path_args = "file", cmd = ["rustfmt"], ok_exit_codes = 0 }
+"#;
+        "inline table"
+    )]
+    #[test_case(
+        r#"
+[commands.clippy]
+type = "lint"
+include = "**/*.rs"
+invoke = "once"
+path_args = "dot"
+cmd = ["cargo", "clippy"]
+ok_exit_codes = 0
+
+[profiles.ci.commands.clippy]
+run_mode = "root"
+"#,
+        r#"
+[commands.clippy]
+type = "lint"
+include = "**/*.rs"
+invoke = "once"
+path_args = "dot"
+cmd = ["cargo", "clippy"]
+ok_exit_codes = 0
+
+[profiles.ci.commands.clippy]
+invoke = "once"
+working_dir = "root"
+path_args = "dot"
+"#;
+        "profile command"
+    )]
+    #[parallel]
+    fn migrate_rewrites_old_keys(old: &str, expect: &str) -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let file = td.path().join("precious.toml");
+        fs::write(&file, old)?;
+
+        let migration = migrate(&file)?.expect("config was migrated");
+        assert_eq!(migration.old, old);
+        assert_eq!(migration.new, expect);
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn migrate_embedded_config() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let file = td.path().join("Cargo.toml");
+        fs::write(
+            &file,
+            r#"[package]
+name = "foo"
+
+[package.metadata.precious.commands.clippy]
+type = "lint"
+include = "**/*.rs"
+run_mode = "root"
+cmd = ["cargo", "clippy"]
+ok_exit_codes = 0
+"#,
+        )?;
+
+        let migration = migrate(&file)?.expect("config was migrated");
+        assert!(migration
+            .new
+            .contains("invoke = \"once\"\nworking_dir = \"root\"\npath_args = \"dot\"\n"));
+        assert!(migration.new.starts_with("[package]\nname = \"foo\"\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn migrate_with_nothing_to_change() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let file = td.path().join("precious.toml");
+        fs::write(
+            &file,
+            "[commands.foo]\ntype = \"lint\"\ninclude = \"*\"\ninvoke = \"once\"\ncmd = \"foo\"\nok_exit_codes = 0\n",
+        )?;
+
+        assert!(migrate(&file)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn migrate_mixed_old_and_new_keys_is_an_error() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let file = td.path().join("precious.toml");
+        fs::write(
+            &file,
+            "[commands.foo]\ntype = \"lint\"\ninclude = \"*\"\nrun_mode = \"root\"\ninvoke = \"once\"\ncmd = \"foo\"\nok_exit_codes = 0\n",
+        )?;
+
+        let err = migrate(&file).unwrap_err().downcast::<ConfigError>()?;
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Invalid config in {}: The foo command mixes old command params (run_mode or chdir) with new command params (invoke, working_dir, or path_args)",
+                file.display(),
+            ),
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+use serde_json::{json, Value};
+
+// This is a JSON Schema for the config file, for editors that can validate
+// TOML against one. The tests check that it has the same keys and values as
+// the serde definitions, so it needs to be updated whenever those change.
+//
+// No command key is marked as required. A file that extends another one, or
+// a `precious.local.toml` file, can set just the keys that it changes.
+//
+// This uses draft 2019-09 rather than draft-07 because draft-07 ignores every
+// keyword next to a `$ref`, which would drop the descriptions of most keys,
+// and it has no `deprecated` keyword.
+pub fn schema() -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2019-09/schema",
+        "title": "precious config",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "exclude": {
+                "description": "Files matching these patterns are never passed to any command.",
+                "$ref": "#/$defs/stringOrArray",
+            },
+            "exclude_attributes": {
+                "description": "Files with any of these git attributes are never passed to any command.",
+                "$ref": "#/$defs/stringOrArray",
+            },
+            "submodules": {
+                "description": "Whether files in git submodules are part of the project.",
+                "$ref": "#/$defs/submodules",
+            },
+            "all_files_from": {
+                "description": "Where --all gets the list of files from.",
+                "$ref": "#/$defs/allFilesFrom",
+            },
+            "max_file_size": {
+                "description": "Files larger than this are not passed to any command.",
+                "$ref": "#/$defs/fileSize",
+            },
+            "skip_binary": {
+                "description": "Whether binary files are skipped by every command.",
+                "type": "boolean",
+            },
+            "nested_configs": {
+                "description": "Whether config files in subdirectories are used for the files under them.",
+                "type": "boolean",
+            },
+            "jobs": {
+                "description": "The number of parallel jobs to run.",
+                "type": "integer",
+                "minimum": 0,
+            },
+            "extends": {
+                "description": "Paths to other config files that this one builds on.",
+                "$ref": "#/$defs/stringOrArray",
+            },
+            "allow_unknown_keys": {
+                "description": "Whether unknown keys in this file are warnings instead of errors.",
+                "type": "boolean",
+            },
+            "hooks": {
+                "description": "The git hooks that `precious hooks install` installs.",
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "pre-commit": { "$ref": "#/$defs/hookArgs" },
+                    "pre-push": { "$ref": "#/$defs/hookArgs" },
+                    "commit-msg": { "$ref": "#/$defs/hookArgs" },
+                },
+            },
+            "vars": {
+                "description": "Variables that can be used in command keys with ${var:name}.",
+                "type": "object",
+                "additionalProperties": { "type": "string" },
+            },
+            "commands": {
+                "description": "The commands to run, keyed by name.",
+                "type": "object",
+                "additionalProperties": { "$ref": "#/$defs/command" },
+            },
+            "profiles": {
+                "description": "Named sets of overrides, selected with --profile or PRECIOUS_PROFILE.",
+                "type": "object",
+                "additionalProperties": { "$ref": "#/$defs/profile" },
+            },
+        },
+        "$defs": {
+            "profile": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "jobs": {
+                        "description": "The number of parallel jobs to run with this profile.",
+                        "type": "integer",
+                        "minimum": 0,
+                    },
+                    "commands": {
+                        "description": "Keys that override the same keys of each command, or new commands.",
+                        "type": "object",
+                        "additionalProperties": { "$ref": "#/$defs/command" },
+                    },
+                },
+            },
+            "command": {
+                "type": "object",
+                "additionalProperties": false,
+                "properties": {
+                    "type": {
+                        "description": "Whether this command lints, tidies, or both.",
+                        "$ref": "#/$defs/commandType",
+                    },
+                    "include": {
+                        "description": "Files matching these patterns are passed to the command.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "include_types": {
+                        "description": "Files of these types, like \"rust\" or \"py\", are passed to the command.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "include_shebang": {
+                        "description": "Files with a shebang line for any of these interpreters are passed to the command.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "exclude": {
+                        "description": "Files matching these patterns are not passed to the command.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "exclude_attributes": {
+                        "description": "Files with any of these git attributes are not passed to the command.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "invoke": {
+                        "description": "How often the command is run.",
+                        "$ref": "#/$defs/invoke",
+                    },
+                    "working_dir": {
+                        "description": "The directory the command is run in.",
+                        "$ref": "#/$defs/workingDir",
+                    },
+                    "path_args": {
+                        "description": "The paths passed to the command.",
+                        "$ref": "#/$defs/pathArgs",
+                    },
+                    "once_per_shard": {
+                        "description": "Whether a command that is invoked once runs once in each shard.",
+                        "type": "boolean",
+                    },
+                    "max_file_size": {
+                        "description": "Files larger than this are not passed to the command.",
+                        "$ref": "#/$defs/fileSize",
+                    },
+                    "skip_binary": {
+                        "description": "Whether binary files are skipped by the command.",
+                        "type": "boolean",
+                    },
+                    "disabled": {
+                        "description": "Whether the command is turned off.",
+                        "type": "boolean",
+                    },
+                    "labels": {
+                        "description": "Labels for selecting this command with --label.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "run_mode": {
+                        "description": "Deprecated. Use invoke, working_dir, and path_args instead.",
+                        "deprecated": true,
+                        "$ref": "#/$defs/runMode",
+                    },
+                    "chdir": {
+                        "description": "Deprecated. Use invoke, working_dir, and path_args instead.",
+                        "deprecated": true,
+                        "type": "boolean",
+                    },
+                    "cmd": {
+                        "description": "The executable to run and its arguments.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "env": {
+                        "description": "Environment variables to set when running the command.",
+                        "type": "object",
+                        "additionalProperties": { "type": "string" },
+                    },
+                    "lint_flags": {
+                        "description": "Flags added to cmd when linting.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "tidy_flags": {
+                        "description": "Flags added to cmd when tidying.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                    "path_flag": {
+                        "description": "A flag passed before the paths.",
+                        "type": "string",
+                    },
+                    "ok_exit_codes": {
+                        "description": "Exit codes which mean the command succeeded.",
+                        "$ref": "#/$defs/exitCodes",
+                    },
+                    "lint_failure_exit_codes": {
+                        "description": "Exit codes which mean linting found a problem.",
+                        "$ref": "#/$defs/exitCodes",
+                    },
+                    "expect_stderr": {
+                        "description": "Whether output on stderr is expected even when the command succeeds.",
+                        "type": "boolean",
+                    },
+                    "ignore_stderr": {
+                        "description": "Regexes for stderr output that is ignored.",
+                        "$ref": "#/$defs/stringOrArray",
+                    },
+                },
+            },
+            "stringOrArray": {
+                "oneOf": [
+                    { "type": "string" },
+                    { "type": "array", "items": { "type": "string" } },
+                ],
+            },
+            "hookArgs": {
+                "description": "The arguments to pass to precious. A single string is split on whitespace.",
+                "$ref": "#/$defs/stringOrArray",
+            },
+            "exitCode": {
+                "type": "integer",
+                "minimum": u8::MIN,
+                "maximum": u8::MAX,
+            },
+            "exitCodes": {
+                "oneOf": [
+                    { "$ref": "#/$defs/exitCode" },
+                    { "type": "array", "items": { "$ref": "#/$defs/exitCode" } },
+                ],
+            },
+            "fileSize": {
+                "oneOf": [
+                    { "type": "integer", "minimum": 0 },
+                    {
+                        "type": "string",
+                        "pattern": "^\\s*[0-9]+\\s*([bB]|[kKmMgG][iI]?[bB])?\\s*$",
+                    },
+                ],
+            },
+            "commandType": { "enum": ["lint", "tidy", "both", "commit-msg"] },
+            "invoke": { "enum": ["per-file", "per-dir", "once"] },
+            "workingDir": {
+                "oneOf": [
+                    { "enum": ["root", "dir"] },
+                    {
+                        "type": "object",
+                        "additionalProperties": false,
+                        "required": ["chdir_to"],
+                        "properties": { "chdir_to": { "type": "string" } },
+                    },
+                ],
+            },
+            "pathArgs": {
+                "enum": ["file", "dir", "none", "dot", "absolute-file", "absolute-dir"],
+            },
+            "runMode": { "enum": ["files", "dirs", "root"] },
+            "submodules": { "enum": ["include", "exclude"] },
+            "allFilesFrom": { "enum": ["vcs", "walk"] },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::{CommandType, Invoke, PathArgs},
+        config::{
+            validate::{serde_names, EXTRA_TOP_LEVEL_KEYS, PROFILE_KEYS},
+            CommandConfig, Config, HooksConfig, OldRunMode,
+        },
+        paths::finder::AllFilesFrom,
+        vcs::Submodules,
+    };
+    use pretty_assertions::assert_eq;
+    use serial_test::parallel;
+    use test_case::test_case;
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys
+    }
+
+    fn sorted(names: &[&'static str]) -> Vec<&'static str> {
+        let mut names = names.to_vec();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    #[parallel]
+    fn schema_has_every_top_level_key() {
+        let mut expect = serde_names::<Config>().to_vec();
+        expect.extend(EXTRA_TOP_LEVEL_KEYS);
+        assert_eq!(keys(&schema()["properties"]), sorted(&expect));
+    }
+
+    #[test]
+    #[parallel]
+    fn schema_has_every_command_key() {
+        assert_eq!(
+            keys(&schema()["$defs"]["command"]["properties"]),
+            sorted(serde_names::<CommandConfig>()),
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn schema_has_every_profile_key() {
+        assert_eq!(
+            keys(&schema()["$defs"]["profile"]["properties"]),
+            sorted(PROFILE_KEYS),
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn schema_has_every_hook() {
+        assert_eq!(
+            keys(&schema()["properties"]["hooks"]["properties"]),
+            sorted(serde_names::<HooksConfig>()),
+        );
+    }
+
+    #[test_case("commandType", serde_names::<CommandType>())]
+    #[test_case("invoke", serde_names::<Invoke>())]
+    #[test_case("pathArgs", serde_names::<PathArgs>())]
+    #[test_case("runMode", serde_names::<OldRunMode>())]
+    #[test_case("submodules", serde_names::<Submodules>())]
+    #[test_case("allFilesFrom", serde_names::<AllFilesFrom>())]
+    #[parallel]
+    fn schema_has_every_enum_value(definition: &str, expect: &[&str]) {
+        let values = schema()["$defs"][definition]["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(values, expect);
+    }
+
+    fn collect_refs<'a>(value: &'a Value, refs: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(o) => {
+                if let Some(r) = o.get("$ref").and_then(Value::as_str) {
+                    refs.push(r);
+                }
+                o.values().for_each(|v| collect_refs(v, refs));
+            }
+            Value::Array(a) => a.iter().for_each(|v| collect_refs(v, refs)),
+            _ => (),
+        }
+    }
+
+    #[test]
+    #[parallel]
+    fn schema_refs_point_at_defs() {
+        let schema = schema();
+        let mut found = vec![];
+        collect_refs(&schema, &mut found);
+        assert!(!found.is_empty());
+        for r in found {
+            let name = r
+                .strip_prefix("#/$defs/")
+                .unwrap_or_else(|| panic!("{r} is not a ref to $defs"));
+            assert!(schema["$defs"].get(name).is_some(), "{r} is not defined");
+        }
+    }
+
+    // The working_dir key has its own deserializer, so we check that each
+    // value in the schema is accepted instead.
+    #[test]
+    #[parallel]
+    fn schema_working_dir_values_are_valid() -> anyhow::Result<()> {
+        let values = schema()["$defs"]["workingDir"]["oneOf"][0]["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap().to_string())
+            .chain(["{ chdir_to = \"some/dir\" }".to_string()])
+            .collect::<Vec<_>>();
+        for v in values {
+            let v = if v.starts_with('{') {
+                v
+            } else {
+                format!("{v:?}")
+            };
+            toml::from_str::<CommandConfig>(&format!(
+                "type = \"lint\"\ninclude = \"*\"\ncmd = \"foo\"\nok_exit_codes = 0\nworking_dir = {v}\n",
+            ))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn schema_exit_code_range_matches_config() {
+        let exit_code = &schema()["$defs"]["exitCode"];
+        let min = exit_code["minimum"].as_i64().unwrap();
+        let max = exit_code["maximum"].as_i64().unwrap();
+        let parse = |code: i64| {
+            toml::from_str::<CommandConfig>(&format!(
+                "type = \"lint\"\ninclude = \"*\"\ncmd = \"foo\"\nok_exit_codes = {code}\n",
+            ))
+        };
+        assert!(parse(min).is_ok());
+        assert!(parse(max).is_ok());
+        assert!(parse(min - 1).is_err());
+        assert!(parse(max + 1).is_err());
+    }
+}
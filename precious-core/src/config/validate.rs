@@ -0,0 +1,385 @@
+use super::{CommandConfig, Config, ConfigError, HooksConfig};
+use indexmap::IndexMap;
+use itertools::Itertools;
+use log::warn;
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+use toml::{
+    value::{Table, Value},
+    Spanned,
+};
+
+// These are keys that are handled before the config is deserialized, so
+// they aren't fields of the `Config` struct.
+pub(super) const EXTRA_TOP_LEVEL_KEYS: &[&str] = &["allow_unknown_keys", "extends"];
+
+// These are the keys that a profile can override. A profile is kept as a
+// table until it's merged into the config, so there's no struct for it.
+pub(super) const PROFILE_KEYS: &[&str] = &["jobs", "commands"];
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct UnknownKey {
+    pub file: PathBuf,
+    pub key: String,
+    // This is the 1-based line and column of the key.
+    pub position: Option<(usize, usize)>,
+    pub suggestion: Option<&'static str>,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown key `{}` in {}", self.key, self.file.display())?;
+        if let Some((line, col)) = self.position {
+            write!(f, " at line {line} column {col}")?;
+        }
+        if let Some(s) = self.suggestion {
+            write!(f, ". Did you mean `{s}`?")?;
+        }
+        Ok(())
+    }
+}
+
+// Returns an error if the table from the given file has any keys that
+// precious doesn't know about. If the file sets `allow_unknown_keys = true`
+// then these are logged as warnings instead. That's useful for a config file
+// that's shared with older versions of precious.
+pub(super) fn check_unknown_keys(
+    file: &Path,
+    table: &Table,
+    source: &Source,
+) -> Result<(), ConfigError> {
+    let unknown = unknown_keys(file, table, source);
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    if table.get("allow_unknown_keys").and_then(Value::as_bool) == Some(true) {
+        for u in unknown {
+            warn!("{u}");
+        }
+        return Ok(());
+    }
+    Err(ConfigError::UnknownKeys { keys: unknown })
+}
+
+fn unknown_keys(file: &Path, table: &Table, source: &Source) -> Vec<UnknownKey> {
+    let top_level_keys = serde_names::<Config>()
+        .iter()
+        .chain(EXTRA_TOP_LEVEL_KEYS)
+        .copied()
+        .collect::<Vec<_>>();
+    let command_keys = serde_names::<CommandConfig>();
+    let hooks_keys = serde_names::<HooksConfig>();
+
+    let mut unknown = vec![];
+    let mut check = |path: &[&str], valid: &[&'static str]| {
+        let key = path.last().unwrap();
+        if !valid.contains(key) {
+            unknown.push(UnknownKey {
+                file: file.to_path_buf(),
+                key: path.join("."),
+                position: source.position(path),
+                suggestion: suggestion(key, valid),
+            });
+        }
+    };
+
+    for (key, value) in table {
+        check(&[key], &top_level_keys);
+        match (key.as_str(), value) {
+            ("commands", Value::Table(commands)) => {
+                for (name, command) in commands {
+                    if let Value::Table(command) = command {
+                        for k in command.keys() {
+                            check(&["commands", name, k], command_keys);
+                        }
+                    }
+                }
+            }
+            ("hooks", Value::Table(hooks)) => {
+                for k in hooks.keys() {
+                    check(&["hooks", k], hooks_keys);
+                }
+            }
+            ("profiles", Value::Table(profiles)) => {
+                for (profile, table) in profiles {
+                    let table = match table {
+                        Value::Table(t) => t,
+                        _ => continue,
+                    };
+                    for (k, v) in table {
+                        check(&["profiles", profile, k], PROFILE_KEYS);
+                        if let ("commands", Value::Table(commands)) = (k.as_str(), v) {
+                            for (name, command) in commands {
+                                if let Value::Table(command) = command {
+                                    for k in command.keys() {
+                                        check(
+                                            &["profiles", profile, "commands", name, k],
+                                            command_keys,
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    unknown
+}
+
+// We only suggest a key that is a small number of edits away, since a wrong
+// suggestion is worse than none.
+fn suggestion(key: &str, valid: &[&'static str]) -> Option<&'static str> {
+    let max_distance = (key.len() / 3).max(2);
+    valid
+        .iter()
+        .map(|v| (strsim::levenshtein(key, v), *v))
+        .filter(|(d, _)| *d <= max_distance)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, v)| v)
+}
+
+// Serde doesn't give us a way to get the fields of a struct or the variants of
+// an enum, but a derived `Deserialize` impl passes them to
+// `deserialize_struct` or `deserialize_enum`. This deserializer records them
+// and then returns an error to stop deserialization. The names are the ones
+// used in the config, so they include any `rename`.
+pub(super) fn serde_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct NamesDeserializer<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> Deserializer<'de> for NamesDeserializer<'a> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("done"))
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            variants: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = variants;
+            Err(de::Error::custom("done"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map identifier ignored_any
+        }
+    }
+
+    let mut names: &'static [&'static str] = &[];
+    let _ = T::deserialize(NamesDeserializer(&mut names));
+    names
+}
+
+// This holds the text of a config file so that we can find the position of a
+// key in it. For a config embedded in another file, like `Cargo.toml`, the
+// prefix is the path to the table holding the config. Finding positions is
+// best effort. If the file can't be parsed, or isn't TOML, we just don't
+// report a position.
+pub(super) struct Source {
+    text: String,
+    root: Option<Node>,
+    prefix: Vec<String>,
+}
+
+impl Source {
+    // The prefixes are the keys that may hold an embedded config. We use the
+    // first one that exists in the file.
+    pub(super) fn load(file: &Path, prefixes: &[&str]) -> Source {
+        let text = fs::read_to_string(file).unwrap_or_default();
+        let root = if file.extension().is_some_and(|e| e == "toml") {
+            toml::from_str::<Node>(&text).ok()
+        } else {
+            None
+        };
+        let prefix = prefixes
+            .iter()
+            .map(|p| p.split('.').map(String::from).collect::<Vec<_>>())
+            .find(|p| {
+                root.as_ref()
+                    .and_then(|r| r.find(&p.iter().map(String::as_str).collect::<Vec<_>>()))
+                    .is_some()
+            })
+            .unwrap_or_default();
+
+        Source { text, root, prefix }
+    }
+
+    pub(super) fn position(&self, path: &[&str]) -> Option<(usize, usize)> {
+        let full = self
+            .prefix
+            .iter()
+            .map(String::as_str)
+            .chain(path.iter().copied())
+            .collect::<Vec<_>>();
+        let (start, _) = self.root.as_ref()?.find(&full)?;
+
+        let before = &self.text[..start];
+        let line = before.matches('\n').count() + 1;
+        let col = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        Some((line, col))
+    }
+}
+
+// This is a TOML document with the span of every key. We only need the keys,
+// so other values aren't kept.
+#[derive(Debug)]
+enum Node {
+    Table(IndexMap<Spanned<String>, Node>),
+    Other,
+}
+
+impl Node {
+    // Returns the span of the key at the given path.
+    fn find(&self, path: &[&str]) -> Option<(usize, usize)> {
+        let (first, rest) = path.split_first()?;
+        let table = match self {
+            Node::Table(t) => t,
+            Node::Other => return None,
+        };
+        let (key, node) = table.iter().find(|(k, _)| k.get_ref() == first)?;
+        if rest.is_empty() {
+            return Some(key.span());
+        }
+        node.find(rest)
+    }
+}
+
+impl<'de> Deserialize<'de> for Node {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Node, D::Error> {
+        struct NodeVisitor;
+
+        impl<'de> Visitor<'de> for NodeVisitor {
+            type Value = Node;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("any TOML value")
+            }
+
+            fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Node, M::Error> {
+                let mut table = IndexMap::new();
+                while let Some(key) = map.next_key::<Spanned<String>>()? {
+                    let value = map.next_value::<Node>()?;
+                    table.insert(key, value);
+                }
+                Ok(Node::Table(table))
+            }
+
+            fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<Node, S::Error> {
+                while seq.next_element::<IgnoredAny>()?.is_some() {}
+                Ok(Node::Other)
+            }
+
+            fn visit_bool<E>(self, _: bool) -> Result<Node, E> {
+                Ok(Node::Other)
+            }
+
+            fn visit_i64<E>(self, _: i64) -> Result<Node, E> {
+                Ok(Node::Other)
+            }
+
+            fn visit_u64<E>(self, _: u64) -> Result<Node, E> {
+                Ok(Node::Other)
+            }
+
+            fn visit_f64<E>(self, _: f64) -> Result<Node, E> {
+                Ok(Node::Other)
+            }
+
+            fn visit_str<E>(self, _: &str) -> Result<Node, E> {
+                Ok(Node::Other)
+            }
+        }
+
+        deserializer.deserialize_any(NodeVisitor)
+    }
+}
+
+// Lets us list the keys in an error.
+pub(super) fn format_keys(keys: &[UnknownKey]) -> String {
+    keys.iter().map(|k| k.to_string()).join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serial_test::parallel;
+    use test_case::test_case;
+
+    #[test_case("lint_flag", &["lint_flags", "tidy_flags"], Some("lint_flags"); "missing s")]
+    #[test_case("exlude", &["exclude", "include"], Some("exclude"); "missing letter")]
+    #[test_case("invok", &["invoke", "include"], Some("invoke"); "missing last letter")]
+    #[test_case("something_else", &["exclude", "include"], None; "nothing close")]
+    #[parallel]
+    fn suggestions(key: &str, valid: &[&'static str], expect: Option<&'static str>) {
+        assert_eq!(suggestion(key, valid), expect);
+    }
+
+    #[test]
+    #[parallel]
+    fn fields_of_command_config() {
+        let fields = serde_names::<CommandConfig>();
+        for f in ["type", "include", "lint_flags", "disabled"] {
+            assert!(fields.contains(&f), "{f} is a field");
+        }
+    }
+
+    #[test]
+    #[parallel]
+    fn variants_of_enum() {
+        assert_eq!(
+            serde_names::<crate::config::OldRunMode>(),
+            &["files", "dirs", "root"],
+        );
+    }
+
+    #[test]
+    #[parallel]
+    fn key_positions() -> anyhow::Result<()> {
+        let td = tempfile::tempdir()?;
+        let file = td.path().join("Cargo.toml");
+        fs::write(
+            &file,
+            "[package]\nname = \"foo\"\n\n[package.metadata.precious.commands.rustfmt]\ntype = \"lint\"\n  lint_flag = \"--check\"\n",
+        )?;
+
+        let source = Source::load(
+            &file,
+            &["package.metadata.precious", "workspace.metadata.precious"],
+        );
+        assert_eq!(
+            source.position(&["commands", "rustfmt", "lint_flag"]),
+            Some((6, 3))
+        );
+        assert_eq!(
+            source.position(&["commands", "rustfmt", "type"]),
+            Some((5, 1))
+        );
+        assert_eq!(source.position(&["commands", "rustfmt", "nope"]), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+use crate::{config::HooksConfig, vcs};
+use anyhow::{Context, Result};
+use log::debug;
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum HookError {
+    #[error("Could not find a git checkout containing {}, which is needed to manage hooks", root.display())]
+    NoGitCheckout { root: PathBuf },
+
+    #[error("Cannot install the {name:} hook because {} already exists", chained.display())]
+    ChainedHookExists {
+        name: &'static str,
+        chained: PathBuf,
+    },
+}
+
+pub const HOOK_NAMES: &[&str] = &["pre-commit", "pre-push", "commit-msg"];
+
+// This line is how we recognize a hook script that we wrote.
+const MARKER: &str = "# This hook was installed by precious.";
+
+// When we install a hook where one already exists, we move the existing hook
+// aside to this name and call it from our hook.
+const CHAINED_SUFFIX: &str = ".precious-chained";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HookStatus {
+    NotInstalled,
+    Installed,
+    InstalledAndChained,
+    NotPrecious,
+}
+
+impl fmt::Display for HookStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HookStatus::NotInstalled => write!(f, "not installed"),
+            HookStatus::Installed => write!(f, "installed"),
+            HookStatus::InstalledAndChained => {
+                write!(f, "installed, and runs the hook that was there before it")
+            }
+            HookStatus::NotPrecious => write!(f, "a hook not installed by precious is present"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Hooks {
+    hooks_dir: PathBuf,
+    // The config file path relative to the checkout root, if it's not in the
+    // root. Git runs hooks from the root, so precious wouldn't find it
+    // otherwise.
+    config_file: Option<PathBuf>,
+    precious_exe: PathBuf,
+    config: HooksConfig,
+}
+
+impl Hooks {
+    pub fn new(
+        project_root: &Path,
+        config_file: &Path,
+        config: Option<HooksConfig>,
+        has_commit_msg_commands: bool,
+    ) -> Result<Hooks> {
+        let mut vcs = vcs::find(project_root).ok_or_else(|| HookError::NoGitCheckout {
+            root: project_root.to_path_buf(),
+        })?;
+        let hooks_dir = vcs.hooks_dir()?;
+        let vcs_root = fs::canonicalize(vcs.root()?)?;
+
+        let config_file = fs::canonicalize(config_file)?;
+        let config_file = match config_file.parent() {
+            Some(p) if p == vcs_root => None,
+            _ => pathdiff::diff_paths(&config_file, &vcs_root),
+        };
+
+        // If there's no [hooks] table at all we install a pre-commit hook
+        // that lints staged files, since that's what almost everyone wants,
+        // and a commit-msg hook if there's anything for it to run.
+        let config = config.unwrap_or_else(|| HooksConfig {
+            pre_commit: Some(vec![String::from("lint"), String::from("--staged")]),
+            commit_msg: has_commit_msg_commands
+                .then(|| vec![String::from("lint"), String::from("--commit-msg")]),
+            ..Default::default()
+        });
+
+        Ok(Hooks {
+            hooks_dir,
+            config_file,
+            precious_exe: env::current_exe()?,
+            config,
+        })
+    }
+
+    pub fn install(&self) -> Result<Vec<(&'static str, HookStatus)>> {
+        fs::create_dir_all(&self.hooks_dir)
+            .with_context(|| format!("Creating hooks dir at {}", self.hooks_dir.display()))?;
+
+        let mut installed = vec![];
+        for name in HOOK_NAMES {
+            let args = match self.args_for(name) {
+                Some(a) => a,
+                None => continue,
+            };
+
+            let hook = self.hook_path(name);
+            if hook.exists() && !is_precious_hook(&hook) {
+                let chained = self.chained_path(name);
+                if chained.exists() {
+                    return Err(HookError::ChainedHookExists { name, chained }.into());
+                }
+                debug!(
+                    "Moving existing hook at {} to {}",
+                    hook.display(),
+                    chained.display(),
+                );
+                fs::rename(&hook, &chained)?;
+            }
+
+            debug!("Writing {name} hook to {}", hook.display());
+            fs::write(&hook, self.script(name, args))
+                .with_context(|| format!("Writing hook to {}", hook.display()))?;
+            make_executable(&hook)?;
+
+            installed.push((*name, self.status_of(name)));
+        }
+
+        Ok(installed)
+    }
+
+    pub fn uninstall(&self) -> Result<Vec<(&'static str, HookStatus)>> {
+        let mut uninstalled = vec![];
+        for name in HOOK_NAMES {
+            let hook = self.hook_path(name);
+            if !hook.exists() || !is_precious_hook(&hook) {
+                continue;
+            }
+
+            debug!("Removing {name} hook at {}", hook.display());
+            fs::remove_file(&hook)?;
+            let chained = self.chained_path(name);
+            if chained.exists() {
+                debug!("Restoring {name} hook from {}", chained.display());
+                fs::rename(&chained, &hook)?;
+            }
+
+            uninstalled.push((*name, self.status_of(name)));
+        }
+
+        Ok(uninstalled)
+    }
+
+    pub fn status(&self) -> Vec<(&'static str, HookStatus)> {
+        HOOK_NAMES.iter().map(|n| (*n, self.status_of(n))).collect()
+    }
+
+    pub fn hooks_dir(&self) -> &Path {
+        &self.hooks_dir
+    }
+
+    fn status_of(&self, name: &str) -> HookStatus {
+        let hook = self.hook_path(name);
+        if !hook.exists() {
+            return HookStatus::NotInstalled;
+        }
+        if !is_precious_hook(&hook) {
+            return HookStatus::NotPrecious;
+        }
+        if self.chained_path(name).exists() {
+            return HookStatus::InstalledAndChained;
+        }
+        HookStatus::Installed
+    }
+
+    fn args_for(&self, name: &str) -> Option<&[String]> {
+        match name {
+            "pre-commit" => self.config.pre_commit.as_deref(),
+            "pre-push" => self.config.pre_push.as_deref(),
+            "commit-msg" => self.config.commit_msg.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn hook_path(&self, name: &str) -> PathBuf {
+        self.hooks_dir.join(name)
+    }
+
+    fn chained_path(&self, name: &str) -> PathBuf {
+        self.hooks_dir.join(format!("{name}{CHAINED_SUFFIX}"))
+    }
+
+    fn script(&self, name: &str, args: &[String]) -> String {
+        let mut script =
+            format!("#!/bin/sh\n{MARKER} Run \"precious hooks uninstall\" to remove it.\n\n");
+
+        // Git sends the list of refs being pushed to a pre-push hook on
+        // stdin, so we need to save it in order to pass it on to the chained
+        // hook. The trailing "x" preserves any trailing newlines.
+        let chained = format!("\"$(dirname \"$0\")/{name}{CHAINED_SUFFIX}\"");
+        if name == "pre-push" {
+            script.push_str(&format!(
+                "input=$(cat; echo x)\ninput=${{input%x}}\nif [ -x {chained} ]; then\n    printf '%s' \"$input\" | {chained} \"$@\" || exit $?\nfi\n\n",
+            ));
+        } else {
+            script.push_str(&format!(
+                "if [ -x {chained} ]; then\n    {chained} \"$@\" || exit $?\nfi\n\n",
+            ));
+        }
+
+        let exe = shell_quote(&self.precious_exe.to_string_lossy());
+        script.push_str(&format!(
+            "if [ -x {exe} ]; then\n    precious={exe}\nelse\n    precious=precious\nfi\n\n",
+        ));
+
+        let mut cmd = vec![String::from("exec \"$precious\"")];
+        if let Some(c) = &self.config_file {
+            let c = c.to_string_lossy().replace('\\', "/");
+            cmd.push(format!("--config \"$PWD\"/{}", shell_quote(&c)));
+        }
+        cmd.extend(args.iter().map(|a| shell_quote(a)));
+        // The commit-msg hook is given the path to the file containing the
+        // commit message.
+        if name == "commit-msg" {
+            cmd.push(String::from("\"$@\""));
+        }
+        script.push_str(&cmd.join(" "));
+        script.push('\n');
+
+        script
+    }
+}
+
+fn is_precious_hook(path: &Path) -> bool {
+    match fs::read_to_string(path) {
+        Ok(content) => content.lines().any(|l| l.starts_with(MARKER)),
+        Err(_) => false,
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use precious_testhelper::TestHelper;
+    use pretty_assertions::assert_eq;
+    use serial_test::parallel;
+
+    fn hooks(helper: &TestHelper, config: Option<HooksConfig>) -> Result<Hooks> {
+        let config_file = helper.config_file("precious.toml");
+        fs::write(&config_file, "")?;
+        Hooks::new(&helper.precious_root(), &config_file, config, false)
+    }
+
+    #[test]
+    #[parallel]
+    fn install_default_hooks() -> Result<()> {
+        let helper = TestHelper::new()?.with_git_repo()?;
+        let hooks = hooks(&helper, None)?;
+
+        assert_eq!(
+            hooks.install()?,
+            vec![("pre-commit", HookStatus::Installed)],
+        );
+        assert_eq!(
+            hooks.status(),
+            vec![
+                ("pre-commit", HookStatus::Installed),
+                ("pre-push", HookStatus::NotInstalled),
+                ("commit-msg", HookStatus::NotInstalled),
+            ],
+        );
+
+        let script = fs::read_to_string(helper.git_root().join(".git/hooks/pre-commit"))?;
+        assert!(script.contains(MARKER));
+        assert!(script.ends_with("exec \"$precious\" 'lint' '--staged'\n"));
+
+        assert_eq!(
+            hooks.uninstall()?,
+            vec![("pre-commit", HookStatus::NotInstalled)],
+        );
+        assert!(!helper.git_root().join(".git/hooks/pre-commit").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn install_default_hooks_with_commit_msg_commands() -> Result<()> {
+        let helper = TestHelper::new()?.with_git_repo()?;
+        let config_file = helper.config_file("precious.toml");
+        fs::write(&config_file, "")?;
+        let hooks = Hooks::new(&helper.precious_root(), &config_file, None, true)?;
+
+        assert_eq!(
+            hooks.install()?,
+            vec![
+                ("pre-commit", HookStatus::Installed),
+                ("commit-msg", HookStatus::Installed),
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn install_configured_hooks() -> Result<()> {
+        let helper = TestHelper::new()?.with_git_repo()?;
+        let hooks = hooks(
+            &helper,
+            Some(HooksConfig {
+                pre_commit: None,
+                pre_push: Some(vec![String::from("lint"), String::from("--all")]),
+                commit_msg: Some(vec![String::from("lint"), String::from("--commit-msg")]),
+            }),
+        )?;
+
+        assert_eq!(
+            hooks.install()?,
+            vec![
+                ("pre-push", HookStatus::Installed),
+                ("commit-msg", HookStatus::Installed),
+            ],
+        );
+
+        let pre_push = fs::read_to_string(helper.git_root().join(".git/hooks/pre-push"))?;
+        assert!(pre_push.contains("input=$(cat; echo x)"));
+        assert!(pre_push.ends_with("exec \"$precious\" 'lint' '--all'\n"));
+        let commit_msg = fs::read_to_string(helper.git_root().join(".git/hooks/commit-msg"))?;
+        assert!(commit_msg.ends_with("exec \"$precious\" 'lint' '--commit-msg' \"$@\"\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn install_chains_existing_hook() -> Result<()> {
+        let helper = TestHelper::new()?.with_git_repo()?;
+        let existing = "#!/bin/sh\necho existing\n";
+        let hook = helper.git_root().join(".git/hooks/pre-commit");
+        fs::write(&hook, existing)?;
+
+        let hooks = hooks(&helper, None)?;
+        assert_eq!(hooks.status()[0], ("pre-commit", HookStatus::NotPrecious));
+        assert_eq!(
+            hooks.install()?,
+            vec![("pre-commit", HookStatus::InstalledAndChained)],
+        );
+        assert_eq!(
+            fs::read_to_string(
+                helper
+                    .git_root()
+                    .join(".git/hooks/pre-commit.precious-chained")
+            )?,
+            existing,
+        );
+
+        // Installing again replaces our hook without touching the chained
+        // one.
+        assert_eq!(
+            hooks.install()?,
+            vec![("pre-commit", HookStatus::InstalledAndChained)],
+        );
+
+        assert_eq!(
+            hooks.uninstall()?,
+            vec![("pre-commit", HookStatus::NotPrecious)],
+        );
+        assert_eq!(fs::read_to_string(&hook)?, existing);
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn install_respects_core_hooks_path() -> Result<()> {
+        let helper = TestHelper::new()?.with_git_repo()?;
+        precious_helpers::exec::run(
+            "git",
+            &["config", "core.hooksPath", "my-hooks"],
+            &std::collections::HashMap::new(),
+            &[0],
+            None,
+            Some(&helper.git_root()),
+        )?;
+
+        let hooks = hooks(&helper, None)?;
+        hooks.install()?;
+        assert!(helper.git_root().join("my-hooks/pre-commit").exists());
+        assert!(!helper.git_root().join(".git/hooks/pre-commit").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn config_file_in_subdir() -> Result<()> {
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_precious_root_in_subdir("subdir");
+        fs::create_dir_all(helper.precious_root())?;
+
+        let hooks = hooks(&helper, None)?;
+        hooks.install()?;
+        let script = fs::read_to_string(helper.git_root().join(".git/hooks/pre-commit"))?;
+        assert!(script.ends_with(
+            "exec \"$precious\" --config \"$PWD\"/'subdir/precious.toml' 'lint' '--staged'\n"
+        ));
+
+        Ok(())
+    }
+}
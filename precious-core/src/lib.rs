@@ -3,5 +3,8 @@ pub mod precious;
 mod chars;
 mod command;
 mod config;
+mod hooks;
+mod lock;
 mod paths;
+mod report;
 mod vcs;
@@ -0,0 +1,171 @@
+use crate::vcs;
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use log::{debug, info};
+use precious_helpers::exec;
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process, thread,
+    time::Duration,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum LockError {
+    #[error("Another precious is running (pid {pid:}). Pass --wait to wait for it to finish.")]
+    AlreadyLocked { pid: String },
+
+    #[error("Precious was interrupted while waiting for another precious to finish")]
+    Interrupted,
+}
+
+const LOCK_FILE: &str = "precious.lock";
+
+// This is an advisory lock that is held by any precious run that may change
+// the working tree, either by tidying files or by stashing unstaged changes.
+// Without it, two runs that overlap (say, one from an editor and one from a
+// pre-commit hook) could pop each other's stashes. The OS releases the lock
+// when the file is closed, so a precious process which is killed never leaves
+// a stale lock behind.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    pub fn acquire(project_root: &Path, wait: bool) -> Result<Lock> {
+        let path = lock_path(project_root)?;
+        debug!("Taking the precious lock at {}", path.display());
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Opening lock file at {}", path.display()))?;
+
+        if file.try_lock_exclusive().is_err() {
+            let pid = holder_pid(&path);
+            if !wait {
+                return Err(LockError::AlreadyLocked { pid }.into());
+            }
+
+            info!("Waiting for another precious (pid {pid}) to finish");
+            // We poll rather than blocking on the lock so that we can still
+            // exit promptly on SIGINT or SIGTERM.
+            while file.try_lock_exclusive().is_err() {
+                if exec::is_terminating() {
+                    return Err(LockError::Interrupted.into());
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.flush()?;
+
+        Ok(Lock { file, path })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        debug!("Releasing the precious lock at {}", self.path.display());
+        // We empty the file rather than deleting it. If we deleted it,
+        // another process could be waiting on a lock for the unlinked file
+        // while a third process locks a newly created one.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+// We prefer to put the lock file in the VCS directory, since that is shared
+// by every precious config in the checkout and is never linted. Outside of a
+// checkout, we use a file in the temp dir that is unique to the project root.
+fn lock_path(project_root: &Path) -> Result<PathBuf> {
+    if let Some(mut vcs) = vcs::find(project_root) {
+        return Ok(vcs.state_dir()?.join(LOCK_FILE));
+    }
+
+    let digest = md5::compute(project_root.to_string_lossy().as_bytes());
+    Ok(env::temp_dir().join(format!("precious-{digest:x}.lock")))
+}
+
+fn holder_pid(path: &Path) -> String {
+    match fs::read_to_string(path) {
+        Ok(pid) if !pid.trim().is_empty() => pid.trim().to_string(),
+        _ => String::from("unknown"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use precious_testhelper::TestHelper;
+    use pretty_assertions::assert_eq;
+    use serial_test::parallel;
+
+    #[test]
+    #[parallel]
+    fn lock_in_git_dir() -> Result<()> {
+        let helper = TestHelper::new()?.with_git_repo()?;
+        let root = helper.precious_root();
+
+        let lock = Lock::acquire(&root, false)?;
+        let path = helper.git_root().join(".git").join(LOCK_FILE);
+        assert_eq!(fs::read_to_string(&path)?, process::id().to_string());
+
+        let err = Lock::acquire(&root, false).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&LockError::AlreadyLocked {
+                pid: process::id().to_string(),
+            }),
+        );
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path)?, "");
+        Lock::acquire(&root, false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn lock_without_vcs() -> Result<()> {
+        let helper = TestHelper::new()?;
+        let root = helper.precious_root();
+
+        let lock = Lock::acquire(&root, false)?;
+        assert!(Lock::acquire(&root, false).is_err());
+        drop(lock);
+        Lock::acquire(&root, false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn wait_for_lock() -> Result<()> {
+        let helper = TestHelper::new()?.with_git_repo()?;
+        let root = helper.precious_root();
+
+        let lock = Lock::acquire(&root, false)?;
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(lock);
+        });
+        Lock::acquire(&root, true)?;
+        releaser.join().unwrap();
+
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
 pub mod finder;
 pub mod matcher;
 pub mod mode;
+pub mod shard;
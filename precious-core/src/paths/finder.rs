@@ -1,31 +1,53 @@
 use crate::{
+    config,
     paths::{
         matcher::{Matcher, MatcherBuilder},
         mode::Mode,
     },
-    vcs,
+    vcs::{self, Submodules, Vcs},
 };
 use anyhow::Result;
 use clean_path::Clean;
+use ignore::WalkState;
+use itertools::Itertools;
 use log::{debug, error};
-use once_cell::sync::Lazy;
-use precious_helpers::exec;
-use regex::Regex;
+use serde::Deserialize;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
 };
+use tempfile::TempDir;
 use thiserror::Error;
 
+// This controls how `Mode::All` finds files. In a VCS checkout we ask the VCS
+// by default, since that's much faster than walking a large tree and it
+// agrees with the VCS about what is ignored.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum AllFilesFrom {
+    #[default]
+    #[serde(rename = "vcs")]
+    Vcs,
+    #[serde(rename = "walk")]
+    Walk,
+}
+
 #[derive(Debug)]
 pub struct Finder {
     mode: Mode,
     project_root: PathBuf,
-    git_root: Option<PathBuf>,
+    vcs: Option<Box<dyn Vcs>>,
     cwd: PathBuf,
     exclude_globs: Vec<String>,
+    exclude_attributes: Vec<String>,
+    submodules: Submodules,
+    all_files_from: AllFilesFrom,
     stashed: bool,
+    // This holds a copy of the staged content for `Mode::GitStagedFromIndex`.
+    // The directory is deleted when the `Finder` is dropped.
+    staged_dir: Option<TempDir>,
+    staged_root: Option<PathBuf>,
 }
 
 #[derive(Debug, Error, Eq, PartialEq)]
@@ -39,35 +61,51 @@ pub enum FinderError {
     #[error("Found a path on the Cli which does not exist: {:}", path.display())]
     NonExistentPathOnCli { path: PathBuf },
 
-    #[error("Could not determine the repo root by running \"git rev-parse --show-toplevel\"")]
-    CouldNotDetermineRepoRoot,
+    #[error("The commit message file at {} does not exist", path.display())]
+    CommitMsgFileDoesNotExist { path: PathBuf },
+
+    #[error("Could not find a git or hg checkout containing {} when looking for {mode:}", root.display())]
+    NoVcsCheckout { mode: Mode, root: PathBuf },
+
+    #[error("Found unstaged changes that were stashed by an earlier run of precious which did not exit cleanly. Restore them by running \"git stash pop {stash:}\" before running with {mode:} again.")]
+    OrphanedStash { mode: Mode, stash: String },
+
+    #[error("Cannot use {mode:} because the {} submodule has staged files and unstaged changes, and precious only stashes changes in the superproject. Set submodules = \"exclude\" in your config or use --staged-from-index instead.", submodule.display())]
+    CannotStashInSubmodule { mode: Mode, submodule: PathBuf },
 
     #[error("The path \"{}\" does not contain \"{}\" as a prefix", path.display(), prefix.display())]
     PrefixNotFound { path: PathBuf, prefix: PathBuf },
 }
 
-static KEEP_INDEX_RE: Lazy<Regex> = Lazy::new(|| Regex::new(".*").unwrap());
-
 impl Finder {
     pub fn new(
         mode: Mode,
         project_root: PathBuf,
         cwd: PathBuf,
         exclude_globs: Vec<String>,
+        exclude_attributes: Vec<String>,
+        submodules: Submodules,
+        all_files_from: AllFilesFrom,
     ) -> Result<Finder> {
+        let project_root = fs::canonicalize(project_root)?;
         Ok(Finder {
             mode,
-            project_root: fs::canonicalize(project_root)?,
-            git_root: None,
+            vcs: vcs::find(&project_root),
+            project_root,
             cwd,
             exclude_globs,
+            exclude_attributes,
+            submodules,
+            all_files_from,
             stashed: false,
+            staged_dir: None,
+            staged_root: None,
         })
     }
 
     pub fn files(&mut self, cli_paths: Vec<PathBuf>) -> Result<Option<Vec<PathBuf>>> {
         match self.mode {
-            Mode::FromCli => (),
+            Mode::FromCli | Mode::CommitMsg => (),
             _ => {
                 if !cli_paths.is_empty() {
                     return Err(
@@ -80,47 +118,201 @@ impl Finder {
         let mut files = match self.mode {
             Mode::All => self.all_files()?,
             Mode::FromCli => self.files_from_cli(cli_paths)?,
-            Mode::GitModified => self.git_modified_files()?,
-            Mode::GitStaged | Mode::GitStagedWithStash => self.git_staged_files()?,
+            Mode::CommitMsg => self.commit_msg_file(cli_paths)?,
+            Mode::GitModified => self.vcs_modified_files()?,
+            Mode::GitStaged | Mode::GitStagedWithStash | Mode::GitStagedFromIndex => {
+                self.vcs_staged_files()?
+            }
         };
         files.sort();
 
+        if self.mode != Mode::CommitMsg {
+            files = self.without_excluded_attributes(files)?;
+        }
+
         if files.is_empty() {
             return match self.mode {
-                Mode::GitModified | Mode::GitStaged | Mode::GitStagedWithStash => Ok(None),
+                Mode::GitModified
+                | Mode::GitStaged
+                | Mode::GitStagedWithStash
+                | Mode::GitStagedFromIndex => Ok(None),
                 _ => Err(FinderError::AllPathsWereExcluded { mode: self.mode }.into()),
             };
         }
 
+        if self.mode == Mode::GitStagedFromIndex {
+            self.checkout_staged()?;
+        }
+
         Ok(Some(files))
     }
 
-    fn git_root(&mut self) -> Result<PathBuf> {
-        if let Some(r) = &self.git_root {
-            return Ok(r.to_path_buf());
+    // Returns the attributes from the given list that are set for each
+    // file. The files must be relative to the project root. If there is no
+    // VCS checkout then no file has any attributes.
+    pub fn attributes(
+        &mut self,
+        files: &[PathBuf],
+        names: &[String],
+    ) -> Result<HashMap<PathBuf, HashSet<String>>> {
+        match &mut self.vcs {
+            Some(vcs) => vcs.attributes(files, names),
+            None => Ok(HashMap::new()),
         }
+    }
 
-        let res = exec::run(
-            "git",
-            &["rev-parse", "--show-toplevel"],
-            &HashMap::new(),
-            &[0],
-            None,
-            Some(&self.project_root),
-        )?;
+    fn without_excluded_attributes(&mut self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let names = self.exclude_attributes.clone();
+        let attributes = self.attributes(&files, &names)?;
+        if attributes.is_empty() {
+            return Ok(files);
+        }
+
+        Ok(files
+            .into_iter()
+            .filter(|f| match attributes.get(f) {
+                Some(attrs) => {
+                    debug!(
+                        "Excluding {} because it has the git attribute(s) {}",
+                        f.display(),
+                        attrs.iter().sorted().join(" "),
+                    );
+                    false
+                }
+                None => true,
+            })
+            .collect())
+    }
+
+    // When the mode is `Mode::GitStagedFromIndex`, this returns the project
+    // root inside the directory containing the copy of the staged
+    // content. Commands should be run against this root instead of the real
+    // project root. The paths returned by `files` are valid relative to
+    // either root.
+    pub fn staged_root(&self) -> Option<&Path> {
+        self.staged_root.as_deref()
+    }
+
+    fn checkout_staged(&mut self) -> Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("precious-staged-")
+            .tempdir()?;
+        debug!("Copying staged content into {}", dir.path().display());
+        // We only copy the staged files, plus any config files, since
+        // copying the whole index is slow in a large repo. The staged files
+        // from the VCS are relative to its root, unlike the ones from
+        // `files`. A changed submodule is reported as a single path, which
+        // isn't something we can copy.
+        let names = config::file_names();
+        let subs = self.vcs()?.submodules()?;
+        let mut staged = self.vcs()?.staged_files()?;
+        staged.retain(|f| !subs.contains(f));
+        self.vcs()?.checkout_staged(dir.path(), &staged, &names)?;
+        if self.submodules == Submodules::Include {
+            for sub in subs {
+                let mut sub_vcs = self.submodule_vcs(&sub)?;
+                let staged = sub_vcs.staged_files()?;
+                sub_vcs.checkout_staged(&dir.path().join(&sub), &staged, &names)?;
+            }
+        }
 
-        let stdout = res.stdout.ok_or(FinderError::CouldNotDetermineRepoRoot)?;
-        self.git_root = Some(PathBuf::from(stdout.trim()));
+        let vcs_root = fs::canonicalize(self.vcs()?.root()?)?;
+        let rel =
+            self.project_root
+                .strip_prefix(&vcs_root)
+                .map_err(|_| FinderError::PrefixNotFound {
+                    path: self.project_root.clone(),
+                    prefix: vcs_root.clone(),
+                })?;
+        self.staged_root = Some(fs::canonicalize(dir.path())?.join(rel));
+        self.staged_dir = Some(dir);
+
+        Ok(())
+    }
+
+    fn vcs(&mut self) -> Result<&mut Box<dyn Vcs>> {
+        let mode = self.mode;
+        let root = self.project_root.clone();
+        self.vcs
+            .as_mut()
+            .ok_or_else(|| FinderError::NoVcsCheckout { mode, root }.into())
+    }
 
-        Ok(self.git_root.clone().unwrap())
+    fn submodule_vcs(&mut self, sub: &Path) -> Result<Box<dyn Vcs>> {
+        let dir = self.vcs()?.root()?.join(sub);
+        Ok(vcs::find(&dir).ok_or(FinderError::NoVcsCheckout {
+            mode: self.mode,
+            root: dir,
+        })?)
     }
 
-    fn all_files(&self) -> Result<Vec<PathBuf>> {
+    fn all_files(&mut self) -> Result<Vec<PathBuf>> {
+        if self.all_files_from == AllFilesFrom::Vcs && self.vcs.is_some() {
+            return self.vcs_all_files();
+        }
+
         debug!("Getting all files under {}", self.project_root.display());
-        self.walkdir_files(self.project_root.as_path())
+        let files = self.walkdir_files(self.project_root.as_path())?;
+        self.without_excluded_submodules(files)
+    }
+
+    // The VCS may give us files outside the project root, since the
+    // submodules we look in may be outside of it, and it also lists tracked
+    // files which have been deleted from the working tree.
+    fn vcs_all_files(&mut self) -> Result<Vec<PathBuf>> {
+        debug!(
+            "Getting all files under {} from the VCS",
+            self.project_root.display(),
+        );
+        let vcs_root = fs::canonicalize(self.vcs()?.root()?)?;
+        let files = self
+            .vcs_files(|vcs| vcs.all_files())?
+            .into_iter()
+            .filter(|f| {
+                let full = vcs_root.join(f);
+                full.starts_with(&self.project_root) && full.is_file()
+            })
+            .collect();
+        self.files_from_vcs(files)
     }
 
-    fn files_from_cli(&self, cli_paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    // Walking the tree finds everything in checked out submodules, so when
+    // they are excluded we need to remove their contents afterwards.
+    fn without_excluded_submodules(&mut self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        if self.submodules == Submodules::Include || self.vcs.is_none() {
+            return Ok(files);
+        }
+
+        // Submodules outside of the project root can't contain any of the
+        // files we found, so we can ignore those.
+        let vcs_root = self.vcs()?.root()?;
+        let prefixes = self
+            .vcs()?
+            .submodules()?
+            .into_iter()
+            .filter_map(|s| self.path_relative_to_project_root(&vcs_root.join(s)).ok())
+            .collect::<Vec<_>>();
+        if prefixes.is_empty() {
+            return Ok(files);
+        }
+
+        Ok(files
+            .into_iter()
+            .filter(|f| match prefixes.iter().find(|p| f.starts_with(p)) {
+                Some(p) => {
+                    debug!(
+                        "Excluding {} because it is in the {} submodule",
+                        f.display(),
+                        p.display(),
+                    );
+                    false
+                }
+                None => true,
+            })
+            .collect())
+    }
+
+    fn files_from_cli(&mut self, cli_paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
         debug!("Using the list of files passed from the command line");
         let excluder = self.excluder()?;
 
@@ -144,45 +336,88 @@ impl Finder {
             }
         }
 
-        Ok(files)
+        self.without_excluded_submodules(files)
+    }
+
+    // The commit message file is returned as an absolute path, since it's
+    // usually not under the project root. No exclusions are applied to it.
+    fn commit_msg_file(&mut self, cli_paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let file = match cli_paths.into_iter().next() {
+            Some(f) => self.cwd.join(f),
+            None => self.vcs()?.state_dir()?.join("COMMIT_EDITMSG"),
+        };
+        debug!("Using the commit message file at {}", file.display());
+        if !file.is_file() {
+            return Err(FinderError::CommitMsgFileDoesNotExist { path: file }.into());
+        }
+
+        Ok(vec![file.clean()])
     }
 
-    fn git_modified_files(&mut self) -> Result<Vec<PathBuf>> {
-        debug!("Getting modified files according to git");
-        self.files_from_git(&["diff", "--name-only", "--diff-filter=ACM", "HEAD"])
+    fn vcs_modified_files(&mut self) -> Result<Vec<PathBuf>> {
+        let files = self.vcs_files(|vcs| vcs.modified_files())?;
+        self.files_from_vcs(files)
     }
 
-    fn git_staged_files(&mut self) -> Result<Vec<PathBuf>> {
-        debug!("Getting staged files according to git");
-        self.maybe_git_stash()?;
-        self.files_from_git(&["diff", "--cached", "--name-only", "--diff-filter=ACM"])
+    fn vcs_staged_files(&mut self) -> Result<Vec<PathBuf>> {
+        if self.mode == Mode::GitStagedWithStash {
+            // If we stashed on top of an orphaned stash, the user would have
+            // to untangle two sets of unstaged changes.
+            if let Some(stash) = self.vcs()?.orphaned_stash()? {
+                return Err(FinderError::OrphanedStash {
+                    mode: self.mode,
+                    stash,
+                }
+                .into());
+            }
+            self.stashed = self.vcs()?.stash_unstaged()?;
+        }
+        let files = self.vcs_files(|vcs| vcs.staged_files())?;
+        self.files_from_vcs(files)
     }
 
-    fn maybe_git_stash(&mut self) -> Result<()> {
-        if self.mode != Mode::GitStagedWithStash {
-            return Ok(());
+    // Returns the files from the superproject, plus the files from each
+    // submodule when those are included. The submodule paths are relative to
+    // the submodule root, so we prefix them with the submodule's path. Git
+    // reports a changed submodule as a single path, which is not a file we
+    // can pass to any command, so we always drop those.
+    //
+    // With `--staged-with-stash` we only stash in the superproject. If a
+    // submodule with staged files also has unstaged changes, commands would
+    // see those changes instead of the staged content, so this is an error.
+    fn vcs_files(&mut self, get: fn(&mut dyn Vcs) -> Result<Vec<PathBuf>>) -> Result<Vec<PathBuf>> {
+        let mut files = get(self.vcs()?.as_mut())?;
+        let subs = self.vcs()?.submodules()?;
+        if subs.is_empty() {
+            return Ok(files);
         }
 
-        let git_root = self.git_root()?;
-        let mut mm = git_root.clone();
-        mm.push(".git");
-        mm.push("MERGE_MODE");
-
-        if !mm.exists() {
-            exec::run(
-                "git",
-                &["stash", "--keep-index"],
-                &HashMap::new(),
-                &[0],
-                // If there is a post-checkout hook, git will show any output
-                // it prints to stdout on stderr instead.
-                Some(&[KEEP_INDEX_RE.clone()]),
-                Some(&git_root),
-            )?;
-            self.stashed = true;
+        files.retain(|f| !subs.contains(f));
+        if self.submodules == Submodules::Include {
+            for sub in &subs {
+                debug!("Getting files from the {} submodule", sub.display());
+                let mut sub_vcs = self.submodule_vcs(sub)?;
+                let sub_files = get(sub_vcs.as_mut())?;
+                if self.mode == Mode::GitStagedWithStash
+                    && !sub_files.is_empty()
+                    && !sub_vcs.unstaged_files()?.is_empty()
+                {
+                    return Err(FinderError::CannotStashInSubmodule {
+                        mode: self.mode,
+                        submodule: sub.clone(),
+                    }
+                    .into());
+                }
+                files.extend(
+                    sub_files
+                        .into_iter()
+                        .map(|f| sub.join(f))
+                        .filter(|f| !subs.contains(f)),
+                );
+            }
         }
 
-        Ok(())
+        Ok(files)
     }
 
     fn walkdir_files(&self, root: &Path) -> Result<Vec<PathBuf>> {
@@ -191,68 +426,69 @@ impl Finder {
             excludes.add(&format!("!{d}/**/*"))?;
         }
 
-        let mut files: Vec<PathBuf> = vec![];
-        for result in ignore::WalkBuilder::new(root)
+        // The walk order doesn't matter since `files` sorts what we return.
+        let files: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+        let error: Mutex<Option<ignore::Error>> = Mutex::new(None);
+        ignore::WalkBuilder::new(root)
             .hidden(false)
             .overrides(excludes.build()?)
-            .build()
-        {
-            match result {
-                Ok(ent) => {
-                    if ent.path().is_dir() {
-                        continue;
+            .build_parallel()
+            .run(|| {
+                Box::new(|result| match result {
+                    Ok(ent) => {
+                        // The file type from the walk doesn't follow
+                        // symlinks, so we only need to stat those.
+                        let is_dir = match ent.file_type() {
+                            Some(t) if !t.is_symlink() => t.is_dir(),
+                            _ => ent.path().is_dir(),
+                        };
+                        if !is_dir {
+                            files.lock().unwrap().push(ent.into_path());
+                        }
+                        WalkState::Continue
                     }
-                    files.push(ent.into_path());
-                }
-                Err(e) => return Err(e.into()),
-            };
+                    Err(e) => {
+                        *error.lock().unwrap() = Some(e);
+                        WalkState::Quit
+                    }
+                })
+            });
+        if let Some(e) = error.into_inner().unwrap() {
+            return Err(e.into());
         }
 
         let excluder = self.excluder()?;
         Ok(self
-            .paths_relative_to_project_root(&self.project_root, files)?
+            .paths_relative_to_project_root(&self.project_root, files.into_inner().unwrap())?
             .into_iter()
             .filter(|f| !excluder.path_matches(f, false))
             .collect::<Vec<_>>())
     }
 
-    fn files_from_git(&mut self, args: &[&str]) -> Result<Vec<PathBuf>> {
-        let git_root = self.git_root()?;
-        let result = exec::run(
-            "git",
-            args,
-            &HashMap::new(),
-            &[0],
-            None,
-            Some(&self.project_root),
-        )?;
+    fn files_from_vcs(&mut self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let vcs_root = self.vcs()?.root()?;
         let excluder = self.excluder()?;
 
-        match result.stdout {
-            Some(s) => Ok(
-                // In the common case where the git repo root and project root
-                // are the same, this isn't necessary, because git will give
-                // us paths relative to the project root. But if the precious
-                // root _isn't_ the git root, we need to get the path relative
-                // to the project root, not the repo root.
-                self.paths_relative_to_project_root(
-                    &git_root,
-                    s.lines()
-                        .filter_map(|rel| {
-                            let pb = PathBuf::from(rel);
-                            if excluder.path_matches(&pb, false) {
-                                return None;
-                            }
-
-                            let mut f = git_root.clone();
-                            f.push(&pb);
-                            Some(f)
-                        })
-                        .collect(),
-                )?,
-            ),
-            None => Ok(vec![]),
-        }
+        // In the common case where the repo root and project root are the
+        // same, this isn't necessary, because the VCS will give us paths
+        // relative to the project root. But if the precious root _isn't_ the
+        // repo root, we need to get the path relative to the project root,
+        // not the repo root.
+        self.paths_relative_to_project_root(
+            &vcs_root,
+            files
+                .into_iter()
+                .filter_map(|rel| {
+                    if excluder.path_matches(&rel, false) {
+                        return None;
+                    }
+
+                    let mut f = vcs_root.clone();
+                    f.push(&rel);
+                    Some(f)
+                })
+                .collect(),
+        )
     }
 
     fn excluder(&self) -> Result<Matcher> {
@@ -287,6 +523,17 @@ impl Finder {
     }
 
     fn path_relative_to_project_root(&self, path: &Path) -> Result<PathBuf> {
+        // Canonicalizing every path is slow in a big tree, and the paths we
+        // get from walking the project root or from the VCS are almost always
+        // under the canonical project root already. If the path contains "."
+        // or "..", we can't be sure what it refers to without resolving
+        // symlinks.
+        if let Ok(rel) = path.strip_prefix(&self.project_root) {
+            if rel.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Ok(rel.to_path_buf().clean());
+            }
+        }
+
         // If the directory given is just "." then the first clean() removes
         // that and we then strip the prefix, leaving an empty string. The
         // second clean turns that back into ".".
@@ -308,14 +555,10 @@ impl Drop for Finder {
             return;
         }
 
-        let res = exec::run(
-            "git",
-            &["stash", "pop"],
-            &HashMap::new(),
-            &[0],
-            None,
-            Some(&self.project_root),
-        );
+        let res = match self.vcs.as_mut() {
+            Some(vcs) => vcs.unstash(),
+            None => return,
+        };
 
         if res.is_ok() {
             return;
@@ -330,10 +573,12 @@ mod tests {
     use super::*;
     use anyhow::Result;
     use itertools::Itertools;
+    use precious_helpers::exec;
     use precious_testhelper as testhelper;
     use pretty_assertions::assert_eq;
     use serial_test::parallel;
-    use std::fs;
+    use std::{collections::HashMap, fs};
+    use which::which;
 
     fn new_finder(mode: Mode, root: PathBuf) -> Result<Finder> {
         new_finder_with_excludes(mode, root.clone(), root, vec![])
@@ -349,7 +594,42 @@ mod tests {
         cwd: PathBuf,
         exclude: Vec<String>,
     ) -> Result<Finder> {
-        Finder::new(mode, root, cwd, exclude)
+        Finder::new(
+            mode,
+            root,
+            cwd,
+            exclude,
+            vec![],
+            Submodules::Include,
+            AllFilesFrom::Vcs,
+        )
+    }
+
+    fn new_finder_with_all_files_from(
+        root: PathBuf,
+        all_files_from: AllFilesFrom,
+    ) -> Result<Finder> {
+        Finder::new(
+            Mode::All,
+            root.clone(),
+            root,
+            vec![],
+            vec![],
+            Submodules::Include,
+            all_files_from,
+        )
+    }
+
+    fn git_output(helper: &testhelper::TestHelper, args: &[&str]) -> Result<String> {
+        let res = exec::run(
+            "git",
+            args,
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&helper.git_root()),
+        )?;
+        Ok(res.stdout.unwrap_or_default())
     }
 
     #[cfg(not(target_os = "windows"))]
@@ -404,11 +684,45 @@ mod tests {
         expect.append(&mut gitignores);
         expect.sort();
 
-        let mut finder = new_finder(Mode::All, helper.precious_root())?;
+        let mut finder =
+            new_finder_with_all_files_from(helper.precious_root(), AllFilesFrom::Walk)?;
+        assert_eq!(finder.files(vec![])?, Some(expect));
+        Ok(())
+    }
+
+    // Unlike walking the tree, git includes files that match a gitignore
+    // pattern if they are tracked.
+    #[test]
+    #[parallel]
+    fn all_mode_from_vcs_with_gitignore() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let mut expect = helper.all_files();
+        expect.append(&mut helper.add_gitignore_files()?);
+        helper.write_file("src/can_ignore.md", "untracked")?;
+        helper.write_file("src/untracked.rs", "fn foo() {}\n")?;
+        expect.push(PathBuf::from("src/untracked.rs"));
+        fs::remove_file(helper.precious_root().join("README.md"))?;
+        expect.retain(|f| f != Path::new("README.md"));
+        expect.sort();
+
+        let mut finder = new_finder_with_all_files_from(helper.precious_root(), AllFilesFrom::Vcs)?;
         assert_eq!(finder.files(vec![])?, Some(expect));
         Ok(())
     }
 
+    #[test]
+    #[parallel]
+    fn all_mode_from_vcs_when_repo_root_ne_precious_root() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?
+            .with_precious_root_in_subdir("subdir")
+            .with_git_repo()?;
+        fs::write(helper.git_root().join("outside.txt"), "outside")?;
+
+        let mut finder = new_finder_with_all_files_from(helper.precious_root(), AllFilesFrom::Vcs)?;
+        assert_eq!(finder.files(vec![])?, Some(helper.all_files()));
+        Ok(())
+    }
+
     #[test]
     #[parallel]
     fn all_mode_with_excluded_files() -> Result<()> {
@@ -678,6 +992,150 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[parallel]
+    fn git_staged_mode_from_index_copies_staged_content() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let modified = helper.modify_files()?;
+        helper.stage_all()?;
+        let unstaged = "tests/data/foo.txt";
+        helper.write_file(PathBuf::from(unstaged), "unstaged content")?;
+
+        let staged_root = {
+            let mut finder = new_finder(Mode::GitStagedFromIndex, helper.precious_root())?;
+            assert_eq!(finder.files(vec![])?, Some(modified));
+            assert!(!finder.stashed);
+
+            let staged_root = finder.staged_root().unwrap().to_path_buf();
+            assert_eq!(
+                String::from_utf8(fs::read(staged_root.join(unstaged))?)?,
+                String::from("new text"),
+            );
+            assert_eq!(
+                String::from_utf8(fs::read(helper.precious_root().join(unstaged))?)?,
+                String::from("unstaged content"),
+            );
+            staged_root
+        };
+        assert!(!staged_root.exists());
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_staged_mode_from_index_copies_only_staged_and_config_files() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let config = "src/sub/precious.toml";
+        helper.write_file(PathBuf::from(config), "")?;
+        helper.stage_all()?;
+        git_output(&helper, &["commit", "-m", "add a nested config"])?;
+        let modified = helper.modify_files()?;
+        helper.stage_all()?;
+
+        let mut finder = new_finder(Mode::GitStagedFromIndex, helper.precious_root())?;
+        assert_eq!(finder.files(vec![])?, Some(modified.clone()));
+        let staged_root = finder.staged_root().unwrap();
+        for m in modified {
+            assert!(staged_root.join(&m).exists(), "{} exists", m.display());
+        }
+        assert!(staged_root.join(config).exists(), "{config} exists");
+        assert!(
+            !staged_root.join("src/bar.rs").exists(),
+            "src/bar.rs does not exist",
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_staged_mode_from_index_when_repo_root_ne_precious_root() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?
+            .with_precious_root_in_subdir("subdir")
+            .with_git_repo()?;
+        let modified = helper.modify_files()?;
+        helper.stage_all()?;
+        let mut project_root = helper.git_root();
+        project_root.push("subdir");
+
+        let mut finder = new_finder(Mode::GitStagedFromIndex, project_root)?;
+        assert_eq!(finder.files(vec![])?, Some(modified.clone()));
+        let staged_root = finder.staged_root().unwrap();
+        assert!(staged_root.ends_with("subdir"));
+        for m in modified {
+            assert!(staged_root.join(&m).exists(), "{} exists", m.display());
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_staged_mode_with_stash_does_not_pop_existing_stash() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        helper.modify_files()?;
+        git_output(&helper, &["stash", "push", "--message", "user stash"])?;
+
+        {
+            let mut finder = new_finder(Mode::GitStagedWithStash, helper.precious_root())?;
+            assert_eq!(finder.files(vec![])?, None);
+            assert!(!finder.stashed);
+        }
+        assert_eq!(
+            git_output(&helper, &["stash", "list", "--format=%s"])?,
+            "On master: user stash\n",
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_staged_mode_with_stash_detects_orphaned_stash() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let modified = helper.modify_files()?;
+        helper.stage_all()?;
+        let unstaged = "tests/data/bar.txt";
+        helper.write_file(PathBuf::from(unstaged), "new content")?;
+
+        {
+            let mut finder = new_finder(Mode::GitStagedWithStash, helper.precious_root())?;
+            assert_eq!(finder.files(vec![])?, Some(modified));
+            assert!(finder.stashed);
+            // This simulates precious being killed before it can pop the
+            // stash.
+            std::mem::forget(finder);
+        }
+        assert_eq!(
+            String::from_utf8(fs::read(helper.precious_root().join(unstaged))?)?,
+            String::from("some text"),
+        );
+
+        let mut finder = new_finder(Mode::GitStagedWithStash, helper.precious_root())?;
+        let err = finder.files(vec![]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&FinderError::OrphanedStash {
+                mode: Mode::GitStagedWithStash,
+                stash: String::from("stash@{0}"),
+            }),
+        );
+
+        let mut git = vcs::git::Git::new(helper.precious_root());
+        assert_eq!(git.orphaned_stash()?, Some(String::from("stash@{0}")));
+        let err = git.stash_unstaged().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&vcs::git::GitError::OrphanedStashExists {
+                stash: String::from("stash@{0}"),
+            }),
+        );
+        git.unstash()?;
+        assert_eq!(git.orphaned_stash()?, None);
+        assert_eq!(
+            String::from_utf8(fs::read(helper.precious_root().join(unstaged))?)?,
+            String::from("new content"),
+        );
+        Ok(())
+    }
+
     // This tests the issue reported in
     // https://github.com/houseabsolute/precious/issues/9. I had tried to test
     // for this earlier, but I thought it was a non-issue because I couldn't
@@ -718,6 +1176,382 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[parallel]
+    fn hg_modified_mode_with_changes() -> Result<()> {
+        if which("hg").is_err() {
+            println!("Skipping test since hg is not in path");
+            return Ok(());
+        }
+
+        let helper = testhelper::TestHelper::new()?.with_hg_repo()?;
+        let modified = helper.modify_files()?;
+        let mut finder = new_finder(Mode::GitModified, helper.precious_root())?;
+        assert_eq!(finder.files(vec![])?, Some(modified));
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn hg_modified_mode_when_repo_root_ne_precious_root() -> Result<()> {
+        if which("hg").is_err() {
+            println!("Skipping test since hg is not in path");
+            return Ok(());
+        }
+
+        let helper = testhelper::TestHelper::new()?
+            .with_precious_root_in_subdir("subdir")
+            .with_hg_repo()?;
+        let modified = helper.modify_files()?;
+        let mut project_root = helper.git_root();
+        project_root.push("subdir");
+        let mut finder = new_finder(Mode::GitModified, project_root)?;
+        assert_eq!(finder.files(vec![])?, Some(modified));
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn hg_staged_mode_with_stash_includes_all_changes() -> Result<()> {
+        if which("hg").is_err() {
+            println!("Skipping test since hg is not in path");
+            return Ok(());
+        }
+
+        let helper = testhelper::TestHelper::new()?.with_hg_repo()?;
+        let modified = helper.modify_files()?;
+        let mut finder = new_finder(Mode::GitStagedWithStash, helper.precious_root())?;
+        assert_eq!(finder.files(vec![])?, Some(modified));
+        assert!(!finder.stashed);
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_mode_without_checkout() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?;
+        helper.write_file("foo.txt", "some text")?;
+        let mut finder = new_finder(Mode::GitModified, helper.precious_root())?;
+        let err = finder.files(vec![]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&FinderError::NoVcsCheckout {
+                mode: Mode::GitModified,
+                root: fs::canonicalize(helper.precious_root())?,
+            }),
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn all_mode_with_exclude_attributes() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        helper.write_file(
+            ".gitattributes",
+            "tests/data/foo.txt precious-skip\nsrc/*.rs linguist-generated\nsrc/main.rs linguist-generated=false\n",
+        )?;
+
+        let mut finder = Finder::new(
+            Mode::All,
+            helper.precious_root(),
+            helper.precious_root(),
+            vec![],
+            vec![String::from("precious-skip")],
+            Submodules::Include,
+            AllFilesFrom::Vcs,
+        )?;
+        let files = finder.files(vec![])?.unwrap();
+        assert!(!files.contains(&PathBuf::from("tests/data/foo.txt")));
+        assert!(files.contains(&PathBuf::from("tests/data/bar.txt")));
+
+        let attributes = finder.attributes(&files, &[String::from("linguist-generated")])?;
+        assert_eq!(
+            attributes.keys().sorted().collect::<Vec<_>>(),
+            vec![
+                &PathBuf::from("src/bar.rs"),
+                &PathBuf::from("src/can_ignore.rs"),
+                &PathBuf::from("src/module.rs"),
+            ],
+        );
+
+        Ok(())
+    }
+
+    fn new_finder_with_submodules(
+        mode: Mode,
+        root: PathBuf,
+        submodules: Submodules,
+    ) -> Result<Finder> {
+        Finder::new(
+            mode,
+            root.clone(),
+            root,
+            vec![],
+            vec![],
+            submodules,
+            AllFilesFrom::Vcs,
+        )
+    }
+
+    fn with_sub_files(mut files: Vec<PathBuf>, sub_files: &[&str]) -> Vec<PathBuf> {
+        files.extend(sub_files.iter().map(PathBuf::from));
+        files.sort();
+        files
+    }
+
+    #[test]
+    #[parallel]
+    fn git_modified_mode_with_submodule() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?
+            .with_git_repo()?
+            .with_git_submodule("vendor/lib")?;
+        let modified = helper.modify_files()?;
+        helper.write_file("vendor/lib/src/lib.rs", "fn bar() {}\n")?;
+
+        let mut finder = new_finder_with_submodules(
+            Mode::GitModified,
+            helper.precious_root(),
+            Submodules::Include,
+        )?;
+        assert_eq!(
+            finder.files(vec![])?,
+            Some(with_sub_files(modified.clone(), &["vendor/lib/src/lib.rs"])),
+        );
+
+        let mut finder = new_finder_with_submodules(
+            Mode::GitModified,
+            helper.precious_root(),
+            Submodules::Exclude,
+        )?;
+        assert_eq!(finder.files(vec![])?, Some(modified));
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_staged_mode_with_submodule() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?
+            .with_git_repo()?
+            .with_git_submodule("vendor/lib")?;
+        let modified = helper.modify_files()?;
+        helper.stage_all()?;
+        helper.write_file("vendor/lib/src/lib.rs", "fn staged() {}\n")?;
+        exec::run(
+            "git",
+            &["add", "src/lib.rs"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&helper.git_root().join("vendor/lib")),
+        )?;
+        helper.write_file("vendor/lib/src/lib.rs", "fn unstaged() {}\n")?;
+
+        let mut finder = new_finder_with_submodules(
+            Mode::GitStaged,
+            helper.precious_root(),
+            Submodules::Exclude,
+        )?;
+        assert_eq!(finder.files(vec![])?, Some(modified.clone()));
+
+        let mut finder = new_finder_with_submodules(
+            Mode::GitStagedFromIndex,
+            helper.precious_root(),
+            Submodules::Include,
+        )?;
+        assert_eq!(
+            finder.files(vec![])?,
+            Some(with_sub_files(modified, &["vendor/lib/src/lib.rs"])),
+        );
+        assert_eq!(
+            fs::read_to_string(finder.staged_root().unwrap().join("vendor/lib/src/lib.rs"))?,
+            "fn staged() {}\n",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_staged_mode_with_stash_and_unstaged_submodule_changes() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?
+            .with_git_repo()?
+            .with_git_submodule("vendor/lib")?;
+        let modified = helper.modify_files()?;
+        helper.stage_all()?;
+        helper.write_file("vendor/lib/src/lib.rs", "fn staged() {}\n")?;
+        exec::run(
+            "git",
+            &["add", "src/lib.rs"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&helper.git_root().join("vendor/lib")),
+        )?;
+
+        // With nothing unstaged in the submodule, its staged files are used.
+        {
+            let mut finder = new_finder_with_submodules(
+                Mode::GitStagedWithStash,
+                helper.precious_root(),
+                Submodules::Include,
+            )?;
+            assert_eq!(
+                finder.files(vec![])?,
+                Some(with_sub_files(modified.clone(), &["vendor/lib/src/lib.rs"])),
+            );
+        }
+
+        helper.write_file("vendor/lib/src/lib.rs", "fn unstaged() {}\n")?;
+        {
+            let mut finder = new_finder_with_submodules(
+                Mode::GitStagedWithStash,
+                helper.precious_root(),
+                Submodules::Include,
+            )?;
+            let err = finder.files(vec![]).unwrap_err();
+            assert_eq!(
+                err.downcast::<FinderError>()?,
+                FinderError::CannotStashInSubmodule {
+                    mode: Mode::GitStagedWithStash,
+                    submodule: PathBuf::from("vendor/lib"),
+                },
+            );
+        }
+        assert_eq!(
+            helper.read_file(Path::new("vendor/lib/src/lib.rs"))?,
+            "fn unstaged() {}\n",
+        );
+
+        let mut finder = new_finder_with_submodules(
+            Mode::GitStagedWithStash,
+            helper.precious_root(),
+            Submodules::Exclude,
+        )?;
+        assert_eq!(finder.files(vec![])?, Some(modified));
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn all_mode_with_submodule() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?
+            .with_git_repo()?
+            .with_git_submodule("vendor/lib")?;
+
+        let mut finder =
+            new_finder_with_submodules(Mode::All, helper.precious_root(), Submodules::Include)?;
+        assert_eq!(
+            finder.files(vec![])?,
+            Some(with_sub_files(
+                helper.all_files(),
+                &[
+                    ".gitmodules",
+                    "vendor/lib/README.md",
+                    "vendor/lib/src/lib.rs",
+                ],
+            )),
+        );
+
+        let mut finder =
+            new_finder_with_submodules(Mode::All, helper.precious_root(), Submodules::Exclude)?;
+        assert_eq!(
+            finder.files(vec![])?,
+            Some(with_sub_files(helper.all_files(), &[".gitmodules"])),
+        );
+
+        let mut finder =
+            new_finder_with_submodules(Mode::FromCli, helper.precious_root(), Submodules::Exclude)?;
+        assert_eq!(
+            finder.files(vec![PathBuf::from("src/main.rs"), PathBuf::from("vendor")])?,
+            Some(vec![PathBuf::from("src/main.rs")]),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn git_modes_in_linked_worktree() -> Result<()> {
+        let mut helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let worktree = helper.add_git_worktree("other")?;
+        assert!(worktree.join(".git").is_file());
+
+        fs::write(worktree.join("src/main.rs"), "fn staged() {}\n")?;
+        fs::write(worktree.join("README.md"), "modified")?;
+        let run_git =
+            |args: &[&str]| exec::run("git", args, &HashMap::new(), &[0], None, Some(&worktree));
+        run_git(&["add", "src/main.rs"])?;
+
+        let mut finder = new_finder(Mode::GitModified, worktree.clone())?;
+        assert_eq!(
+            finder.files(vec![])?,
+            Some(vec![
+                PathBuf::from("README.md"),
+                PathBuf::from("src/main.rs")
+            ]),
+        );
+
+        {
+            let mut finder = new_finder(Mode::GitStagedWithStash, worktree.clone())?;
+            assert_eq!(
+                finder.files(vec![])?,
+                Some(vec![PathBuf::from("src/main.rs")])
+            );
+            assert!(finder.stashed);
+            assert_eq!(fs::read_to_string(worktree.join("README.md"))?, "some text");
+        }
+        assert_eq!(fs::read_to_string(worktree.join("README.md"))?, "modified");
+
+        // The main checkout is unaffected by changes in the worktree.
+        let mut finder = new_finder(Mode::GitModified, helper.precious_root())?;
+        assert_eq!(finder.files(vec![])?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn commit_msg_mode() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let git_dir = helper.git_root().join(".git");
+        fs::write(git_dir.join("COMMIT_EDITMSG"), "A commit message\n")?;
+
+        let mut finder = new_finder(Mode::CommitMsg, helper.precious_root())?;
+        assert_eq!(
+            finder.files(vec![])?,
+            Some(vec![git_dir.join("COMMIT_EDITMSG")]),
+        );
+
+        // Git passes the hook a path relative to the checkout root. Exclusions
+        // are not applied to the commit message file.
+        let mut finder = new_finder_with_excludes(
+            Mode::CommitMsg,
+            helper.precious_root(),
+            helper.git_root(),
+            vec![String::from("*")],
+        )?;
+        assert_eq!(
+            finder.files(vec![PathBuf::from(".git/COMMIT_EDITMSG")])?,
+            Some(vec![git_dir.join("COMMIT_EDITMSG")]),
+        );
+
+        let mut finder = new_finder(Mode::CommitMsg, helper.precious_root())?;
+        let err = finder
+            .files(vec![PathBuf::from("no-such-file")])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&FinderError::CommitMsgFileDoesNotExist {
+                path: helper.precious_root().join("no-such-file"),
+            }),
+        );
+
+        Ok(())
+    }
+
     #[test]
     #[parallel]
     fn cli_mode() -> Result<()> {
@@ -768,6 +1602,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[parallel]
+    fn cli_mode_given_paths_with_parent_dir() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let cwd = helper.precious_root().join("src");
+        let mut finder = new_finder_with_cwd(Mode::FromCli, helper.precious_root(), cwd)?;
+        let expect = ["README.md", "tests/data/bar.txt", "tests/data/foo.txt"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(
+            finder.files(vec![
+                PathBuf::from("../README.md"),
+                PathBuf::from("sub/../../tests/data/bar.txt"),
+                PathBuf::from("../tests/./data/foo.txt"),
+            ])?,
+            Some(expect),
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    #[cfg(not(target_os = "windows"))]
+    fn cli_mode_with_cwd_through_symlink() -> Result<()> {
+        let helper = testhelper::TestHelper::new()?.with_git_repo()?;
+        let link_dir = tempfile::tempdir()?;
+        let link = link_dir.path().join("link");
+        std::os::unix::fs::symlink(helper.precious_root().join("src"), &link)?;
+
+        let mut finder = new_finder_with_cwd(Mode::FromCli, helper.precious_root(), link)?;
+        let expect = ["src/main.rs", "src/sub/mod.rs"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<PathBuf>>();
+        assert_eq!(
+            finder.files(vec![PathBuf::from("main.rs"), PathBuf::from("sub")])?,
+            Some(expect),
+        );
+        Ok(())
+    }
+
     #[test]
     #[parallel]
     fn cli_mode_given_dir_with_excluded_files() -> Result<()> {
@@ -1,45 +1,177 @@
 use anyhow::Result;
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use std::path::Path;
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    types::{Types, TypesBuilder},
+    Match,
+};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum MatcherError {
+    #[error("Unknown file type \"{name:}\". Run \"rg --type-list\" to see all known types.")]
+    UnknownFileType { name: String },
+}
 
 #[derive(Debug)]
 pub struct MatcherBuilder {
+    root: PathBuf,
     builder: GitignoreBuilder,
+    types: Option<Types>,
+    shebangs: Vec<String>,
 }
 
 #[allow(clippy::new_without_default)]
 impl MatcherBuilder {
     pub fn new<P: AsRef<Path>>(root: P) -> Self {
         Self {
+            root: root.as_ref().to_path_buf(),
             builder: GitignoreBuilder::new(root),
+            types: None,
+            shebangs: vec![],
         }
     }
 
     pub fn with(mut self, globs: &[impl AsRef<str>]) -> Result<Self> {
         for g in globs {
-            self.builder.add_line(None, g.as_ref())?;
+            let g = self.anchored(g.as_ref());
+            self.builder.add_line(None, &g)?;
         }
         Ok(self)
     }
 
+    // A glob that uses `$PRECIOUS_ROOT` starts with the absolute path of the
+    // root once it's interpolated. Globs are matched against paths relative
+    // to the root, so we turn that into a glob that is anchored at the root,
+    // like "/target".
+    fn anchored(&self, glob: &str) -> String {
+        let (negate, rest) = match glob.strip_prefix('!') {
+            Some(rest) => ("!", rest),
+            None => ("", glob),
+        };
+        let root = self.root.to_string_lossy();
+        match rest.strip_prefix(root.as_ref()) {
+            Some(r) if r.starts_with('/') => format!("{negate}{r}"),
+            _ => glob.to_string(),
+        }
+    }
+
+    // The names are file types from the `ignore` crate, which are the same
+    // as the types ripgrep supports, like "perl" or "sh".
+    pub fn with_types(mut self, names: &[impl AsRef<str>]) -> Result<Self> {
+        if names.is_empty() {
+            return Ok(self);
+        }
+
+        let mut builder = TypesBuilder::new();
+        builder.add_defaults();
+        for n in names {
+            let n = n.as_ref();
+            if !builder.definitions().iter().any(|d| d.name() == n) {
+                return Err(MatcherError::UnknownFileType {
+                    name: n.to_string(),
+                }
+                .into());
+            }
+            builder.select(n);
+        }
+        self.types = Some(builder.build()?);
+        Ok(self)
+    }
+
+    // The names are interpreters, like "perl" or "python3". These are
+    // matched against the name of the interpreter in a file's shebang line,
+    // with any directory removed. For "#!/usr/bin/env perl" the interpreter
+    // is "perl".
+    pub fn with_shebangs(mut self, names: &[impl AsRef<str>]) -> Self {
+        self.shebangs
+            .extend(names.iter().map(|n| n.as_ref().to_string()));
+        self
+    }
+
     pub fn build(self) -> Result<Matcher> {
         Ok(Matcher {
+            root: self.root,
             gitignore: self.builder.build()?,
+            types: self.types,
+            shebangs: self.shebangs,
         })
     }
 }
 
 #[derive(Debug)]
 pub struct Matcher {
+    root: PathBuf,
     gitignore: Gitignore,
+    types: Option<Types>,
+    shebangs: Vec<String>,
 }
 
+// Linux never reads more than this much of a shebang line.
+const SHEBANG_MAX_LEN: u64 = 256;
+
 impl Matcher {
+    // A path matches if it matches one of the globs, has one of the file
+    // types, or has one of the shebang interpreters. A path that is
+    // explicitly excluded by a glob starting with "!" never matches.
     pub fn path_matches(&self, path: &Path, is_dir: bool) -> bool {
-        self.gitignore.matched(path, is_dir).is_ignore()
+        match self.gitignore.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => (),
+        }
+        if is_dir {
+            return false;
+        }
+
+        if let Some(types) = &self.types {
+            if types.matched(path, false).is_whitelist() {
+                return true;
+            }
+        }
+
+        if !self.shebangs.is_empty() {
+            if let Some(interpreter) = shebang_interpreter(&self.root.join(path)) {
+                return self.shebangs.contains(&interpreter);
+            }
+        }
+
+        false
     }
 }
 
+fn shebang_interpreter(file: &Path) -> Option<String> {
+    let mut buf = vec![];
+    File::open(file)
+        .ok()?
+        .take(SHEBANG_MAX_LEN)
+        .read_to_end(&mut buf)
+        .ok()?;
+    let buf = buf.strip_prefix(b"#!")?;
+    let line = String::from_utf8_lossy(buf);
+    let mut words = line.lines().next()?.split_whitespace();
+
+    let basename = |w: &str| {
+        Path::new(w)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+    };
+    let interpreter = basename(words.next()?)?;
+    if interpreter != "env" {
+        return Some(interpreter);
+    }
+
+    // With env, the interpreter is the first word that isn't a flag, like
+    // "-S", or an environment variable, like "FOO=bar".
+    words
+        .find(|w| !(w.starts_with('-') || w.contains('=')))
+        .and_then(basename)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -105,4 +237,88 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[parallel]
+    fn path_matches_types_and_shebangs() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let files = [
+            ("bin/env-perl", "#!/usr/bin/env perl\nprint 42;\n"),
+            (
+                "bin/env-flags-python",
+                "#!/usr/bin/env -S FOO=1 python3 -u\n",
+            ),
+            ("bin/bash", "#!/bin/bash\necho 42\n"),
+            ("bin/no-shebang", "perl\n"),
+            ("bin/binary", "\0\0\0"),
+            ("lib/Foo.pm", "package Foo;\n"),
+            ("lib/foo.sh", "echo 42\n"),
+            ("lib/excluded.pm", "package Excluded;\n"),
+            ("README.md", "# Readme\n"),
+        ];
+        for (f, content) in files {
+            let path = td.path().join(f);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, content)?;
+        }
+
+        let m = MatcherBuilder::new(td.path())
+            .with(&["*.md", "!lib/excluded.pm"])?
+            .with_types(&["perl", "sh"])?
+            .with_shebangs(&["perl", "python3"])
+            .build()?;
+        let matched = files
+            .iter()
+            .map(|(f, _)| *f)
+            .filter(|f| m.path_matches(Path::new(f), false))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            matched,
+            vec![
+                "bin/env-perl",
+                "bin/env-flags-python",
+                "lib/Foo.pm",
+                "lib/foo.sh",
+                "README.md",
+            ],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn globs_under_the_root_are_anchored() -> Result<()> {
+        let m = MatcherBuilder::new("/project")
+            .with(&[
+                "/project/target/**/*",
+                "!/project/target/keep.rs",
+                "/elsewhere/*.rs",
+            ])?
+            .build()?;
+        for (path, expect) in [
+            ("target/debug/foo.rs", true),
+            ("target/keep.rs", false),
+            ("src/target/foo.rs", false),
+            ("elsewhere/foo.rs", true),
+        ] {
+            assert_eq!(m.path_matches(Path::new(path), false), expect, "{path}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn unknown_file_type() {
+        let err = MatcherBuilder::new("/")
+            .with_types(&["not-a-type"])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&MatcherError::UnknownFileType {
+                name: String::from("not-a-type"),
+            }),
+        );
+    }
 }
@@ -7,6 +7,8 @@ pub enum Mode {
     GitModified,
     GitStaged,
     GitStagedWithStash,
+    GitStagedFromIndex,
+    CommitMsg,
 }
 
 impl fmt::Display for Mode {
@@ -20,6 +22,26 @@ impl fmt::Display for Mode {
                 f,
                 "files staged for a git commit, stashing unstaged content"
             ),
+            Mode::GitStagedFromIndex => write!(
+                f,
+                "files staged for a git commit, using a copy of the staged content"
+            ),
+            Mode::CommitMsg => write!(f, "the commit message"),
+        }
+    }
+}
+
+impl Mode {
+    // This is the value of the `PRECIOUS_MODE` environment variable that
+    // commands are run with. All the ways of getting staged files are
+    // "staged", since a command sees the same files for each of them.
+    pub fn env_value(&self) -> &'static str {
+        match self {
+            Mode::FromCli => "cli",
+            Mode::All => "all",
+            Mode::GitModified => "git",
+            Mode::GitStaged | Mode::GitStagedWithStash | Mode::GitStagedFromIndex => "staged",
+            Mode::CommitMsg => "commit-msg",
         }
     }
 }
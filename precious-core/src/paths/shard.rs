@@ -0,0 +1,225 @@
+use clap::ValueEnum;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum ShardError {
+    #[error(r#"A shard must be given as "K/N", where 1 <= K <= N, but got "{shard:}""#)]
+    InvalidShard { shard: String },
+}
+
+// A shard is one of N disjoint subsets of the files found by the `Finder`.
+// Each CI worker can run with a different shard so that together they
+// operate on every file exactly once. The index is 1-based.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Shard {
+    index: usize,
+    count: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShardBy {
+    /// Pick each file's shard from a hash of its path. A file always lands in
+    /// the same shard no matter what other files exist.
+    Hash,
+    /// Spread files across shards so that each shard has about the same
+    /// number of bytes to process
+    Size,
+}
+
+impl FromStr for Shard {
+    type Err = ShardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ShardError::InvalidShard {
+            shard: s.to_string(),
+        };
+
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index = index.trim().parse::<usize>().map_err(|_| invalid())?;
+        let count = count.trim().parse::<usize>().map_err(|_| invalid())?;
+        if index == 0 || index > count {
+            return Err(invalid());
+        }
+
+        Ok(Shard { index, count })
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl Shard {
+    pub fn is_first(&self) -> bool {
+        self.index == 1
+    }
+
+    // Returns the files which belong to this shard. The files must be
+    // relative to the given root. Every worker must see the same list of
+    // files for the shards to be disjoint, which is true for `--all` run
+    // against the same commit.
+    pub fn select(&self, files: &[PathBuf], by: ShardBy, root: &Path) -> Vec<PathBuf> {
+        match by {
+            ShardBy::Hash => files
+                .iter()
+                .filter(|f| self.hash_index(f) == self.index - 1)
+                .cloned()
+                .collect(),
+            ShardBy::Size => self.select_by_size(files, root),
+        }
+    }
+
+    fn hash_index(&self, file: &Path) -> usize {
+        // We always hash with "/" as the separator so that a file lands in
+        // the same shard on every platform.
+        let digest = md5::compute(file.to_string_lossy().replace('\\', "/").as_bytes());
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        (u64::from_be_bytes(bytes) % self.count as u64) as usize
+    }
+
+    // This is the greedy "largest first" partition. Each file, starting with
+    // the largest, goes to the shard with the fewest bytes so far. Ties are
+    // broken by path and by shard index so that every worker computes the
+    // same partition.
+    fn select_by_size(&self, files: &[PathBuf], root: &Path) -> Vec<PathBuf> {
+        let mut sized = files
+            .iter()
+            .map(|f| {
+                let size = fs::metadata(root.join(f)).map(|m| m.len()).unwrap_or(0);
+                (size, f)
+            })
+            .collect::<Vec<_>>();
+        sized.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+
+        let mut totals = vec![0_u64; self.count];
+        let mut selected = vec![];
+        for (size, f) in sized {
+            let (i, _) = totals
+                .iter()
+                .enumerate()
+                .min_by_key(|(i, t)| (**t, *i))
+                .unwrap();
+            totals[i] += size;
+            if i == self.index - 1 {
+                selected.push(f.clone());
+            }
+        }
+        selected.sort();
+
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use serial_test::parallel;
+    use std::collections::HashSet;
+    use test_case::test_case;
+
+    #[test_case("1/1", 1, 1; "only shard")]
+    #[test_case("2/4", 2, 4; "middle shard")]
+    #[test_case("4/4", 4, 4; "last shard")]
+    #[test_case(" 3 / 5 ", 3, 5; "with whitespace")]
+    #[parallel]
+    fn parse_shard(s: &str, index: usize, count: usize) -> Result<()> {
+        assert_eq!(s.parse::<Shard>()?, Shard { index, count });
+        Ok(())
+    }
+
+    #[test_case("0/4"; "zero index")]
+    #[test_case("5/4"; "index greater than count")]
+    #[test_case("0/0"; "zero count")]
+    #[test_case("1"; "no slash")]
+    #[test_case("a/4"; "index is not a number")]
+    #[test_case("1/4/5"; "too many parts")]
+    #[test_case("-1/4"; "negative index")]
+    #[parallel]
+    fn parse_invalid_shard(s: &str) {
+        assert_eq!(
+            s.parse::<Shard>(),
+            Err(ShardError::InvalidShard {
+                shard: s.to_string(),
+            }),
+        );
+    }
+
+    fn assert_shards_partition(files: &[PathBuf], by: ShardBy, root: &Path) -> Result<()> {
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+        for i in 1..=3 {
+            let shard = format!("{i}/3").parse::<Shard>()?;
+            let selected = shard.select(files, by, root);
+            assert!(!selected.is_empty(), "shard {shard} has some files");
+            assert_eq!(selected, shard.select(files, by, root));
+            for f in selected {
+                assert!(seen.insert(f.clone()), "{} is in one shard", f.display());
+            }
+        }
+        assert_eq!(seen, files.iter().cloned().collect::<HashSet<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn select_by_hash() -> Result<()> {
+        let files = (0..100)
+            .map(|i| PathBuf::from(format!("src/file-{i}.rs")))
+            .collect::<Vec<_>>();
+        assert_shards_partition(&files, ShardBy::Hash, Path::new("."))?;
+
+        // Adding a file doesn't move any other file to a different shard.
+        let shard = "2/3".parse::<Shard>()?;
+        let before = shard.select(&files, ShardBy::Hash, Path::new("."));
+        let mut more_files = files.clone();
+        more_files.push(PathBuf::from("src/new.rs"));
+        let after = shard.select(&more_files, ShardBy::Hash, Path::new("."));
+        assert_eq!(
+            after
+                .into_iter()
+                .filter(|f| f != Path::new("src/new.rs"))
+                .collect::<Vec<_>>(),
+            before,
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[parallel]
+    fn select_by_size() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sizes = [900, 500, 400, 300, 300, 200, 100, 100, 100];
+        let mut files = vec![];
+        for (i, size) in sizes.iter().enumerate() {
+            let f = PathBuf::from(format!("file-{i}"));
+            fs::write(td.path().join(&f), "x".repeat(*size))?;
+            files.push(f);
+        }
+        assert_shards_partition(&files, ShardBy::Size, td.path())?;
+
+        let totals = (1..=3)
+            .map(|i| -> Result<u64> {
+                let shard = format!("{i}/3").parse::<Shard>()?;
+                Ok(shard
+                    .select(&files, ShardBy::Size, td.path())
+                    .iter()
+                    .map(|f| fs::metadata(td.path().join(f)).unwrap().len())
+                    .sum())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(totals, vec![1000, 1000, 900]);
+
+        Ok(())
+    }
+}
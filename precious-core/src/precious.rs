@@ -1,9 +1,17 @@
 use crate::{
     chars,
     command::{self, TidyOutcome},
-    config,
-    paths::{self, finder::Finder},
-    vcs,
+    config::{self, CONFIG_FILE_NAMES},
+    hooks::Hooks,
+    lock::Lock,
+    paths::{
+        self,
+        finder::Finder,
+        matcher::{Matcher, MatcherBuilder},
+        shard::{Shard, ShardBy},
+    },
+    report::{Outcome, Report},
+    vcs::{self, Vcs},
 };
 use anyhow::{Error, Result};
 use clap::{AppSettings, ArgGroup, Parser};
@@ -12,10 +20,14 @@ use fern::{
     Dispatch,
 };
 use itertools::Itertools;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use precious_helpers::exec;
 use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 use std::{
-    env,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    io::{self, IsTerminal, Write},
     path::{Path, PathBuf},
     time::{Duration, Instant},
 };
@@ -35,8 +47,26 @@ enum PreciousError {
     #[error("No {what:} commands defined in your config")]
     NoCommands { what: String },
 
-    #[error("No {what:} commands match the given command name, {name:}")]
-    NoCommandsMatch { what: String, name: String },
+    #[error("No {what:} commands match {selection:}")]
+    NoCommandsMatch { what: String, selection: String },
+
+    #[error("No command in your config matches {}", flags.join(", "))]
+    UnmatchedCommandSelection { flags: Vec<String> },
+
+    #[error("The --staged-from-index flag can only be used when linting")]
+    CannotTidyFromIndex,
+
+    #[error("The --commit-msg flag can only be used when linting")]
+    CannotTidyCommitMsg,
+
+    #[error("The {subcommand:} subcommand does not use a project, so it is run by App::run_without_project")]
+    SubcommandRunsWithoutProject { subcommand: String },
+
+    #[error("The --restage flag can only be used with --staged")]
+    RestageRequiresStaged,
+
+    #[error("Could not find a git or hg checkout containing {}, which is needed for --restage", root.display())]
+    CannotRestageWithoutVcs { root: PathBuf },
 }
 
 #[derive(Debug)]
@@ -56,6 +86,21 @@ impl From<Error> for Exit {
     }
 }
 
+// With `nested_configs`, the files are split up by the config file that
+// owns them. Each group's commands run with the group's directory as their
+// project root. The `dir` is relative to the top-level project root and is
+// empty for the top-level config.
+#[derive(Debug)]
+struct ConfigGroup {
+    dir: PathBuf,
+    config_file: PathBuf,
+    config: config::Config,
+    excluder: Matcher,
+    // These are relative to the top-level project root.
+    files: Vec<PathBuf>,
+    shard_files: Vec<PathBuf>,
+}
+
 #[derive(Debug)]
 struct ActionFailure {
     error: String,
@@ -63,7 +108,60 @@ struct ActionFailure {
     paths: Vec<PathBuf>,
 }
 
-const CONFIG_FILE_NAMES: &[&str] = &["precious.toml", ".precious.toml"];
+// This is used to implement `tidy --staged --restage`. It records which
+// files have unstaged changes before tidying so that we only re-stage files
+// which were fully staged. We can't rely on the `TidyOutcome` for this
+// because commands which are invoked per-dir or once only report an outcome
+// for a whole directory or the whole project, so instead we ask the VCS which
+// staged files have unstaged changes once tidying is done.
+struct Restager {
+    vcs: Box<dyn Vcs>,
+    unstaged_before: HashSet<PathBuf>,
+}
+
+impl Restager {
+    fn new(project_root: &Path) -> Result<Restager> {
+        let mut vcs =
+            vcs::find(project_root).ok_or_else(|| PreciousError::CannotRestageWithoutVcs {
+                root: project_root.to_path_buf(),
+            })?;
+        let unstaged_before = vcs.unstaged_files()?.into_iter().collect();
+        Ok(Restager {
+            vcs,
+            unstaged_before,
+        })
+    }
+
+    // Returns the paths that were re-staged, relative to the repo root.
+    fn restage(mut self) -> Result<Vec<PathBuf>> {
+        let unstaged_after = self
+            .vcs
+            .unstaged_files()?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let mut restage = vec![];
+        for f in self.vcs.staged_files()? {
+            if !unstaged_after.contains(&f) {
+                continue;
+            }
+            if self.unstaged_before.contains(&f) {
+                warn!(
+                    "Not re-staging {} because it was only partially staged before tidying",
+                    f.display(),
+                );
+                continue;
+            }
+            restage.push(f);
+        }
+
+        if !restage.is_empty() {
+            self.vcs.stage(&restage)?;
+        }
+
+        Ok(restage)
+    }
+}
 
 #[derive(Debug, Parser)]
 #[clap(name = "precious")]
@@ -76,9 +174,14 @@ pub struct App {
     /// Path to the precious config file
     #[clap(long, short)]
     config: Option<PathBuf>,
-    /// Number of parallel jobs (threads) to run (defaults to one per core)
+    /// Number of parallel jobs (threads) to run (defaults to the jobs config
+    /// key, or one per core if that isn't set)
     #[clap(long, short, default_value_t = 0)]
     jobs: usize,
+    /// The profile from the config file's profiles table to use. This can
+    /// also be set with the PRECIOUS_PROFILE environment variable.
+    #[clap(long, value_name = "NAME")]
+    profile: Option<String>,
     /// Replace super-fun Unicode symbols with terribly boring ASCII
     #[clap(long, short)]
     ascii: bool,
@@ -102,20 +205,67 @@ pub struct App {
 #[derive(Debug, Parser)]
 pub enum Subcommand {
     Lint(CommonArgs),
-    Tidy(CommonArgs),
+    Tidy(TidyArgs),
+    /// Manage git hooks that run precious
+    #[clap(subcommand)]
+    Hooks(HooksCommand),
+    /// Work with the precious config file
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Clone, Copy, Debug, Parser)]
+pub enum HooksCommand {
+    /// Install the hooks listed in the [hooks] table of your config file. An
+    /// existing hook that was not installed by precious is run before
+    /// precious.
+    Install,
+    /// Remove any hooks installed by precious, restoring the hooks they
+    /// replaced
+    Uninstall,
+    /// Show which hooks are installed
+    Status,
+}
+
+#[derive(Clone, Copy, Debug, Parser)]
+pub enum ConfigCommand {
+    /// Rewrite commands that use the deprecated run_mode and chdir keys to
+    /// use invoke, working_dir, and path_args instead. This prints a diff of
+    /// the changes before writing them. Only the project's config file is
+    /// changed, not any files it extends or precious.local.toml.
+    Migrate(MigrateArgs),
+    /// Print a JSON Schema for the config file, for editors that can validate
+    /// TOML files against one
+    Schema,
+}
+
+#[derive(Clone, Copy, Debug, Parser)]
+pub struct MigrateArgs {
+    /// Print the changes without writing them to the config file
+    #[clap(long)]
+    dry_run: bool,
 }
 
 #[derive(Debug, Parser)]
 #[clap(group(
     ArgGroup::new("path-spec")
         .required(true)
-        .args(&["all", "git", "staged", "staged-with-stash", "paths"]),
+        .args(&["all", "git", "staged", "staged-with-stash", "staged-from-index", "commit-msg", "paths"]),
 ))]
 pub struct CommonArgs {
-    /// The command to run. If specified, only this command will be run. This
-    /// should match the command name in your config file.
-    #[clap(long)]
-    command: Option<String>,
+    /// A command to run. If this is given, only the commands given with this
+    /// flag or selected with --label will be run. This should match the
+    /// command name in your config file. This can be given more than once.
+    #[clap(long, value_name = "NAME")]
+    command: Vec<String>,
+    /// Run the commands with this label. This can be given more than once.
+    #[clap(long, value_name = "LABEL")]
+    label: Vec<String>,
+    /// Do not run this command. This can be given more than once. The
+    /// PRECIOUS_SKIP environment variable can also be set to a
+    /// comma-separated list of commands to skip.
+    #[clap(long, value_name = "NAME")]
+    skip_command: Vec<String>,
     /// Run against all files in the current directory and below
     #[clap(long, short)]
     all: bool,
@@ -129,30 +279,117 @@ pub struct CommonArgs {
     /// unstaged content first
     #[clap(long)]
     staged_with_stash: bool,
+    /// Run against file content that is staged for a git commit, using a
+    /// temporary copy of the staged content. This never changes your working
+    /// tree, but it can only be used when linting.
+    #[clap(long)]
+    staged_from_index: bool,
+    /// Run commit-msg commands against a commit message file. If no file is
+    /// given, this uses the COMMIT_EDITMSG file in the .git directory. This
+    /// can only be used when linting.
+    #[clap(long, value_name = "FILE")]
+    commit_msg: Option<Option<PathBuf>>,
+    /// If another precious process that may change the working tree is
+    /// running in this repo, wait for it to finish instead of exiting with an
+    /// error
+    #[clap(long)]
+    wait: bool,
+    /// Only operate on one shard of the files that would otherwise be
+    /// selected, where K is a number from 1 to N. Commands with invoke =
+    /// "once" only run for the first shard unless they set once_per_shard.
+    #[clap(long, value_name = "K/N", conflicts_with = "commit-msg")]
+    shard: Option<Shard>,
+    /// How to split files into shards
+    #[clap(long, value_enum, default_value_t = ShardBy::Hash, requires = "shard")]
+    shard_by: ShardBy,
+    /// Write a report of every command run and its outcome to this file as
+    /// JSON Lines. The reports from each shard of a sharded run can be merged
+    /// by concatenating them.
+    #[clap(long, value_name = "FILE")]
+    report: Option<PathBuf>,
     /// A list of paths on which to operate
     #[clap(value_parser)]
     paths: Vec<PathBuf>,
 }
 
+#[derive(Debug, Parser)]
+pub struct TidyArgs {
+    #[clap(flatten)]
+    common: CommonArgs,
+    /// After tidying, stage any changes made to files that were fully staged
+    /// before tidying. Files which also had unstaged changes are never
+    /// re-staged.
+    #[clap(long)]
+    restage: bool,
+}
+
 pub fn app() -> App {
     App::parse()
 }
 
+// When precious receives SIGINT or SIGTERM, we pass the signal on to any
+// commands that are running and then skip the rest. This lets us get back to
+// the main thread normally, so that stashed changes are always restored.
+pub fn trap_signals() -> Result<()> {
+    ctrlc::set_handler(exec::terminate_children)?;
+    Ok(())
+}
+
+type IntoCommands =
+    fn(config::Config, &Path, &config::CommandSelection) -> Result<Vec<command::Command>>;
+
+#[derive(Debug)]
+enum Action {
+    Lint,
+    Tidy,
+    Hooks(HooksCommand),
+    Migrate(MigrateArgs),
+}
+
 #[derive(Debug)]
 pub struct Precious {
-    mode: paths::mode::Mode,
+    action: Action,
+    // This is only `None` for subcommands that don't operate on files.
+    mode: Option<paths::mode::Mode>,
     project_root: PathBuf,
     cwd: PathBuf,
+    config_file: PathBuf,
     config: config::Config,
-    command: Option<String>,
+    profile: Option<String>,
+    selection: config::CommandSelection,
     chars: chars::Chars,
     quiet: bool,
     thread_pool: ThreadPool,
-    should_lint: bool,
+    restage: bool,
+    wait: bool,
+    shard: Option<Shard>,
+    shard_by: ShardBy,
+    report: Option<Report>,
+    // This is the directory of the config whose commands are being run,
+    // relative to the project root. It's only non-empty when running
+    // commands from a nested config.
+    config_dir: PathBuf,
     paths: Vec<PathBuf>,
 }
 
 impl App {
+    // Some subcommands don't need a project or a config file, so they run
+    // without creating a `Precious`. This returns the exit status if the
+    // subcommand was one of those.
+    pub fn run_without_project(&self) -> Option<i8> {
+        match self.subcommand {
+            Subcommand::Config(ConfigCommand::Schema) => {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&config::schema::schema())
+                        .expect("the config schema can always be serialized"),
+                );
+                Some(0)
+            }
+            _ => None,
+        }
+    }
+
     pub fn init_logger(&self) -> Result<(), log::SetLoggerError> {
         let line_colors = ColoredLevelConfig::new()
             .error(Color::Red)
@@ -210,47 +447,147 @@ impl Precious {
         let mode = Self::mode(&app)?;
         let cwd = env::current_dir()?;
         let project_root = Self::project_root(app.config.as_ref(), &cwd)?;
-        let config_file = Self::config_file(app.config.as_ref(), &project_root);
-        let config = config::Config::new(config_file)?;
+        let config_file = Self::config_file(app.config.as_ref(), &project_root)?;
+        let profile = app
+            .profile
+            .clone()
+            .or_else(|| env::var("PRECIOUS_PROFILE").ok())
+            .filter(|p| !p.is_empty());
+        let config = config::Config::with_layers(config_file.clone(), profile.as_deref())?;
         let quiet = app.quiet;
-        let jobs = app.jobs;
-        let (should_lint, paths, command) = match app.subcommand {
-            Subcommand::Lint(a) => (true, a.paths, a.command),
-            Subcommand::Tidy(a) => (false, a.paths, a.command),
+        let jobs = match app.jobs {
+            0 => config.jobs.unwrap_or(0),
+            j => j,
+        };
+        let (action, restage, common) = match app.subcommand {
+            Subcommand::Lint(mut a) => {
+                // For --commit-msg, the paths are the commit message file, if
+                // one was given.
+                if let Some(f) = a.commit_msg.take() {
+                    a.paths = f.into_iter().collect();
+                }
+                (Action::Lint, false, Some(a))
+            }
+            Subcommand::Tidy(a) => (Action::Tidy, a.restage, Some(a.common)),
+            Subcommand::Hooks(h) => (Action::Hooks(h), false, None),
+            Subcommand::Config(ConfigCommand::Migrate(a)) => (Action::Migrate(a), false, None),
+            Subcommand::Config(ConfigCommand::Schema) => {
+                return Err(PreciousError::SubcommandRunsWithoutProject {
+                    subcommand: "config schema".to_string(),
+                }
+                .into());
+            }
+        };
+        // Only runs that use the commands warn about deprecated keys. It
+        // would be silly to tell someone to run `precious config migrate`
+        // while they're running it.
+        if matches!(action, Action::Lint | Action::Tidy) {
+            config.warn_about_deprecated_keys();
+        }
+        let (wait, paths, selection, shard, shard_by, report) = match common {
+            Some(c) => {
+                let report = c.report.map(|f| {
+                    let what = if let Action::Lint = action {
+                        "lint"
+                    } else {
+                        "tidy"
+                    };
+                    Report::new(cwd.join(f), c.shard, what)
+                });
+                let selection = Self::selection(c.command, c.label, c.skip_command);
+                (c.wait, c.paths, selection, c.shard, c.shard_by, report)
+            }
+            None => (
+                false,
+                vec![],
+                config::CommandSelection::default(),
+                None,
+                ShardBy::Hash,
+                None,
+            ),
         };
 
         Ok(Precious {
+            action,
             mode,
             project_root,
             cwd,
+            config_file,
             config,
-            command,
+            profile,
+            selection,
             chars: c,
             quiet,
             thread_pool: ThreadPoolBuilder::new().num_threads(jobs).build()?,
-            should_lint,
+            restage,
+            wait,
+            shard,
+            shard_by,
+            report,
+            config_dir: PathBuf::new(),
             paths,
         })
     }
 
-    fn mode(app: &App) -> Result<paths::mode::Mode> {
+    // Commands named in the PRECIOUS_SKIP env var are skipped along with any
+    // given with --skip-command. This makes it easy to skip a slow command
+    // for one run of a hook, like `PRECIOUS_SKIP=clippy git commit`.
+    fn selection(
+        names: Vec<String>,
+        labels: Vec<String>,
+        mut skip: Vec<String>,
+    ) -> config::CommandSelection {
+        if let Some(s) = env::var_os("PRECIOUS_SKIP") {
+            skip.extend(
+                s.to_string_lossy()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|n| !n.is_empty())
+                    .map(String::from),
+            );
+        }
+        config::CommandSelection {
+            names,
+            labels,
+            skip,
+        }
+    }
+
+    fn mode(app: &App) -> Result<Option<paths::mode::Mode>> {
         let common = match &app.subcommand {
             Subcommand::Lint(c) => c,
-            Subcommand::Tidy(c) => c,
+            Subcommand::Tidy(t) => &t.common,
+            Subcommand::Hooks(_) | Subcommand::Config(_) => return Ok(None),
         };
+        if let Subcommand::Tidy(t) = &app.subcommand {
+            if t.restage && !common.staged {
+                return Err(PreciousError::RestageRequiresStaged.into());
+            }
+        }
+
         if common.all {
-            return Ok(paths::mode::Mode::All);
+            return Ok(Some(paths::mode::Mode::All));
         } else if common.git {
-            return Ok(paths::mode::Mode::GitModified);
+            return Ok(Some(paths::mode::Mode::GitModified));
         } else if common.staged {
-            return Ok(paths::mode::Mode::GitStaged);
+            return Ok(Some(paths::mode::Mode::GitStaged));
         } else if common.staged_with_stash {
-            return Ok(paths::mode::Mode::GitStagedWithStash);
+            return Ok(Some(paths::mode::Mode::GitStagedWithStash));
+        } else if common.staged_from_index {
+            if let Subcommand::Tidy(_) = app.subcommand {
+                return Err(PreciousError::CannotTidyFromIndex.into());
+            }
+            return Ok(Some(paths::mode::Mode::GitStagedFromIndex));
+        } else if common.commit_msg.is_some() {
+            if let Subcommand::Tidy(_) = app.subcommand {
+                return Err(PreciousError::CannotTidyCommitMsg.into());
+            }
+            return Ok(Some(paths::mode::Mode::CommitMsg));
         }
         if common.paths.is_empty() {
             return Err(PreciousError::NoModeOrPathsInCliArgs.into());
         }
-        Ok(paths::mode::Mode::FromCli)
+        Ok(Some(paths::mode::Mode::FromCli))
     }
 
     fn project_root(file: Option<&PathBuf>, cwd: &Path) -> Result<PathBuf> {
@@ -261,7 +598,7 @@ impl Precious {
             return Err(PreciousError::ConfigFileHasNoParent { file: file.clone() }.into());
         }
 
-        if Self::has_config_file(cwd) {
+        if config::find_config_file(cwd)?.is_some() {
             return Ok(cwd.into());
         }
 
@@ -277,59 +614,25 @@ impl Precious {
         .into())
     }
 
-    fn has_config_file(dir: &Path) -> bool {
-        Self::default_config_file(dir).exists()
-    }
-
-    fn default_config_file(dir: &Path) -> PathBuf {
-        // It'd be nicer to use the version of this provided by itertools, but
-        // that requires itertools 0.10.1, and we want to keep the version at
-        // 0.9.0 for the benefit of Debian.
-        Self::find_or_first(
-            CONFIG_FILE_NAMES.iter().map(|n| {
-                let mut path = dir.to_path_buf();
-                path.push(n);
-                path
-            }),
-            |p| p.exists(),
-        )
-    }
-
-    fn find_or_first<I, P>(mut iter: I, pred: P) -> PathBuf
-    where
-        I: Iterator<Item = PathBuf>,
-        P: Fn(&Path) -> bool,
-    {
-        let first = iter.next().unwrap();
-        if pred(&first) {
-            return first;
-        }
-        iter.find(|i| pred(i)).unwrap_or(first)
-    }
-
-    fn config_file(file: Option<&PathBuf>, dir: &Path) -> PathBuf {
+    fn config_file(file: Option<&PathBuf>, dir: &Path) -> Result<PathBuf> {
         if let Some(cf) = file {
             debug!("Loading config from {} (set via flag)", cf.display());
-            return cf.to_path_buf();
+            return Ok(cf.to_path_buf());
         }
 
-        let default = Self::default_config_file(dir);
+        // If there's no config file we use the default name anyway, so that
+        // the error from loading it says where we looked.
+        let default =
+            config::find_config_file(dir)?.unwrap_or_else(|| dir.join(CONFIG_FILE_NAMES[0]));
         debug!(
             "Loading config from {} (default location)",
             default.display()
         );
-        default
+        Ok(default)
     }
 
     fn is_checkout_root(dir: &Path) -> bool {
-        for subdir in vcs::DIRS {
-            let mut poss = PathBuf::from(dir);
-            poss.push(subdir);
-            if poss.exists() {
-                return true;
-            }
-        }
-        false
+        vcs::checkout_dir(dir).is_some()
     }
 
     pub fn run(&mut self) -> i8 {
@@ -342,108 +645,500 @@ impl Precious {
                 if let Some(msg) = e.message {
                     println!("{} {}", self.chars.empty, msg);
                 }
+                if exec::is_terminating() {
+                    error!("Precious was interrupted, so some commands were not run");
+                    return 1;
+                }
                 e.status
             }
             Err(e) => {
                 error!("Failed to run precious: {}", e);
+                if let Some(r) = &self.report {
+                    if let Err(e) = r.write_error(e.to_string()) {
+                        error!("Failed to write the report: {}", e);
+                    }
+                }
                 1
             }
         }
     }
 
     fn run_subcommand(&mut self) -> Result<Exit> {
-        if self.should_lint {
-            self.lint()
+        match self.action {
+            Action::Lint => self.run_with_lock(Self::lint),
+            Action::Tidy => self.run_with_lock(Self::tidy),
+            Action::Hooks(h) => self.hooks(h),
+            Action::Migrate(a) => self.migrate_config(a.dry_run),
+        }
+    }
+
+    fn run_with_lock(&mut self, run: fn(&mut Self) -> Result<Exit>) -> Result<Exit> {
+        // This must live until the subcommand is done, including restaging
+        // and popping any stash.
+        let _lock = if self.changes_tree() {
+            Some(Lock::acquire(&self.project_root, self.wait)?)
         } else {
-            self.tidy()
+            None
+        };
+        // Only runs that stash can leave a stash behind, and they always hold
+        // the lock, so no other precious can be using the stash we find.
+        if self.mode == Some(paths::mode::Mode::GitStagedWithStash) {
+            self.maybe_restore_orphaned_stash()?;
+        }
+
+        run(self)
+    }
+
+    fn changes_tree(&self) -> bool {
+        matches!(self.action, Action::Tidy)
+            || self.mode == Some(paths::mode::Mode::GitStagedWithStash)
+    }
+
+    // Lint and tidy always have a mode, so this is safe to call from any code
+    // that runs for those subcommands.
+    fn path_mode(&self) -> paths::mode::Mode {
+        self.mode.expect("lint and tidy always have a mode")
+    }
+
+    fn hooks(&mut self, command: HooksCommand) -> Result<Exit> {
+        let hooks = Hooks::new(
+            &self.project_root,
+            &self.config_file,
+            self.config.hooks.clone(),
+            self.config.has_commit_msg_commands(),
+        )?;
+        let (verb, results) = match command {
+            HooksCommand::Install => ("Installed", hooks.install()?),
+            HooksCommand::Uninstall => ("Uninstalled", hooks.uninstall()?),
+            HooksCommand::Status => ("Status of", hooks.status()),
+        };
+
+        println!(
+            "{} {verb} hooks in {}",
+            self.chars.ring,
+            hooks.hooks_dir().display(),
+        );
+        for (name, status) in &results {
+            println!("{} {name}: {status}", self.chars.bullet);
         }
+        if results.is_empty() {
+            println!("{} No hooks to change", self.chars.empty);
+        }
+
+        Ok(Exit {
+            status: 0,
+            message: None,
+            error: None,
+        })
+    }
+
+    fn migrate_config(&self, dry_run: bool) -> Result<Exit> {
+        let file = self.config_file.display();
+        let message = match config::migrate::migrate(&self.config_file)? {
+            None => format!("No deprecated config keys found in {file}"),
+            Some(migration) => {
+                print!("{}", migration.diff(&self.config_file));
+                if dry_run {
+                    format!("Run without --dry-run to write these changes to {file}")
+                } else {
+                    fs::write(&self.config_file, &migration.new)?;
+                    format!("Migrated the config in {file}")
+                }
+            }
+        };
+
+        Ok(Exit {
+            status: 0,
+            message: Some(message),
+            error: None,
+        })
     }
 
     fn tidy(&mut self) -> Result<Exit> {
-        println!("{} Tidying {}", self.chars.ring, self.mode);
+        println!("{} Tidying {}", self.chars.ring, self.path_mode());
 
-        let tidiers = self
-            .config
-            // XXX - This clone can be removed if config is passed into this
-            // method instead of being a field of self.
-            .clone()
-            .into_tidy_commands(&self.project_root, self.command.as_deref())?;
-        self.run_all_commands(
+        let restager = if self.restage {
+            Some(Restager::new(&self.project_root)?)
+        } else {
+            None
+        };
+
+        let exit = self.run_all_commands(
             "tidying",
-            tidiers,
+            config::Config::into_tidy_commands,
             |self_: &mut Self, files: &[PathBuf], tidier: &command::Command| {
                 self_.run_one_tidier(files, tidier)
             },
-        )
+        )?;
+
+        if let Some(r) = restager {
+            let restaged = r.restage()?;
+            if !self.quiet && !restaged.is_empty() {
+                println!(
+                    "{} Re-staged: [{}]",
+                    self.chars.tidied,
+                    restaged.iter().map(|p| p.to_string_lossy()).join(" "),
+                );
+            }
+        }
+
+        Ok(exit)
     }
 
     fn lint(&mut self) -> Result<Exit> {
-        println!("{} Linting {}", self.chars.ring, self.mode);
-
-        let linters = self
-            .config
-            // XXX - same as above.
-            .clone()
-            .into_lint_commands(&self.project_root, self.command.as_deref())?;
+        println!("{} Linting {}", self.chars.ring, self.path_mode());
+
+        let (action, into_commands): (_, IntoCommands) =
+            if self.path_mode() == paths::mode::Mode::CommitMsg {
+                (
+                    "commit message linting",
+                    config::Config::into_commit_msg_commands,
+                )
+            } else {
+                ("linting", config::Config::into_lint_commands)
+            };
         self.run_all_commands(
-            "linting",
-            linters,
+            action,
+            into_commands,
             |self_: &mut Self, files: &[PathBuf], linter: &command::Command| {
                 self_.run_one_linter(files, linter)
             },
         )
     }
 
-    fn run_all_commands<R>(
+    fn run_all_commands<C, R>(
         &mut self,
         action: &str,
-        commands: Vec<command::Command>,
+        into_commands: C,
         run_command: R,
     ) -> Result<Exit>
     where
+        C: Fn(config::Config, &Path, &config::CommandSelection) -> Result<Vec<command::Command>>,
         R: Fn(&mut Self, &[PathBuf], &command::Command) -> Result<Option<Vec<ActionFailure>>>,
     {
-        if commands.is_empty() {
-            if let Some(c) = &self.command {
-                return Err(PreciousError::NoCommandsMatch {
-                    what: action.into(),
-                    name: c.into(),
+        // With nested configs, a command may be defined in a nested config
+        // file, so we check those once they're loaded below.
+        if !self.config.nested_configs {
+            self.check_selection(&[&self.config])?;
+        }
+
+        // XXX - This clone can be removed if config is passed into this
+        // method instead of being a field of self.
+        let commands = into_commands(self.config.clone(), &self.project_root, &self.selection)?;
+        // With nested configs, the commands may all be defined in the nested
+        // config files, which we can't look for until we have the files.
+        if commands.is_empty() && !self.config.nested_configs {
+            return Err(self.no_commands_error(action));
+        }
+
+        let cli_paths = match self.path_mode() {
+            paths::mode::Mode::FromCli | paths::mode::Mode::CommitMsg => self.paths.clone(),
+            _ => vec![],
+        };
+
+        // The finder needs to live until all the commands have run, since
+        // dropping it may pop a stash or delete a copy of the staged files.
+        let mut finder = self.finder()?;
+        match finder.files(cli_paths)? {
+            None => {
+                let exit = self.no_files_exit();
+                self.write_report(vec![], &exit)?;
+                Ok(exit)
+            }
+            Some(files) => {
+                // If the finder made a copy of the staged content, the
+                // commands need to run against that copy instead.
+                let root = match finder.staged_root() {
+                    Some(root) => {
+                        debug!("Running commands against {}", root.display());
+                        root.to_path_buf()
+                    }
+                    None => self.project_root.clone(),
+                };
+
+                let shard_files = self.shard_files(&files);
+                let groups = self.config_groups(&root, &files, &shard_files)?;
+                if self.config.nested_configs {
+                    self.check_selection(&groups.iter().map(|g| &g.config).collect::<Vec<_>>())?;
                 }
-                .into());
+                let show_groups = groups.len() > 1;
+
+                let mut all_failures: Vec<ActionFailure> = vec![];
+                let mut have_commands = !commands.is_empty();
+                for group in groups {
+                    // Joining an empty path would add a trailing slash.
+                    let group_root = if group.dir.as_os_str().is_empty() {
+                        root.clone()
+                    } else {
+                        root.join(&group.dir)
+                    };
+                    let commands = into_commands(group.config, &group_root, &self.selection)?;
+                    have_commands |= !commands.is_empty();
+                    if commands.is_empty() || group.files.is_empty() {
+                        continue;
+                    }
+
+                    if show_groups && !self.quiet {
+                        println!(
+                            "{} Running commands from {}",
+                            self.chars.ring,
+                            relative_to(&group.config_file, &self.project_root).display(),
+                        );
+                    }
+
+                    // Attributes don't apply to the commit message file.
+                    let names = if self.path_mode() == paths::mode::Mode::CommitMsg {
+                        vec![]
+                    } else {
+                        commands
+                            .iter()
+                            .flat_map(|c| c.exclude_attributes.iter().cloned())
+                            .unique()
+                            .collect::<Vec<_>>()
+                    };
+                    let attributes = finder
+                        .attributes(&group.files, &names)?
+                        .into_iter()
+                        .map(|(f, attrs)| (relative_to(&f, &group.dir), attrs))
+                        .collect::<HashMap<_, _>>();
+                    let group_files = group
+                        .files
+                        .iter()
+                        .map(|f| relative_to(f, &group.dir))
+                        .collect::<Vec<_>>();
+                    let group_shard_files = group
+                        .shard_files
+                        .iter()
+                        .map(|f| relative_to(f, &group.dir))
+                        .collect::<Vec<_>>();
+
+                    self.config_dir = group.dir;
+                    for c in commands {
+                        debug!(r#"Command config for {}: {}"#, c.name, c.config_debug(),);
+                        // A command which is invoked once can't be split up,
+                        // so by default it runs on the first shard with every
+                        // file.
+                        let files = match self.shard {
+                            Some(s) if c.is_invoked_once() && !c.once_per_shard => {
+                                if !s.is_first() {
+                                    info!(
+                                        "Skipping {} on shard {s} because it is invoked once and once_per_shard is not set",
+                                        c.name,
+                                    );
+                                    continue;
+                                }
+                                &group_files
+                            }
+                            _ => &group_shard_files,
+                        };
+                        let command_files = files_for_command(files, &attributes, &c);
+                        if let Some(failures) = run_command(self, &command_files, &c)? {
+                            all_failures.extend(failures.into_iter().map(|mut f| {
+                                f.paths = f.paths.iter().map(|p| self.config_dir.join(p)).collect();
+                                f
+                            }));
+                        }
+                    }
+                    self.config_dir = PathBuf::new();
+                }
+                if !have_commands {
+                    return Err(self.no_commands_error(action));
+                }
+
+                let exit = self.make_exit(all_failures, action);
+                self.write_report(shard_files.into_owned(), &exit)?;
+                Ok(exit)
             }
-            return Err(PreciousError::NoCommands {
+        }
+    }
+
+    // A `--command` or `--label` that matches nothing is almost always a
+    // typo, so we don't want to silently run the rest of the selection
+    // without it.
+    fn check_selection(&self, configs: &[&config::Config]) -> Result<()> {
+        let flags = self.selection.unmatched(configs);
+        if flags.is_empty() {
+            return Ok(());
+        }
+        Err(PreciousError::UnmatchedCommandSelection { flags }.into())
+    }
+
+    fn no_commands_error(&self, action: &str) -> Error {
+        if !self.selection.is_everything() {
+            return PreciousError::NoCommandsMatch {
                 what: action.into(),
+                selection: self.selection.to_string(),
             }
-            .into());
+            .into();
         }
+        PreciousError::NoCommands {
+            what: action.into(),
+        }
+        .into()
+    }
 
-        let cli_paths = match self.mode {
-            paths::mode::Mode::FromCli => self.paths.clone(),
-            _ => vec![],
+    // Splits the files up by the config file which owns them. Without
+    // `nested_configs` there is just one group, for the top-level config.
+    // Otherwise each file belongs to the config file in the closest directory
+    // above it. Each nested config's top-level `exclude` is applied to its
+    // files here, since the finder only knows about the top-level config.
+    //
+    // Nested configs are looked for under the root the commands run in, so
+    // with `--staged-from-index` we use their staged versions.
+    fn config_groups(
+        &self,
+        root: &Path,
+        files: &[PathBuf],
+        shard_files: &[PathBuf],
+    ) -> Result<Vec<ConfigGroup>> {
+        let top = ConfigGroup {
+            dir: PathBuf::new(),
+            config_file: self.config_file.clone(),
+            config: self.config.clone(),
+            excluder: MatcherBuilder::new(&self.project_root).build()?,
+            files: vec![],
+            shard_files: vec![],
         };
+        if !self.config.nested_configs || self.path_mode() == paths::mode::Mode::CommitMsg {
+            return Ok(vec![ConfigGroup {
+                files: files.to_vec(),
+                shard_files: shard_files.to_vec(),
+                ..top
+            }]);
+        }
 
-        match self.finder()?.files(cli_paths)? {
-            None => Ok(self.no_files_exit()),
-            Some(files) => {
-                let mut all_failures: Vec<ActionFailure> = vec![];
-                for c in commands {
-                    debug!(r#"Command config for {}: {}"#, c.name, c.config_debug(),);
-                    if let Some(mut failures) = run_command(self, &files, &c)? {
-                        all_failures.append(&mut failures);
+        let mut config_files: HashMap<PathBuf, Option<PathBuf>> = HashMap::new();
+        let mut groups = BTreeMap::from([(PathBuf::new(), top)]);
+        let mut owners: HashMap<&Path, PathBuf> = HashMap::new();
+        for f in files {
+            let mut owner = None;
+            for dir in f.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() {
+                    break;
+                }
+                if !config_files.contains_key(dir) {
+                    let config_file = config::find_config_file(&root.join(dir))?;
+                    config_files.insert(dir.to_path_buf(), config_file);
+                }
+                if let Some(config_file) = &config_files[dir] {
+                    owner = Some((dir.to_path_buf(), config_file.clone()));
+                    break;
+                }
+            }
+
+            let dir = match owner {
+                Some((dir, config_file)) => {
+                    if !groups.contains_key(&dir) {
+                        groups.insert(
+                            dir.clone(),
+                            self.nested_config_group(root, &dir, config_file)?,
+                        );
                     }
+                    dir
                 }
+                None => PathBuf::new(),
+            };
+
+            if groups[&dir]
+                .excluder
+                .path_matches(&relative_to(f, &dir), false)
+            {
+                debug!(
+                    "Excluding {} because of the exclude key in the config file in {}",
+                    f.display(),
+                    dir.display(),
+                );
+                continue;
+            }
+            owners.insert(f, dir);
+        }
 
-                Ok(self.make_exit(all_failures, action))
+        for f in files {
+            if let Some(dir) = owners.get(f.as_path()) {
+                groups.get_mut(dir).unwrap().files.push(f.clone());
+            }
+        }
+        for f in shard_files {
+            if let Some(dir) = owners.get(f.as_path()) {
+                groups.get_mut(dir).unwrap().shard_files.push(f.clone());
             }
         }
+
+        Ok(groups.into_values().collect())
+    }
+
+    fn nested_config_group(
+        &self,
+        root: &Path,
+        dir: &Path,
+        config_file: PathBuf,
+    ) -> Result<ConfigGroup> {
+        debug!("Loading nested config from {}", config_file.display());
+        let config = config::Config::new(config_file.clone(), self.profile.as_deref())?;
+        config.warn_about_deprecated_keys();
+        // A nested config's `$PRECIOUS_ROOT` is its own directory, as it is
+        // for its commands.
+        let config_root = root.join(dir);
+        let excluder = MatcherBuilder::new(&config_root)
+            .with(&config.interpolated_exclude(&config_root)?)?
+            .build()?;
+        Ok(ConfigGroup {
+            dir: dir.to_path_buf(),
+            // The config may have been loaded from a copy of the staged
+            // files, but we always show its path in the project.
+            config_file: self.project_root.join(relative_to(&config_file, root)),
+            config,
+            excluder,
+            files: vec![],
+            shard_files: vec![],
+        })
+    }
+
+    // If an earlier run was killed while it had unstaged changes stashed, we
+    // offer to restore them. We only ask when stdin is a terminal and we're
+    // not in quiet mode, since otherwise we're probably running in a hook or
+    // CI. If the stash isn't restored, the finder refuses to stash again.
+    fn maybe_restore_orphaned_stash(&self) -> Result<()> {
+        let mut vcs = match vcs::find(&self.project_root) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let stash = match vcs.orphaned_stash()? {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        if self.quiet || !io::stdin().is_terminal() {
+            warn!(
+                "Found unstaged changes in {stash} that were stashed by an earlier run of precious which did not exit cleanly. Run \"git stash pop {stash}\" to restore them."
+            );
+            return Ok(());
+        }
+
+        print!(
+            "{} Found unstaged changes in {stash} that were stashed by an earlier run of precious which did not exit cleanly. Restore them now? [y/N] ",
+            self.chars.bullet,
+        );
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        if answer.trim().eq_ignore_ascii_case("y") {
+            vcs.unstash()?;
+            println!("{} Restored {stash}", self.chars.bullet);
+        }
+
+        Ok(())
     }
 
     fn finder(&mut self) -> Result<Finder> {
         Finder::new(
-            self.mode,
+            self.path_mode(),
             self.project_root.clone(),
             self.cwd.clone(),
-            self.config.exclude.clone(),
+            self.config.interpolated_exclude(&self.project_root)?,
+            self.config.exclude_attributes.clone(),
+            self.config.submodules,
+            self.config.all_files_from,
         )
     }
 
@@ -488,8 +1183,9 @@ impl Precious {
         t: &command::Command,
     ) -> Result<Option<Vec<ActionFailure>>> {
         let runner = |s: &Self, files: &[&Path]| -> Option<Result<(), ActionFailure>> {
-            match t.tidy(files) {
+            match t.tidy(files, s.path_mode()) {
                 Ok(Some(TidyOutcome::Changed)) => {
+                    s.record(t, files, Outcome::Tidied);
                     if !s.quiet {
                         println!(
                             "{} Tidied by {}:    [{}]",
@@ -501,6 +1197,7 @@ impl Precious {
                     Some(Ok(()))
                 }
                 Ok(Some(TidyOutcome::Unchanged)) => {
+                    s.record(t, files, Outcome::Unchanged);
                     if !s.quiet {
                         println!(
                             "{} Unchanged by {}: [{}]",
@@ -512,6 +1209,7 @@ impl Precious {
                     Some(Ok(()))
                 }
                 Ok(Some(TidyOutcome::Unknown)) => {
+                    s.record(t, files, Outcome::MaybeChanged);
                     if !s.quiet {
                         println!(
                             "{} Maybe changed by {}: [{}]",
@@ -524,6 +1222,7 @@ impl Precious {
                 }
                 Ok(None) => None,
                 Err(e) => {
+                    s.record(t, files, Outcome::Error);
                     println!(
                         "{} Error from {}: [{}]",
                         s.chars.execution_error,
@@ -548,9 +1247,10 @@ impl Precious {
         l: &command::Command,
     ) -> Result<Option<Vec<ActionFailure>>> {
         let runner = |s: &Self, files: &[&Path]| -> Option<Result<(), ActionFailure>> {
-            match l.lint(files) {
+            match l.lint(files, s.path_mode()) {
                 Ok(Some(lo)) => {
                     if lo.ok {
+                        s.record(l, files, Outcome::Passed);
                         if !s.quiet {
                             println!(
                                 "{} Passed {}: {}",
@@ -561,6 +1261,7 @@ impl Precious {
                         }
                         Some(Ok(()))
                     } else {
+                        s.record(l, files, Outcome::Failed);
                         println!(
                             "{} Failed {}: {}",
                             s.chars.lint_dirty,
@@ -596,6 +1297,7 @@ impl Precious {
                 }
                 Ok(None) => None,
                 Err(e) => {
+                    s.record(l, files, Outcome::Error);
                     println!(
                         "{} error {}: {}",
                         s.chars.execution_error,
@@ -634,7 +1336,14 @@ impl Precious {
                 res.append(
                     &mut sets
                         .into_par_iter()
-                        .filter_map(|set| runner(self, &set))
+                        .filter_map(|set| {
+                            // If we've been asked to exit we skip everything
+                            // that hasn't started yet.
+                            if exec::is_terminating() {
+                                return None;
+                            }
+                            runner(self, &set)
+                        })
                         .collect::<Vec<Result<(), ActionFailure>>>(),
                 );
                 Ok(res)
@@ -653,10 +1362,7 @@ impl Precious {
 
         let failures = results
             .into_iter()
-            .filter_map(|r| match r {
-                Ok(_) => None,
-                Err(e) => Some(e),
-            })
+            .filter_map(|r| r.err())
             .collect::<Vec<ActionFailure>>();
         if failures.is_empty() {
             Ok(None)
@@ -665,6 +1371,45 @@ impl Precious {
         }
     }
 
+    fn shard_files<'a>(&self, files: &'a [PathBuf]) -> Cow<'a, [PathBuf]> {
+        let shard = match self.shard {
+            Some(s) => s,
+            None => return Cow::Borrowed(files),
+        };
+
+        let selected = shard.select(files, self.shard_by, &self.project_root);
+        if !self.quiet {
+            println!(
+                "{} Running shard {shard} with {} of {} files",
+                self.chars.bullet,
+                selected.len(),
+                files.len(),
+            );
+        }
+        Cow::Owned(selected)
+    }
+
+    fn record(&self, command: &command::Command, files: &[&Path], outcome: Outcome) {
+        if let Some(r) = &self.report {
+            let files = files
+                .iter()
+                .map(|f| self.config_dir.join(f))
+                .collect::<Vec<_>>();
+            r.record(
+                &command.name,
+                &files.iter().map(PathBuf::as_path).collect::<Vec<_>>(),
+                outcome,
+            );
+        }
+    }
+
+    fn write_report(&self, files: Vec<PathBuf>, exit: &Exit) -> Result<()> {
+        if let Some(r) = &self.report {
+            r.write(files, exit.status)?;
+        }
+        Ok(())
+    }
+
     fn no_files_exit(&self) -> Exit {
         Exit {
             status: 0,
@@ -674,6 +1419,43 @@ impl Precious {
     }
 }
 
+fn relative_to(file: &Path, dir: &Path) -> PathBuf {
+    file.strip_prefix(dir).unwrap_or(file).to_path_buf()
+}
+
+// Drops any files which have one of the command's `exclude_attributes` set.
+fn files_for_command<'a>(
+    files: &'a [PathBuf],
+    attributes: &HashMap<PathBuf, HashSet<String>>,
+    command: &command::Command,
+) -> Cow<'a, [PathBuf]> {
+    if command.exclude_attributes.is_empty() || attributes.is_empty() {
+        return Cow::Borrowed(files);
+    }
+
+    files
+        .iter()
+        .filter(|f| {
+            let excluded = attributes.get(*f).is_some_and(|attrs| {
+                command
+                    .exclude_attributes
+                    .iter()
+                    .any(|name| attrs.contains(name))
+            });
+            if excluded {
+                debug!(
+                    "File {} is excluded for the {} command because of its git attributes",
+                    f.display(),
+                    command.name,
+                );
+            }
+            !excluded
+        })
+        .cloned()
+        .collect::<Vec<_>>()
+        .into()
+}
+
 // I tried the humantime crate but it doesn't do what I want. It formats each
 // element separately ("1s 243ms 179us 984ns"), which is _way_ more detail
 // than I want for this. This algorithm will format to the most appropriate of:
@@ -707,6 +1489,7 @@ fn format_duration(d: &Duration) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lock::LockError;
     use itertools::Itertools;
     use precious_helpers::pushd::Pushd;
     use precious_testhelper::TestHelper;
@@ -715,7 +1498,7 @@ mod tests {
     use serial_test::serial;
     #[cfg(not(target_os = "windows"))]
     use std::str::FromStr;
-    use std::{collections::HashMap, path::PathBuf};
+    use std::{collections::HashMap, fs, path::PathBuf};
     use test_case::test_case;
     #[cfg(not(target_os = "windows"))]
     use which::which;
@@ -746,7 +1529,7 @@ lint_failure_exit_codes = [1]
             assert_eq!(p.chars, chars::FUN_CHARS);
             assert!(!p.quiet);
 
-            let config_file = Precious::config_file(config.as_ref(), &p.project_root);
+            let config_file = Precious::config_file(config.as_ref(), &p.project_root)?;
             let mut expect_config_file = p.project_root;
             expect_config_file.push(name);
             assert_eq!(config_file, expect_config_file);
@@ -790,7 +1573,7 @@ lint_failure_exit_codes = [1]
         let config = app.config.clone();
         let p = Precious::new(app)?;
 
-        let config_file = Precious::config_file(config.as_ref(), &p.project_root);
+        let config_file = Precious::config_file(config.as_ref(), &p.project_root)?;
         let mut expect_config_file = p.project_root;
         expect_config_file.push(DEFAULT_CONFIG_FILE_NAME);
         assert_eq!(config_file, expect_config_file);
@@ -994,23 +1777,669 @@ lint_failure_exit_codes = [1]
 
     #[test]
     #[serial]
-    fn one_command_given() -> Result<()> {
-        let helper =
-            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+    #[cfg(not(target_os = "windows"))]
+    fn commands_get_context_env_vars() -> Result<()> {
+        let out = tempfile::tempdir()?;
+        let config = format!(
+            r#"
+    [commands.show-env]
+    type      = "lint"
+    include   = "context/*.txt"
+    invoke    = "once"
+    cmd       = ["sh", "-c", "env | grep ^PRECIOUS_ | sort > {out}/env; cp $PRECIOUS_PATHS_FILE {out}/paths", "sh"]
+    ok_exit_codes = [0]
+    "#,
+            out = out.path().display(),
+        );
+        let helper = TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, &config)?;
+        helper.write_file("context/a.txt", "a\n")?;
+        helper.write_file("context/b.txt", "b\n")?;
         let _pushd = helper.pushd_to_git_root()?;
 
-        let app = App::try_parse_from([
-            "precious",
-            "--quiet",
-            "lint",
-            "--command",
-            "rustfmt",
-            "--all",
-        ])?;
-
-        let mut p = Precious::new(app)?;
-        let status = p.run();
-
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--all"])?;
+        let status = Precious::new(app)?.run();
+        assert_eq!(status, 0);
+
+        let env = fs::read_to_string(out.path().join("env"))?;
+        let env = env
+            .lines()
+            .filter_map(|l| l.split_once('='))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            env.get("PRECIOUS_ROOT").map(PathBuf::from),
+            Some(helper.precious_root()),
+        );
+        assert_eq!(env.get("PRECIOUS_COMMAND_NAME"), Some(&"show-env"));
+        assert_eq!(env.get("PRECIOUS_ACTION"), Some(&"lint"));
+        assert_eq!(env.get("PRECIOUS_MODE"), Some(&"all"));
+        assert!(env.contains_key("PRECIOUS_PATHS_FILE"));
+        assert_eq!(
+            fs::read_to_string(out.path().join("paths"))?,
+            "context/a.txt\ncontext/b.txt\n",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(not(target_os = "windows"))]
+    fn lint_staged_from_index_uses_staged_content() -> Result<()> {
+        let config = r#"
+    [commands.grep]
+    type    = "lint"
+    include = "tests/data/foo.txt"
+    cmd     = ["grep", "--quiet", "new text"]
+    ok_exit_codes = [0]
+    lint_failure_exit_codes = [1]
+    "#;
+        let helper = TestHelper::new()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, config)?
+            .with_git_repo()?;
+        helper.modify_files()?;
+        helper.stage_all()?;
+        let unstaged = PathBuf::from("tests/data/foo.txt");
+        helper.write_file(&unstaged, "unstaged content")?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--staged-from-index"])?;
+
+        let mut p = Precious::new(app)?;
+        let status = p.run();
+
+        assert_eq!(status, 0);
+        assert_eq!(helper.read_file(&unstaged)?, "unstaged content".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(not(target_os = "windows"))]
+    fn lint_staged_from_index_uses_staged_nested_config() -> Result<()> {
+        let config = r#"
+nested_configs = true
+
+[commands.top]
+type          = "lint"
+include       = "**/*"
+cmd           = ["true"]
+ok_exit_codes = 0
+"#;
+        let nested_config = |cmd: &str| {
+            format!(
+                r#"
+[commands.api]
+type                    = "lint"
+include                 = "**/*.txt"
+cmd                     = ["{cmd}"]
+ok_exit_codes           = 0
+lint_failure_exit_codes = 1
+"#
+            )
+        };
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, config)?;
+        helper.write_file("services/api/precious.toml", &nested_config("true"))?;
+        helper.write_file("services/api/api.txt", "api\n")?;
+        helper.stage_all()?;
+        helper.write_file("services/api/precious.toml", &nested_config("false"))?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--staged-from-index"])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn tidy_staged_from_index_is_an_error() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "tidy", "--staged-from-index"])?;
+        let err = Precious::new(app).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            PreciousError::CannotTidyFromIndex.to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(not(target_os = "windows"))]
+    fn tidy_staged_with_restage() -> Result<()> {
+        let config = r#"
+    [commands.replace]
+    type    = "tidy"
+    include = "**/*.rs"
+    cmd     = ["sh", "-c", "echo tidied > $0"]
+    ok_exit_codes = [0]
+    "#;
+        let helper = TestHelper::new()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, config)?
+            .with_git_repo()?;
+        let fully_staged = PathBuf::from("src/module.rs");
+        let partially_staged = PathBuf::from("src/main.rs");
+        helper.write_file(&fully_staged, "fn staged() {}\n")?;
+        helper.write_file(&partially_staged, "fn staged() {}\n")?;
+        helper.stage_all()?;
+        helper.write_file(&partially_staged, "fn unstaged() {}\n")?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "--quiet", "tidy", "--staged", "--restage"])?;
+        let mut p = Precious::new(app)?;
+        let status = p.run();
+        assert_eq!(status, 0);
+
+        let git_output = |args: &[&str]| -> Result<String> {
+            let res = precious_helpers::exec::run(
+                "git",
+                args,
+                &HashMap::new(),
+                &[0],
+                None,
+                Some(&helper.git_root()),
+            )?;
+            Ok(res.stdout.unwrap_or_default())
+        };
+        assert_eq!(git_output(&["diff", "--name-only"])?, "src/main.rs\n");
+        assert_eq!(git_output(&["show", ":src/module.rs"])?, "tidied\n");
+        assert_eq!(git_output(&["show", ":src/main.rs"])?, "fn staged() {}\n");
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn restage_requires_staged() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "tidy", "--all", "--restage"])?;
+        let err = Precious::new(app).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            PreciousError::RestageRequiresStaged.to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn orphaned_stash_is_not_overwritten() -> Result<()> {
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        helper.modify_files()?;
+        helper.stage_all()?;
+        helper.write_file("tests/data/bar.txt", "unstaged\n")?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        // This leaves a stash behind, as if precious had been killed.
+        let mut git = vcs::git::Git::new(helper.precious_root());
+        assert!(git.stash_unstaged()?);
+        let stash = git.orphaned_stash()?;
+        assert_eq!(stash, Some(String::from("stash@{0}")));
+
+        // Linting without stashing doesn't care about the stash.
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--all"])?;
+        assert_eq!(Precious::new(app)?.run(), 0);
+
+        // In quiet mode there's no prompt, and we refuse to stash again.
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--staged-with-stash"])?;
+        let err = Precious::new(app)?.run_subcommand().unwrap_err();
+        assert!(
+            err.to_string().contains("git stash pop stash@{0}"),
+            "error names the stash: {err}",
+        );
+        assert_eq!(git.orphaned_stash()?, stash);
+
+        git.unstash()?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn config_schema_is_not_run_with_a_project() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "config", "schema"])?;
+        assert_eq!(app.run_without_project(), Some(0));
+
+        let app = App::try_parse_from(["precious", "config", "schema"])?;
+        let err = Precious::new(app).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            PreciousError::SubcommandRunsWithoutProject {
+                subcommand: "config schema".to_string(),
+            }
+            .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn tidy_is_locked_while_another_precious_runs() -> Result<()> {
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let lock = Lock::acquire(&helper.precious_root(), false)?;
+
+        let app = App::try_parse_from(["precious", "--quiet", "tidy", "--all"])?;
+        let mut p = Precious::new(app)?;
+        let err = p.run_subcommand().unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&LockError::AlreadyLocked {
+                pid: std::process::id().to_string(),
+            }),
+        );
+
+        // Linting without stashing never changes the tree, so it doesn't
+        // need the lock.
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--all"])?;
+        let mut p = Precious::new(app)?;
+        p.run_subcommand()?;
+
+        drop(lock);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(not(target_os = "windows"))]
+    fn lint_commit_msg() -> Result<()> {
+        let config = format!(
+            r#"
+{SIMPLE_CONFIG}
+
+[commands.capitalized]
+type    = "commit-msg"
+cmd     = ["sh", "-c", "head -n 1 \"$0\" | grep -q '^[A-Z]'"]
+ok_exit_codes = 0
+lint_failure_exit_codes = 1
+"#,
+        );
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, &config)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let msg_file = helper.git_root().join(".git").join("COMMIT_EDITMSG");
+        fs::write(&msg_file, "Good message\n")?;
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--commit-msg"])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 0);
+
+        fs::write(&msg_file, "bad message\n")?;
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--commit-msg",
+            ".git/COMMIT_EDITMSG",
+        ])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn tidy_commit_msg_is_an_error() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "tidy", "--commit-msg"])?;
+        let err = Precious::new(app).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            PreciousError::CannotTidyCommitMsg.to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn command_with_exclude_attributes() -> Result<()> {
+        let config = format!(
+            r#"
+{SIMPLE_CONFIG}
+exclude_attributes = "linguist-generated"
+"#,
+        );
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, &config)?;
+        helper.write_file(".gitattributes", "src/generated.rs linguist-generated\n")?;
+        helper.write_file("src/generated.rs", "fn   badly_formatted() {}\n")?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--all"])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 0);
+
+        // The precious-skip attribute excludes the file for every command by
+        // default.
+        helper.write_file(".gitattributes", "src/generated.rs precious-skip\n")?;
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "src/generated.rs",
+            "src/main.rs",
+        ])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 0);
+
+        helper.write_file(".gitattributes", "")?;
+        let app = App::try_parse_from(["precious", "--quiet", "lint", "--all"])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn lint_report_is_written_on_error() -> Result<()> {
+        let config = r#"
+nested_configs = true
+
+[commands.top]
+type          = "lint"
+include       = "**/*"
+cmd           = ["true"]
+ok_exit_codes = 0
+"#;
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, config)?;
+        helper.write_file("services/api/precious.toml", "not = [valid")?;
+        helper.write_file("services/api/api.txt", "api\n")?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let report = helper.git_root().join("report.jsonl");
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--all",
+            "--shard",
+            "1/1",
+            "--report",
+            report.to_str().unwrap(),
+        ])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 1);
+
+        let records = fs::read_to_string(&report)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<Vec<serde_json::Value>>>()?;
+        let summary = records.last().unwrap();
+        assert_eq!(summary["type"], "summary");
+        assert_eq!(summary["shard"], "1/1");
+        assert_eq!(summary["status"], 1);
+        assert_eq!(summary["files"], serde_json::json!([]));
+        assert!(
+            summary["error"]
+                .as_str()
+                .unwrap()
+                .contains("services/api/precious.toml"),
+            "error names the nested config file: {}",
+            summary["error"],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(not(target_os = "windows"))]
+    fn lint_all_with_shards() -> Result<()> {
+        let config = r#"
+[commands.per-file]
+type          = "lint"
+include       = "**/*"
+cmd           = ["true"]
+ok_exit_codes = 0
+
+[commands.once]
+type          = "lint"
+include       = "**/*"
+invoke        = "once"
+path_args     = "none"
+cmd           = ["true"]
+ok_exit_codes = 0
+
+[commands.once-per-shard]
+type           = "lint"
+include        = "**/*"
+invoke         = "once"
+path_args      = "dot"
+once_per_shard = true
+cmd            = ["true"]
+ok_exit_codes  = 0
+"#;
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, config)?;
+        let _pushd = helper.pushd_to_git_root()?;
+        let mut all_files = helper.all_files();
+        all_files.push(PathBuf::from(DEFAULT_CONFIG_FILE_NAME));
+        all_files.sort();
+
+        let mut seen: Vec<PathBuf> = vec![];
+        for shard in ["1/2", "2/2"] {
+            let report = helper
+                .git_root()
+                .join(format!("report-{}.jsonl", &shard[..1]));
+            let app = App::try_parse_from([
+                "precious",
+                "--quiet",
+                "lint",
+                "--all",
+                "--shard",
+                shard,
+                "--report",
+                report.to_str().unwrap(),
+            ])?;
+            let mut p = Precious::new(app)?;
+            assert_eq!(p.run(), 0);
+
+            let records = fs::read_to_string(&report)?
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<serde_json::Result<Vec<serde_json::Value>>>()?;
+            fs::remove_file(&report)?;
+            assert!(records.iter().all(|r| r["shard"] == shard));
+
+            let summary = records.last().unwrap();
+            assert_eq!(summary["type"], "summary");
+            assert_eq!(summary["status"], 0);
+            let files = serde_json::from_value::<Vec<PathBuf>>(summary["files"].clone())?;
+            assert!(!files.is_empty(), "shard {shard} has files");
+            seen.extend(files.iter().cloned());
+
+            let invocations = |command: &str| {
+                records
+                    .iter()
+                    .filter(|r| r["type"] == "invocation" && r["command"] == command)
+                    .map(|r| serde_json::from_value::<Vec<PathBuf>>(r["paths"].clone()).unwrap())
+                    .collect::<Vec<_>>()
+            };
+            let mut per_file = invocations("per-file").concat();
+            per_file.sort();
+            assert_eq!(per_file, files);
+            assert_eq!(invocations("once-per-shard"), vec![files.clone()]);
+            if shard == "1/2" {
+                assert_eq!(invocations("once"), vec![all_files.clone()]);
+            } else {
+                assert!(invocations("once").is_empty());
+            }
+        }
+
+        seen.sort();
+        assert_eq!(seen, all_files);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(not(target_os = "windows"))]
+    fn lint_all_with_nested_configs() -> Result<()> {
+        let config = r#"
+nested_configs = true
+
+[commands.top]
+type          = "lint"
+include       = "**/*"
+cmd           = ["true"]
+ok_exit_codes = 0
+"#;
+        // Both of these commands only pass if they run with the nested
+        // config's directory as the project root.
+        let nested_config = r#"
+exclude = ["excluded.txt", "$PRECIOUS_ROOT/src/root-excluded.txt"]
+
+[commands.api]
+type          = "lint"
+include       = "**/*.txt"
+cmd           = ["test", "-f"]
+ok_exit_codes = 0
+
+[commands.api-once]
+type          = "lint"
+include       = "**/*"
+invoke        = "once"
+path_args     = "none"
+cmd           = ["test", "-f", "$PRECIOUS_ROOT/api.txt"]
+ok_exit_codes = 0
+"#;
+        let helper = TestHelper::new()?
+            .with_git_repo()?
+            .with_config_file(DEFAULT_CONFIG_FILE_NAME, config)?;
+        helper.write_file("services/api/precious.toml", nested_config)?;
+        helper.write_file("services/api/api.txt", "api\n")?;
+        helper.write_file("services/api/src/lib.txt", "lib\n")?;
+        helper.write_file("services/api/excluded.txt", "excluded\n")?;
+        helper.write_file("services/api/src/root-excluded.txt", "excluded\n")?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let report = helper.git_root().join("report.jsonl");
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--all",
+            "--report",
+            report.to_str().unwrap(),
+        ])?;
+        let mut p = Precious::new(app)?;
+        assert_eq!(p.run(), 0);
+
+        let records = fs::read_to_string(&report)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<Vec<serde_json::Value>>>()?;
+        let invocations = |command: &str| {
+            let mut paths = records
+                .iter()
+                .filter(|r| r["type"] == "invocation" && r["command"] == command)
+                .flat_map(|r| serde_json::from_value::<Vec<PathBuf>>(r["paths"].clone()).unwrap())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        let mut top_files = helper.all_files();
+        top_files.push(PathBuf::from(DEFAULT_CONFIG_FILE_NAME));
+        top_files.sort();
+        assert_eq!(invocations("top"), top_files);
+        assert_eq!(
+            invocations("api"),
+            ["services/api/api.txt", "services/api/src/lib.txt"]
+                .iter()
+                .map(PathBuf::from)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            invocations("api-once"),
+            [
+                "services/api/api.txt",
+                "services/api/precious.toml",
+                "services/api/src/lib.txt",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn shard_args() -> Result<()> {
+        assert!(App::try_parse_from(["precious", "lint", "--all", "--shard", "3/2"]).is_err());
+        assert!(App::try_parse_from(["precious", "lint", "--all", "--shard-by", "size"]).is_err());
+        assert!(
+            App::try_parse_from(["precious", "lint", "--commit-msg", "--shard", "1/2"]).is_err()
+        );
+        App::try_parse_from([
+            "precious",
+            "tidy",
+            "--all",
+            "--shard",
+            "1/2",
+            "--shard-by",
+            "size",
+        ])?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn one_command_given() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--command",
+            "rustfmt",
+            "--all",
+        ])?;
+
+        let mut p = Precious::new(app)?;
+        let status = p.run();
+
         assert_eq!(status, 0);
 
         Ok(())
@@ -1032,10 +2461,202 @@ lint_failure_exit_codes = [1]
             "--all",
         ])?;
 
+        let mut p = Precious::new(app)?;
+        let err = p.run_subcommand().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No command in your config matches --command no-such-command",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn typo_in_command_or_label_is_an_error() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--command",
+            "rustfmt",
+            "--command",
+            "clipy",
+            "--label",
+            "rsut",
+            "--all",
+        ])?;
+
+        let mut p = Precious::new(app)?;
+        let err = p.run_subcommand().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No command in your config matches --command clipy, --label rsut",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn command_selection_flags_and_skip_env_var() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        env::set_var("PRECIOUS_SKIP", "clippy, ,typos");
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--command",
+            "rustfmt",
+            "--command",
+            "clippy",
+            "--label",
+            "rust",
+            "--skip-command",
+            "prettier",
+            "--all",
+        ])?;
+        let p = Precious::new(app);
+        env::remove_var("PRECIOUS_SKIP");
+        let p = p?;
+
+        assert_eq!(p.selection.names, ["rustfmt", "clippy"]);
+        assert_eq!(p.selection.labels, ["rust"]);
+        assert_eq!(p.selection.skip, ["prettier", "clippy", "typos"]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn profile_from_flag_or_env_var() -> Result<()> {
+        let config = format!("{SIMPLE_CONFIG}\n[profiles.ci]\njobs = 3\n");
+        let helper = TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, &config)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let p = Precious::new(App::try_parse_from([
+            "precious", "--quiet", "lint", "--all",
+        ])?)?;
+        assert_eq!(p.profile, None);
+        assert_eq!(p.config.jobs, None);
+
+        let p = Precious::new(App::try_parse_from([
+            "precious",
+            "--quiet",
+            "--profile",
+            "ci",
+            "lint",
+            "--all",
+        ])?)?;
+        assert_eq!(p.profile.as_deref(), Some("ci"));
+        assert_eq!(p.config.jobs, Some(3));
+
+        env::set_var("PRECIOUS_PROFILE", "ci");
+        let p = Precious::new(App::try_parse_from([
+            "precious", "--quiet", "lint", "--all",
+        ])?);
+        env::remove_var("PRECIOUS_PROFILE");
+        assert_eq!(p?.config.jobs, Some(3));
+
+        let err = Precious::new(App::try_parse_from([
+            "precious",
+            "--quiet",
+            "--profile",
+            "local",
+            "lint",
+            "--all",
+        ])?)
+        .unwrap_err();
+        assert_eq!(
+            err.downcast::<config::ConfigError>()?,
+            config::ConfigError::UnknownProfile {
+                name: "local".to_string(),
+                file: helper.config_file(DEFAULT_CONFIG_FILE_NAME),
+            },
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    fn skipped_command_does_not_run() -> Result<()> {
+        let helper =
+            TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, SIMPLE_CONFIG)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--command",
+            "rustfmt",
+            "--skip-command",
+            "rustfmt",
+            "--all",
+        ])?;
+
+        let mut p = Precious::new(app)?;
+        let err = p.run_subcommand().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            PreciousError::NoCommandsMatch {
+                what: "linting".to_string(),
+                selection: "--command rustfmt --skip-command rustfmt".to_string(),
+            }
+            .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(not(target_os = "windows"))]
+    fn skipped_command_does_not_run_while_others_do() -> Result<()> {
+        let config = r#"
+[commands.touch]
+type          = "lint"
+include       = "**/*"
+invoke        = "once"
+path_args     = "none"
+cmd           = ["sh", "-c", "touch ran-touch"]
+ok_exit_codes = [0]
+
+[commands.fails]
+type          = "lint"
+include       = "**/*"
+invoke        = "once"
+path_args     = "none"
+cmd           = ["sh", "-c", "touch ran-fails; exit 1"]
+ok_exit_codes = [0]
+lint_failure_exit_codes = [1]
+"#;
+        let helper = TestHelper::new()?.with_config_file(DEFAULT_CONFIG_FILE_NAME, config)?;
+        let _pushd = helper.pushd_to_git_root()?;
+
+        let app = App::try_parse_from([
+            "precious",
+            "--quiet",
+            "lint",
+            "--skip-command",
+            "fails",
+            "--all",
+        ])?;
+
         let mut p = Precious::new(app)?;
         let status = p.run();
 
-        assert_eq!(status, 1);
+        assert_eq!(status, 0);
+        assert!(helper.precious_root().join("ran-touch").exists());
+        assert!(!helper.precious_root().join("ran-fails").exists());
 
         Ok(())
     }
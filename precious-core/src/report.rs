@@ -0,0 +1,114 @@
+use crate::paths::shard::Shard;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// The report is written as JSON Lines, with one record per line. Every record
+// includes the shard that produced it, so the reports from each shard of a
+// sharded run can be merged by concatenating them.
+#[derive(Debug)]
+pub struct Report {
+    path: PathBuf,
+    shard: Option<Shard>,
+    action: &'static str,
+    records: Mutex<Vec<Record>>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Tidied,
+    Unchanged,
+    MaybeChanged,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Record {
+    // There is one of these for each time a command is run.
+    Invocation {
+        shard: Option<String>,
+        action: &'static str,
+        command: String,
+        paths: Vec<PathBuf>,
+        outcome: Outcome,
+    },
+    // This is always the last record. It lists all the files the shard
+    // operated on, so that merged reports can be checked for completeness.
+    // If precious failed with an error, the files are empty and the error is
+    // included.
+    Summary {
+        shard: Option<String>,
+        action: &'static str,
+        files: Vec<PathBuf>,
+        status: i8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+impl Report {
+    pub fn new(path: PathBuf, shard: Option<Shard>, action: &'static str) -> Report {
+        Report {
+            path,
+            shard,
+            action,
+            records: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn record(&self, command: &str, paths: &[&Path], outcome: Outcome) {
+        self.records.lock().unwrap().push(Record::Invocation {
+            shard: self.shard.map(|s| s.to_string()),
+            action: self.action,
+            command: command.to_string(),
+            paths: paths.iter().map(|p| p.to_path_buf()).collect(),
+            outcome,
+        });
+    }
+
+    pub fn write(&self, files: Vec<PathBuf>, status: i8) -> Result<()> {
+        self.write_with_summary(files, status, None)
+    }
+
+    // This is used when precious fails with an error, so that the report
+    // still says how this shard ended.
+    pub fn write_error(&self, error: String) -> Result<()> {
+        self.write_with_summary(vec![], 1, Some(error))
+    }
+
+    fn write_with_summary(
+        &self,
+        files: Vec<PathBuf>,
+        status: i8,
+        error: Option<String>,
+    ) -> Result<()> {
+        let mut records = self.records.lock().unwrap();
+        records.push(Record::Summary {
+            shard: self.shard.map(|s| s.to_string()),
+            action: self.action,
+            files,
+            status,
+            error,
+        });
+
+        let file = File::create(&self.path)
+            .with_context(|| format!("Creating report file at {}", self.path.display()))?;
+        let mut writer = BufWriter::new(file);
+        for r in records.iter() {
+            serde_json::to_writer(&mut writer, r)?;
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
@@ -1 +1,103 @@
+pub mod git;
+pub mod hg;
+
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
 pub const DIRS: &[&str] = &[".git", ".hg", ".svn"];
+
+// This controls whether files inside submodules are treated as part of the
+// project.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum Submodules {
+    #[default]
+    #[serde(rename = "include")]
+    Include,
+    #[serde(rename = "exclude")]
+    Exclude,
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum VcsError {
+    #[error("Could not determine the repo root by running \"{cmd:}\"")]
+    CouldNotDetermineRepoRoot { cmd: String },
+
+    #[error("{vcs:} does not support {what:}")]
+    Unsupported {
+        vcs: &'static str,
+        what: &'static str,
+    },
+}
+
+// This is the interface that `Finder` uses to ask a VCS about the state of a
+// checkout. All of the paths returned by these methods are relative to the
+// checkout root returned by `root`.
+pub trait Vcs: fmt::Debug {
+    fn root(&mut self) -> Result<PathBuf>;
+    // Returns the VCS's own metadata directory, like ".git", where precious
+    // can keep files that should never be seen as part of the checkout.
+    fn state_dir(&mut self) -> Result<PathBuf>;
+    // Returns every file under the directory passed to `find` which is
+    // either tracked or untracked but not ignored.
+    fn all_files(&mut self) -> Result<Vec<PathBuf>>;
+    fn modified_files(&mut self) -> Result<Vec<PathBuf>>;
+    fn staged_files(&mut self) -> Result<Vec<PathBuf>>;
+    // Returns files with changes in the working tree that are not staged.
+    fn unstaged_files(&mut self) -> Result<Vec<PathBuf>>;
+    fn stage(&mut self, files: &[PathBuf]) -> Result<()>;
+    // Returns true if anything was stashed, in which case `unstash` must be
+    // called later to restore it.
+    fn stash_unstaged(&mut self) -> Result<bool>;
+    fn unstash(&mut self) -> Result<()>;
+    // If an earlier run stashed changes and then exited without restoring
+    // them, this returns a name for the stash that can be given to the user.
+    // Calling `unstash` will restore it.
+    fn orphaned_stash(&mut self) -> Result<Option<String>>;
+    // Writes the staged version of the given files under `dest`, without
+    // touching the working tree. The files must be relative to the repo
+    // root. Every file in the index with one of the given names is written
+    // as well, so that config files are there even when they aren't staged.
+    fn checkout_staged(&mut self, dest: &Path, files: &[PathBuf], names: &[&str]) -> Result<()>;
+    // Returns the paths of all initialized submodules, including submodules
+    // of submodules.
+    fn submodules(&mut self) -> Result<Vec<PathBuf>>;
+    // Returns the directory where the VCS looks for hook scripts.
+    fn hooks_dir(&mut self) -> Result<PathBuf>;
+    // Returns the attributes from the given list that are set for each
+    // file. Unlike other methods, the paths here are relative to the
+    // directory passed to `find`. Files without any of the attributes are not
+    // included in the returned map.
+    fn attributes(
+        &mut self,
+        files: &[PathBuf],
+        names: &[String],
+    ) -> Result<HashMap<PathBuf, HashSet<String>>>;
+}
+
+// Returns the VCS directory (".git", ".hg", etc.) found in the given
+// directory, if any.
+pub fn checkout_dir(dir: &Path) -> Option<&'static str> {
+    DIRS.iter().copied().find(|d| dir.join(d).exists())
+}
+
+// Looks for the checkout root at or above `start` and returns the matching
+// `Vcs` implementation. Commands will be run with `start` as their working
+// directory. This returns `None` if there is no checkout or if the checkout
+// is for a VCS we don't support.
+pub fn find(start: &Path) -> Option<Box<dyn Vcs>> {
+    for anc in start.ancestors() {
+        match checkout_dir(anc) {
+            Some(".git") => return Some(Box::new(git::Git::new(start.to_path_buf()))),
+            Some(".hg") => return Some(Box::new(hg::Hg::new(start.to_path_buf()))),
+            Some(_) => return None,
+            None => (),
+        }
+    }
+    None
+}
@@ -0,0 +1,445 @@
+use crate::vcs::{Vcs, VcsError};
+use anyhow::{Context, Result};
+use clean_path::Clean;
+use itertools::Itertools;
+use log::debug;
+use once_cell::sync::Lazy;
+use precious_helpers::exec;
+use regex::Regex;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+#[derive(Debug)]
+pub struct Git {
+    // The directory in which we run git commands. This is the project root,
+    // which may be a subdirectory of the repo root.
+    cwd: PathBuf,
+    root: Option<PathBuf>,
+    git_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum GitError {
+    #[error("Could not find the stash entry {sha:} created by precious in the output of \"git stash list\"")]
+    StashNotFound { sha: String },
+
+    #[error("Not stashing unstaged changes because {stash:} holds changes stashed by an earlier run of precious, which must be restored first")]
+    OrphanedStashExists { stash: String },
+}
+
+const STASH_MESSAGE: &str = "precious: unstaged changes";
+const STASH_MARKER_FILE: &str = "precious-stash";
+
+static KEEP_INDEX_RE: Lazy<Regex> = Lazy::new(|| Regex::new(".*").unwrap());
+
+impl Git {
+    pub fn new(cwd: PathBuf) -> Git {
+        Git {
+            cwd,
+            root: None,
+            git_dir: None,
+        }
+    }
+
+    // This file records the stash entry we created, so that we pop that exact
+    // entry later. If precious is killed before it can pop the stash, the
+    // marker lets a later run find the orphaned entry.
+    fn stash_marker(&mut self) -> Result<PathBuf> {
+        Ok(self.state_dir()?.join(STASH_MARKER_FILE))
+    }
+
+    fn stash_head(&self) -> Result<Option<String>> {
+        let res = exec::run(
+            "git",
+            &["rev-parse", "--quiet", "--verify", "refs/stash"],
+            &HashMap::new(),
+            &[0, 1],
+            None,
+            Some(&self.cwd),
+        )?;
+        Ok(res.stdout.map(|s| s.trim().to_string()))
+    }
+
+    fn stash_index(&self, sha: &str) -> Result<Option<usize>> {
+        let res = exec::run(
+            "git",
+            &["stash", "list", "--format=%H"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+        )?;
+        Ok(res
+            .stdout
+            .and_then(|s| s.lines().position(|l| l.trim() == sha)))
+    }
+
+    fn files_from_git(&self, args: &[&str]) -> Result<Vec<PathBuf>> {
+        let result = exec::run("git", args, &HashMap::new(), &[0], None, Some(&self.cwd))?;
+        match result.stdout {
+            Some(s) => Ok(s.lines().map(PathBuf::from).collect()),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+impl Vcs for Git {
+    fn root(&mut self) -> Result<PathBuf> {
+        if let Some(r) = &self.root {
+            return Ok(r.to_path_buf());
+        }
+
+        let res = exec::run(
+            "git",
+            &["rev-parse", "--show-toplevel"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+        )?;
+
+        let stdout = res
+            .stdout
+            .ok_or_else(|| VcsError::CouldNotDetermineRepoRoot {
+                cmd: String::from("git rev-parse --show-toplevel"),
+            })?;
+        self.root = Some(PathBuf::from(stdout.trim()));
+
+        Ok(self.root.clone().unwrap())
+    }
+
+    fn state_dir(&mut self) -> Result<PathBuf> {
+        if let Some(d) = &self.git_dir {
+            return Ok(d.to_path_buf());
+        }
+
+        // We can't just use "$root/.git" because in a linked worktree or a
+        // submodule, ".git" is a file pointing at the real git dir.
+        let res = exec::run(
+            "git",
+            &["rev-parse", "--absolute-git-dir"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+        )?;
+        let stdout = res
+            .stdout
+            .ok_or_else(|| VcsError::CouldNotDetermineRepoRoot {
+                cmd: String::from("git rev-parse --absolute-git-dir"),
+            })?;
+        self.git_dir = Some(PathBuf::from(stdout.trim()));
+
+        Ok(self.git_dir.clone().unwrap())
+    }
+
+    fn all_files(&mut self) -> Result<Vec<PathBuf>> {
+        debug!("Getting all files according to git");
+        // We use "-z" so that git doesn't quote paths with unusual
+        // characters. The "--full-name" flag makes the paths relative to the
+        // root even though we only list files under the cwd. A file with
+        // merge conflicts is listed once for each stage, so we dedupe.
+        let res = exec::run(
+            "git",
+            &[
+                "ls-files",
+                "-z",
+                "--cached",
+                "--others",
+                "--exclude-standard",
+                "--full-name",
+            ],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+        )?;
+        match res.stdout {
+            Some(s) => Ok(s
+                .split('\0')
+                .filter(|f| !f.is_empty())
+                .unique()
+                .map(PathBuf::from)
+                .collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn modified_files(&mut self) -> Result<Vec<PathBuf>> {
+        debug!("Getting modified files according to git");
+        self.files_from_git(&["diff", "--name-only", "--diff-filter=ACM", "HEAD"])
+    }
+
+    fn staged_files(&mut self) -> Result<Vec<PathBuf>> {
+        debug!("Getting staged files according to git");
+        self.files_from_git(&["diff", "--cached", "--name-only", "--diff-filter=ACM"])
+    }
+
+    fn unstaged_files(&mut self) -> Result<Vec<PathBuf>> {
+        debug!("Getting unstaged files according to git");
+        self.files_from_git(&["diff", "--name-only"])
+    }
+
+    fn stage(&mut self, files: &[PathBuf]) -> Result<()> {
+        let paths = files
+            .iter()
+            .map(|f| f.to_string_lossy())
+            .collect::<Vec<_>>();
+        let mut args = vec!["add", "--"];
+        args.extend(paths.iter().map(|p| p.as_ref()));
+        exec::run(
+            "git",
+            &args,
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.root()?),
+        )?;
+        Ok(())
+    }
+
+    fn stash_unstaged(&mut self) -> Result<bool> {
+        let git_root = self.root()?;
+        // In a linked worktree or a submodule, ".git" is a file, so we need
+        // to look in the real git dir.
+        if self.state_dir()?.join("MERGE_MODE").exists() {
+            return Ok(false);
+        }
+        // There is only one marker, so stashing again would lose track of
+        // the earlier run's stash.
+        if let Some(stash) = self.orphaned_stash()? {
+            return Err(GitError::OrphanedStashExists { stash }.into());
+        }
+
+        let before = self.stash_head()?;
+        exec::run(
+            "git",
+            &["stash", "push", "--keep-index", "--message", STASH_MESSAGE],
+            &HashMap::new(),
+            &[0],
+            // If there is a post-checkout hook, git will show any output
+            // it prints to stdout on stderr instead.
+            Some(std::slice::from_ref(&KEEP_INDEX_RE)),
+            Some(&git_root),
+        )?;
+
+        // If there was nothing to stash then git doesn't create a new stash
+        // entry, and we must not pop whatever entry was already on top.
+        let after = self.stash_head()?;
+        match after {
+            Some(sha) if before.as_ref() != Some(&sha) => {
+                let marker = self.stash_marker()?;
+                debug!("Recording stash {sha} in {}", marker.display());
+                fs::write(&marker, &sha)
+                    .with_context(|| format!("Writing stash marker to {}", marker.display()))?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn unstash(&mut self) -> Result<()> {
+        let marker = self.stash_marker()?;
+        let sha = fs::read_to_string(&marker)
+            .with_context(|| format!("Reading stash marker from {}", marker.display()))?;
+        let sha = sha.trim();
+        let index = self
+            .stash_index(sha)?
+            .ok_or_else(|| GitError::StashNotFound {
+                sha: sha.to_string(),
+            })?;
+
+        exec::run(
+            "git",
+            &["stash", "pop", &format!("stash@{{{index}}}")],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+        )?;
+        fs::remove_file(&marker)?;
+
+        Ok(())
+    }
+
+    fn orphaned_stash(&mut self) -> Result<Option<String>> {
+        let marker = self.stash_marker()?;
+        if !marker.exists() {
+            return Ok(None);
+        }
+
+        let sha = fs::read_to_string(&marker)?;
+        let sha = sha.trim();
+        match self.stash_index(sha)? {
+            Some(index) => Ok(Some(format!("stash@{{{index}}}"))),
+            // The stash must have been restored or dropped by hand, so the
+            // marker is just left over.
+            None => {
+                debug!("Removing stale stash marker at {}", marker.display());
+                fs::remove_file(&marker)?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn checkout_staged(&mut self, dest: &Path, files: &[PathBuf], names: &[&str]) -> Result<()> {
+        let root = self.root()?;
+        let mut paths = files
+            .iter()
+            .map(|f| f.to_string_lossy().into_owned())
+            .collect::<HashSet<_>>();
+        if !names.is_empty() {
+            let pathspecs = names
+                .iter()
+                .map(|n| format!(":(glob)**/{n}"))
+                .collect::<Vec<_>>();
+            let mut args = vec!["ls-files", "-z", "--cached", "--"];
+            args.extend(pathspecs.iter().map(String::as_str));
+            let res = exec::run("git", &args, &HashMap::new(), &[0], None, Some(&root))?;
+            if let Some(stdout) = res.stdout {
+                paths.extend(
+                    stdout
+                        .split('\0')
+                        .filter(|p| !p.is_empty())
+                        .map(String::from),
+                );
+            }
+        }
+        if paths.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            "Copying {} staged files into {}",
+            paths.len(),
+            dest.display()
+        );
+        let mut input = vec![];
+        for p in paths.iter().sorted() {
+            input.extend_from_slice(p.as_bytes());
+            input.push(0);
+        }
+        // The trailing slash matters here. Without it git treats the prefix
+        // as a prefix for each file name rather than a directory.
+        let prefix = format!("--prefix={}/", dest.display());
+        exec::run_with_stdin(
+            "git",
+            &["checkout-index", "-z", "--stdin", &prefix],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&root),
+            &input,
+        )?;
+        Ok(())
+    }
+
+    fn submodules(&mut self) -> Result<Vec<PathBuf>> {
+        // There can't be any submodules without a .gitmodules file, so we
+        // can skip running git for most repos.
+        let root = self.root()?;
+        if !root.join(".gitmodules").exists() {
+            return Ok(vec![]);
+        }
+
+        // The $displaypath variable is relative to the directory git is run
+        // in, so this gives us paths relative to the root.
+        let res = exec::run(
+            "git",
+            &[
+                "submodule",
+                "--quiet",
+                "foreach",
+                "--recursive",
+                r#"printf '%s\n' "$displaypath""#,
+            ],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&root),
+        )?;
+        match res.stdout {
+            Some(s) => Ok(s.lines().map(PathBuf::from).collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn hooks_dir(&mut self) -> Result<PathBuf> {
+        // Asking for the hooks path this way respects core.hooksPath. The
+        // path git gives back is relative to the directory it ran in.
+        let res = exec::run(
+            "git",
+            &["rev-parse", "--git-path", "hooks"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+        )?;
+        let stdout = res
+            .stdout
+            .ok_or_else(|| VcsError::CouldNotDetermineRepoRoot {
+                cmd: String::from("git rev-parse --git-path hooks"),
+            })?;
+        Ok(self.cwd.join(stdout.trim()).clean())
+    }
+
+    fn attributes(
+        &mut self,
+        files: &[PathBuf],
+        names: &[String],
+    ) -> Result<HashMap<PathBuf, HashSet<String>>> {
+        let mut attributes: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+        if files.is_empty() || names.is_empty() {
+            return Ok(attributes);
+        }
+
+        debug!(
+            "Checking {} files for these git attributes: {}",
+            files.len(),
+            names.join(" "),
+        );
+        let mut args = vec!["check-attr", "-z", "--stdin"];
+        args.extend(names.iter().map(String::as_str));
+        let mut input = vec![];
+        for f in files {
+            input.extend_from_slice(f.to_string_lossy().as_bytes());
+            input.push(0);
+        }
+        let res = exec::run_with_stdin(
+            "git",
+            &args,
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+            &input,
+        )?;
+
+        // The output is a list of "<path> NUL <attribute> NUL <info> NUL"
+        // entries.
+        if let Some(stdout) = res.stdout {
+            for (path, name, info) in stdout.split('\0').tuples() {
+                if attribute_is_set(info) {
+                    attributes
+                        .entry(PathBuf::from(path))
+                        .or_default()
+                        .insert(name.to_string());
+                }
+            }
+        }
+
+        Ok(attributes)
+    }
+}
+
+// An attribute can be set ("attr"), unset ("-attr"), unspecified, or set to
+// a value ("attr=value"). We treat "attr=false" as unset, since that's how
+// linguist attributes are turned off for paths that would otherwise match a
+// broader pattern.
+fn attribute_is_set(info: &str) -> bool {
+    !matches!(info, "unset" | "unspecified" | "false")
+}
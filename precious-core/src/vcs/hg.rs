@@ -0,0 +1,157 @@
+use crate::vcs::{Vcs, VcsError};
+use anyhow::Result;
+use log::debug;
+use precious_helpers::exec;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct Hg {
+    cwd: PathBuf,
+    root: Option<PathBuf>,
+}
+
+impl Hg {
+    pub fn new(cwd: PathBuf) -> Hg {
+        Hg { cwd, root: None }
+    }
+}
+
+impl Vcs for Hg {
+    fn root(&mut self) -> Result<PathBuf> {
+        if let Some(r) = &self.root {
+            return Ok(r.to_path_buf());
+        }
+
+        let res = exec::run(
+            "hg",
+            &["root"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.cwd),
+        )?;
+
+        let stdout = res
+            .stdout
+            .ok_or_else(|| VcsError::CouldNotDetermineRepoRoot {
+                cmd: String::from("hg root"),
+            })?;
+        self.root = Some(PathBuf::from(stdout.trim()));
+
+        Ok(self.root.clone().unwrap())
+    }
+
+    fn state_dir(&mut self) -> Result<PathBuf> {
+        Ok(self.root()?.join(".hg"))
+    }
+
+    // Unlike git, this returns files outside the cwd too. The `Finder` drops
+    // those.
+    fn all_files(&mut self) -> Result<Vec<PathBuf>> {
+        debug!("Getting all files according to hg");
+        let root = self.root()?;
+        let result = exec::run(
+            "hg",
+            &[
+                "status",
+                "--added",
+                "--modified",
+                "--clean",
+                "--unknown",
+                "--no-status",
+            ],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&root),
+        )?;
+        match result.stdout {
+            Some(s) => Ok(s.lines().map(PathBuf::from).collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn modified_files(&mut self) -> Result<Vec<PathBuf>> {
+        debug!("Getting modified files according to hg");
+        // We run this in the repo root because hg may otherwise give us paths
+        // relative to the current directory, depending on the value of the
+        // ui.relative-paths setting.
+        let root = self.root()?;
+        let result = exec::run(
+            "hg",
+            &["status", "--added", "--modified", "--no-status"],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&root),
+        )?;
+        match result.stdout {
+            Some(s) => Ok(s.lines().map(PathBuf::from).collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    // Mercurial has no staging area. Everything that is added or modified
+    // will be part of the next commit.
+    fn staged_files(&mut self) -> Result<Vec<PathBuf>> {
+        self.modified_files()
+    }
+
+    fn unstaged_files(&mut self) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+
+    fn stage(&mut self, _files: &[PathBuf]) -> Result<()> {
+        Ok(())
+    }
+
+    // Since every change is part of the next commit, there is never any
+    // unstaged content to set aside with `hg shelve`.
+    fn stash_unstaged(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn unstash(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn orphaned_stash(&mut self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn checkout_staged(&mut self, _dest: &Path, _files: &[PathBuf], _names: &[&str]) -> Result<()> {
+        Err(VcsError::Unsupported {
+            vcs: "hg",
+            what: "copying staged content to another directory",
+        }
+        .into())
+    }
+
+    // Mercurial subrepos are deprecated, so we don't look for them.
+    fn submodules(&mut self) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+
+    // Mercurial hooks are configured in an hgrc file rather than being
+    // scripts in a directory.
+    fn hooks_dir(&mut self) -> Result<PathBuf> {
+        Err(VcsError::Unsupported {
+            vcs: "hg",
+            what: "installing hooks",
+        }
+        .into())
+    }
+
+    // Mercurial has nothing like .gitattributes, so no file ever has any
+    // attributes.
+    fn attributes(
+        &mut self,
+        _files: &[PathBuf],
+        _names: &[String],
+    ) -> Result<HashMap<PathBuf, HashSet<String>>> {
+        Ok(HashMap::new())
+    }
+}
@@ -5,7 +5,17 @@ use log::{
     {debug, error, log_enabled},
 };
 use regex::Regex;
-use std::{collections::HashMap, env, fs, path::Path, process};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::Write,
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
 use thiserror::Error;
 use which::which;
 
@@ -55,6 +65,46 @@ fn exec_output_summary(stdout: &str, stderr: &str) -> String {
     output
 }
 
+// These track the child processes that are currently running, so that we
+// can pass on a termination signal to them if precious is asked to exit.
+static CHILDREN: Mutex<Vec<u32>> = Mutex::new(vec![]);
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+
+// Sends SIGTERM to every child process that is currently running. Once this
+// has been called, `is_terminating` will return true, so callers can avoid
+// starting any new work. Child processes started after this is called are
+// not affected, which allows cleanup commands to run. Calling this more than
+// once has no effect.
+pub fn terminate_children() {
+    if TERMINATING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+    for pid in children.iter() {
+        debug!("Sending SIGTERM to child process {pid}");
+        terminate(*pid);
+    }
+}
+
+pub fn is_terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
+
+#[cfg(target_family = "unix")]
+fn terminate(pid: u32) {
+    // SAFETY: kill is safe to call with any pid. At worst it returns an error
+    // if the process has already exited.
+    unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGTERM);
+    }
+}
+
+// On Windows, a Ctrl-C is delivered to every process attached to the
+// console, so there's nothing to pass on.
+#[cfg(target_family = "windows")]
+fn terminate(_: u32) {}
+
 #[derive(Debug)]
 pub struct ExecOutput {
     pub exit_code: i32,
@@ -69,6 +119,40 @@ pub fn run(
     ok_exit_codes: &[i32],
     ignore_stderr: Option<&[Regex]>,
     in_dir: Option<&Path>,
+) -> Result<ExecOutput> {
+    run_maybe_with_stdin(exe, args, env, ok_exit_codes, ignore_stderr, in_dir, None)
+}
+
+// This is the same as `run`, except that the given input is written to the
+// command's stdin.
+pub fn run_with_stdin(
+    exe: &str,
+    args: &[&str],
+    env: &HashMap<String, String>,
+    ok_exit_codes: &[i32],
+    ignore_stderr: Option<&[Regex]>,
+    in_dir: Option<&Path>,
+    stdin: &[u8],
+) -> Result<ExecOutput> {
+    run_maybe_with_stdin(
+        exe,
+        args,
+        env,
+        ok_exit_codes,
+        ignore_stderr,
+        in_dir,
+        Some(stdin),
+    )
+}
+
+fn run_maybe_with_stdin(
+    exe: &str,
+    args: &[&str],
+    env: &HashMap<String, String>,
+    ok_exit_codes: &[i32],
+    ignore_stderr: Option<&[Regex]>,
+    in_dir: Option<&Path>,
+    stdin: Option<&[u8]>,
 ) -> Result<ExecOutput> {
     if which(exe).is_err() {
         let path = match env::var("PATH") {
@@ -110,7 +194,7 @@ pub fn run(
         }
     }
 
-    let output = output_from_command(c, ok_exit_codes, exe, args)
+    let output = output_from_command(c, ok_exit_codes, exe, args, stdin)
         .with_context(|| format!(r#"Failed to execute command `{}`"#, exec_string(exe, args)))?;
 
     if log_enabled!(Debug) && !output.stdout.is_empty() {
@@ -151,8 +235,42 @@ fn output_from_command(
     ok_exit_codes: &[i32],
     exe: &str,
     args: &[&str],
+    stdin: Option<&[u8]>,
 ) -> Result<process::Output> {
-    let output = c.output()?;
+    c.stdin(if stdin.is_some() {
+        process::Stdio::piped()
+    } else {
+        process::Stdio::null()
+    })
+    .stdout(process::Stdio::piped())
+    .stderr(process::Stdio::piped());
+
+    // We don't hold the lock while spawning, since that would make every
+    // spawn wait on every other one. Instead, `terminate_children` sets
+    // `TERMINATING` before it takes the lock, so if that changed while we
+    // were spawning, it may have sent its signals without seeing this child.
+    // A child started once we were already terminating is a cleanup command,
+    // which must be left alone.
+    let was_terminating = is_terminating();
+    let child = c.spawn()?;
+    let pid = child.id();
+    {
+        let mut children = CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+        if !was_terminating && is_terminating() {
+            debug!("Sending SIGTERM to child process {pid}, which started while terminating");
+            terminate(pid);
+        }
+        children.push(pid);
+    }
+    let output = match stdin {
+        Some(input) => wait_with_input(child, input),
+        None => child.wait_with_output(),
+    };
+    CHILDREN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|p| *p != pid);
+    let output = output?;
     match output.status.code() {
         Some(code) => {
             let estr = exec_string(exe, args);
@@ -182,6 +300,20 @@ fn output_from_command(
     Ok(output)
 }
 
+// We write the input from another thread. Otherwise a command that fills its
+// stdout pipe before reading all of its input would block forever.
+fn wait_with_input(mut child: process::Child, input: &[u8]) -> std::io::Result<process::Output> {
+    let mut child_stdin = child.stdin.take().expect("stdin should be piped");
+    std::thread::scope(|s| {
+        let writer = s.spawn(move || child_stdin.write_all(input));
+        let output = child.wait_with_output();
+        match writer.join() {
+            Ok(Err(e)) if e.kind() != std::io::ErrorKind::BrokenPipe => Err(e),
+            _ => output,
+        }
+    })
+}
+
 fn exec_string(exe: &str, args: &[&str]) -> String {
     let mut estr = exe.to_string();
     if !args.is_empty() {
@@ -253,6 +385,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[parallel]
+    fn run_with_stdin() -> Result<()> {
+        // This is big enough to fill a pipe buffer, so it checks that we
+        // don't deadlock when the command writes output before it has read
+        // all of its input.
+        let input = "some input\n".repeat(100_000);
+        let res = super::run_with_stdin(
+            "cat",
+            &[],
+            &HashMap::new(),
+            &[0],
+            None,
+            None,
+            input.as_bytes(),
+        )?;
+        assert_eq!(res.exit_code, 0, "process exits 0");
+        assert_eq!(res.stdout, Some(input), "stdout is the same as stdin");
+
+        Ok(())
+    }
+
     #[test]
     #[parallel]
     fn run_exit_0_with_unexpected_stderr() -> Result<()> {
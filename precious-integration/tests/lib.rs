@@ -563,6 +563,137 @@ fn munge_invocation_output(output_dir: PathBuf) -> Result<String> {
         .join(""))
 }
 
+#[test]
+#[serial]
+#[cfg(unix)]
+fn staged_with_stash_restores_stash_on_sigterm() -> Result<()> {
+    let helper = do_test_setup()?.with_config_file(
+        "precious.toml",
+        r#"
+[commands.slow]
+type    = "lint"
+include = "**/*.rs"
+invoke  = "once"
+path_args = "none"
+cmd     = [ "sleep", "60" ]
+ok_exit_codes = 0
+"#,
+    )?;
+    helper.modify_files()?;
+    helper.stage_all()?;
+    let unstaged = "tests/data/bar.txt";
+    helper.write_file(unstaged, "unstaged text")?;
+
+    let mut child = std::process::Command::new(precious_path()?)
+        .args(["lint", "--staged-with-stash"])
+        .current_dir(helper.precious_root())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+
+    let marker = helper.git_root().join(".git").join("precious-stash");
+    let start = std::time::Instant::now();
+    while !marker.exists() {
+        if start.elapsed() > std::time::Duration::from_secs(30) {
+            child.kill()?;
+            panic!("precious never stashed the unstaged changes");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    // Give precious a moment to start the slow command after stashing.
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    exec::run(
+        "kill",
+        &["-TERM", &child.id().to_string()],
+        &HashMap::new(),
+        &[0],
+        None,
+        None,
+    )?;
+    let status = child.wait()?;
+    assert!(!status.success());
+
+    assert_eq!(helper.read_file(unstaged.as_ref())?, "unstaged text");
+    assert!(!marker.exists());
+
+    Ok(())
+}
+
+#[test]
+#[serial]
+#[cfg(unix)]
+fn installed_pre_commit_hook() -> Result<()> {
+    let helper = do_test_setup()?;
+    // This existing hook should still be run after precious installs its
+    // own hook.
+    let existing = helper.git_root().join(".git/hooks/pre-commit");
+    fs::write(&existing, "#!/bin/sh\ntouch existing-hook-ran\n")?;
+    exec::run(
+        "chmod",
+        &["755", &existing.to_string_lossy()],
+        &HashMap::new(),
+        &[0],
+        None,
+        None,
+    )?;
+
+    let precious = precious_path()?;
+    let env = HashMap::new();
+    exec::run(
+        &precious,
+        &["hooks", "install"],
+        &env,
+        &[0],
+        None,
+        Some(&helper.precious_root()),
+    )?;
+
+    helper.write_file("src/bad.rs", "fn   bad() {}\n")?;
+    helper.stage_all()?;
+    let res = exec::run(
+        "git",
+        &["commit", "-m", "bad"],
+        &env,
+        &[1],
+        Some(&[Regex::new(".*")?]),
+        Some(&helper.git_root()),
+    )?;
+    // Git shows output from hooks on stderr.
+    assert!(res
+        .stderr
+        .unwrap_or_default()
+        .contains("Failed rustfmt: src/bad.rs"));
+    assert!(helper.git_root().join("existing-hook-ran").exists());
+    fs::remove_file(helper.git_root().join("existing-hook-ran"))?;
+
+    helper.write_file("src/bad.rs", "fn bad() {}\n")?;
+    helper.stage_all()?;
+    exec::run(
+        "git",
+        &["commit", "-m", "good"],
+        &env,
+        &[0],
+        Some(&[Regex::new(".*")?]),
+        Some(&helper.git_root()),
+    )?;
+
+    exec::run(
+        &precious,
+        &["hooks", "uninstall"],
+        &env,
+        &[0],
+        None,
+        Some(&helper.precious_root()),
+    )?;
+    assert_eq!(
+        fs::read_to_string(&existing)?,
+        "#!/bin/sh\ntouch existing-hook-ran\n",
+    );
+
+    Ok(())
+}
+
 fn precious_path() -> Result<String> {
     let mut precious = env::current_dir()?;
     precious.push("..");
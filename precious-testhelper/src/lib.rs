@@ -18,6 +18,8 @@ pub struct TestHelper {
     // else the directory it references will be deleted.
     _tempdir: Option<TempDir>,
     _preserved_tempdir: Option<PathBuf>,
+    // These hold the repos used for submodules and linked worktrees.
+    _other_tempdirs: Vec<TempDir>,
     git_root: PathBuf,
     precious_root: PathBuf,
     paths: Vec<PathBuf>,
@@ -46,6 +48,12 @@ impl TestHelper {
         static LOGGER_INIT: OnceCell<bool> = OnceCell::new();
         LOGGER_INIT.get_or_init(|| {
             env_logger::builder().is_test(true).init();
+            // Tests must not pick up the personal config of whoever runs them.
+            // This is inherited by any precious binary the tests run.
+            env::set_var(
+                "PRECIOUS_USER_CONFIG",
+                env::temp_dir().join("precious-testhelper-no-such-user-config.toml"),
+            );
             true
         });
 
@@ -61,6 +69,7 @@ impl TestHelper {
         let helper = TestHelper {
             _tempdir: tempdir,
             _preserved_tempdir: preserved_tempdir,
+            _other_tempdirs: vec![],
             git_root: root.clone(),
             precious_root: root,
             paths: Self::PATHS.iter().map(PathBuf::from).collect(),
@@ -82,14 +91,7 @@ impl TestHelper {
 
     fn create_git_repo(&self) -> Result<()> {
         debug!("Creating git repo in {}", self.git_root.display());
-        for p in self.paths.iter() {
-            let content = if is_rust_file(p) {
-                "fn foo() {}\n"
-            } else {
-                "some text"
-            };
-            self.write_file(p, content)?;
-        }
+        self.write_repo_files()?;
 
         self.run_git(&["init", "--initial-branch", "master"])?;
 
@@ -108,6 +110,106 @@ impl TestHelper {
         Ok(())
     }
 
+    // This creates a new repo containing a README.md and src/lib.rs file, and
+    // then adds it as a submodule at the given path and commits that.
+    pub fn with_git_submodule(mut self, path: &str) -> Result<Self> {
+        let td = tempfile::Builder::new()
+            .prefix("precious-testhelper-submodule-")
+            .tempdir()?;
+        let sub_root = maybe_canonicalize(td.path())?;
+        debug!("Creating git repo for submodule in {}", sub_root.display());
+
+        fs::create_dir_all(sub_root.join("src"))?;
+        fs::write(sub_root.join("README.md"), "some text")?;
+        fs::write(sub_root.join("src/lib.rs"), "fn foo() {}\n")?;
+        for args in [
+            &["init", "--initial-branch", "master"][..],
+            &["config", "user.email", "precious@example.com"],
+            &["config", "core.autocrlf", "false"],
+            &["add", "."],
+            &["commit", "-m", "initial commit"],
+        ] {
+            exec::run("git", args, &HashMap::new(), &[0], None, Some(&sub_root))?;
+        }
+
+        // Newer versions of git refuse to clone from a local path for a
+        // submodule unless this is set.
+        self.run_git(&[
+            "-c",
+            "protocol.file.allow=always",
+            "submodule",
+            "--quiet",
+            "add",
+            &sub_root.to_string_lossy(),
+            path,
+        ])?;
+        self.run_git(&["commit", "-m", "add submodule"])?;
+
+        self._other_tempdirs.push(td);
+        Ok(self)
+    }
+
+    // This creates a linked worktree on a new branch and returns its root. In
+    // a linked worktree, ".git" is a file rather than a directory.
+    pub fn add_git_worktree(&mut self, branch: &str) -> Result<PathBuf> {
+        let td = tempfile::Builder::new()
+            .prefix("precious-testhelper-worktree-")
+            .tempdir()?;
+        let worktree = maybe_canonicalize(td.path())?.join("worktree");
+        exec::run(
+            "git",
+            &[
+                "worktree",
+                "add",
+                "--quiet",
+                "-b",
+                branch,
+                &worktree.to_string_lossy(),
+            ],
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.git_root),
+        )?;
+
+        self._other_tempdirs.push(td);
+        Ok(worktree)
+    }
+
+    pub fn with_hg_repo(self) -> Result<Self> {
+        self.create_hg_repo()?;
+        Ok(self)
+    }
+
+    fn create_hg_repo(&self) -> Result<()> {
+        debug!("Creating hg repo in {}", self.git_root.display());
+        self.write_repo_files()?;
+
+        self.run_hg(&["init"])?;
+        self.run_hg(&["add", "--quiet"])?;
+        self.run_hg(&[
+            "commit",
+            "--user",
+            "precious@example.com",
+            "--message",
+            "initial commit",
+        ])?;
+
+        Ok(())
+    }
+
+    fn write_repo_files(&self) -> Result<()> {
+        for p in self.paths.iter() {
+            let content = if is_rust_file(p) {
+                "fn foo() {}\n"
+            } else {
+                "some text"
+            };
+            self.write_file(p, content)?;
+        }
+        Ok(())
+    }
+
     pub fn with_config_file(self, file_name: &str, content: &str) -> Result<Self> {
         if cfg!(windows) {
             self.write_file(self.config_file(file_name), &content.replace('\n', "\r\n"))?;
@@ -206,7 +308,7 @@ generated.*
             &HashMap::new(),
             &expect_codes,
             // If rerere is enabled, it prints to stderr.
-            Some(&[RERERE_RE.clone()]),
+            Some(std::slice::from_ref(&RERERE_RE)),
             Some(&self.git_root),
         )?;
         Ok(())
@@ -234,6 +336,18 @@ generated.*
         Ok(())
     }
 
+    fn run_hg(&self, args: &[&str]) -> Result<()> {
+        exec::run(
+            "hg",
+            args,
+            &HashMap::new(),
+            &[0],
+            None,
+            Some(&self.git_root),
+        )?;
+        Ok(())
+    }
+
     const TO_MODIFY: &'static [&'static str] = &["src/module.rs", "tests/data/foo.txt"];
 
     pub fn modify_files(&self) -> Result<Vec<PathBuf>> {
@@ -9,6 +9,13 @@ fn main() {
         eprintln!("Error creating logger: {e}");
         std::process::exit(1);
     }
+    if let Err(e) = precious::trap_signals() {
+        eprintln!("Error setting up signal handling: {e}");
+        std::process::exit(1);
+    }
+    if let Some(status) = app.run_without_project() {
+        std::process::exit(status as i32);
+    }
     let p = precious::Precious::new(app);
     let status = match p {
         Ok(mut p) => p.run(),